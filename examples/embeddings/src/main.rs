@@ -10,15 +10,14 @@ use std::io::Write;
 use std::path::PathBuf;
 use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use clap::Parser;
 use hf_hub::api::sync::ApiBuilder;
 
 use llama_cpp_2::context::params::LlamaContextParams;
-use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::embedding::{Aggregation, Embedder, SlidingWindow};
 use llama_cpp_2::ggml_time_us;
 use llama_cpp_2::llama_backend::LlamaBackend;
-use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
 use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::model::{AddBos, Special};
@@ -105,19 +104,20 @@ fn main() -> Result<()> {
         .with_context(|| "unable to load model")?;
 
     // initialize the context
-    let ctx_params = LlamaContextParams::default()
+    let ctx_params = LlamaContextParams::for_embeddings(&model)
         .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?)
-        .with_embeddings(true);
+        .with_n_seq_max(16);
 
     let mut ctx = model
         .new_context(&backend, ctx_params)
         .with_context(|| "unable to create the llama_context")?;
 
     // Split the prompt to display the batching functionality
-    let prompt_lines = prompt.lines();
+    let prompt_lines = prompt.lines().collect::<Vec<_>>();
 
     // tokenize the prompt
     let tokens_lines_list = prompt_lines
+        .iter()
         .map(|line| model.str_to_token(line, AddBos::Always))
         .collect::<Result<Vec<_>, _>>()
         .with_context(|| format!("failed to tokenize {prompt}"))?;
@@ -127,10 +127,6 @@ fn main() -> Result<()> {
 
     eprintln!("n_ctx = {n_ctx}, n_ctx_train = {n_ctx_train}");
 
-    if tokens_lines_list.iter().any(|tok| n_ctx < tok.len()) {
-        bail!("One of the provided prompts exceeds the size of the context window");
-    }
-
     // print the prompt token-by-token
     eprintln!();
 
@@ -150,22 +146,29 @@ fn main() -> Result<()> {
     }
 
     std::io::stderr().flush()?;
-    let mut output = Vec::with_capacity(tokens_lines_list.len());
 
     let t_main_start = ggml_time_us();
 
-    for tokens in &tokens_lines_list {
-        // Create a fresh batch for each sequence
-        let mut batch = LlamaBatch::new(n_ctx, 1);
-        batch.add_sequence(tokens, 0, false)?;
-        batch_decode(
-            &mut ctx,
-            &mut batch,
-            1, // Only one sequence in this batch
-            &mut output,
-            normalise,
-        )?;
-    }
+    let mut embedder = Embedder::new(&mut ctx).with_normalize(normalise);
+    let max_tokens = embedder.max_tokens();
+    let output = if tokens_lines_list.iter().all(|tok| tok.len() <= max_tokens) {
+        // the embedder packs as many prompts into each batch as the context allows
+        embedder
+            .embed(&tokens_lines_list)
+            .with_context(|| "failed to compute embeddings")?
+    } else {
+        // prompts longer than a batch are embedded in overlapping windows, each of which gets
+        // its own special tokens
+        let window = SlidingWindow::new(max_tokens, max_tokens / 4);
+        prompt_lines
+            .iter()
+            .map(|line| {
+                let tokens = model.str_to_token(line, AddBos::Never)?;
+                Ok(embedder.embed_long(&tokens, &window, Aggregation::Weighted)?)
+            })
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| "failed to compute embeddings")?
+    };
 
     let t_main_end = ggml_time_us();
 
@@ -187,40 +190,3 @@ fn main() -> Result<()> {
 
     Ok(())
 }
-
-fn batch_decode(
-    ctx: &mut LlamaContext,
-    batch: &mut LlamaBatch,
-    s_batch: i32,
-    output: &mut Vec<Vec<f32>>,
-    normalise: bool,
-) -> Result<()> {
-    ctx.clear_kv_cache();
-    ctx.decode(batch).with_context(|| "llama_decode() failed")?;
-
-    for i in 0..s_batch {
-        let embedding = ctx
-            .embeddings_seq_ith(i)
-            .with_context(|| "Failed to get embeddings")?;
-        let output_embeddings = if normalise {
-            normalize(embedding)
-        } else {
-            embedding.to_vec()
-        };
-
-        output.push(output_embeddings);
-    }
-
-    batch.clear();
-
-    Ok(())
-}
-
-fn normalize(input: &[f32]) -> Vec<f32> {
-    let magnitude = input
-        .iter()
-        .fold(0.0, |acc, &val| val.mul_add(val, acc))
-        .sqrt();
-
-    input.iter().map(|&val| val / magnitude).collect()
-}
@@ -0,0 +1,562 @@
+//! Utilities for turning chat messages into prompts that fit a token budget and for keeping a
+//! chat in the kv cache across turns.
+
+use crate::context::LlamaContext;
+use crate::generation::take_utf8;
+use crate::model::{AddBos, LlamaChatMessage, LlamaChatTemplate, LlamaModel, Special};
+use crate::token::LlamaToken;
+use crate::{
+    ApplyChatTemplateError, NewLlamaChatMessageError, StringToTokenError, TokenToStringError,
+};
+
+pub mod conversation;
+#[cfg(feature = "jinja")]
+pub mod jinja;
+pub mod reasoning;
+pub mod template;
+
+/// What to do with the oldest non-system messages when a prompt does not fit its budget.
+///
+/// The last message of the chat and all messages with the role `system` are never touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TruncationPolicy {
+    /// Fail with [`FitChatPromptError::PromptTooLong`] instead of modifying the chat.
+    Error,
+    /// Drop the oldest non-system messages one at a time until the prompt fits.
+    #[default]
+    DropOldest,
+    /// Cut tokens from the end of the oldest non-system message until the prompt fits. A message
+    /// that would be left with fewer than `min_tokens` content tokens is dropped instead, as is one
+    /// whose shortening did not make the prompt shorter, e.g. because the cut text tokenizes
+    /// differently.
+    ShortenOldest {
+        /// The minimum number of content tokens a shortened message keeps.
+        min_tokens: usize,
+    },
+}
+
+/// The token budget and options used by [`LlamaModel::fit_chat_prompt`].
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::chat::{PromptBudget, TruncationPolicy};
+///
+/// let budget = PromptBudget::new(4096)
+///     .with_policy(TruncationPolicy::ShortenOldest { min_tokens: 32 });
+/// assert_eq!(budget.max_tokens(), 4096);
+/// assert_eq!(budget.policy(), TruncationPolicy::ShortenOldest { min_tokens: 32 });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptBudget {
+    max_tokens: usize,
+    policy: TruncationPolicy,
+    add_ass: bool,
+    add_bos: AddBos,
+}
+
+impl PromptBudget {
+    /// Create a budget that allows at most `max_tokens` prompt tokens. By default the oldest
+    /// messages are dropped, the assistant prompt is appended and a BOS token is added.
+    #[must_use]
+    pub fn new(max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            policy: TruncationPolicy::default(),
+            add_ass: true,
+            add_bos: AddBos::Always,
+        }
+    }
+
+    /// Create a budget for `ctx` that leaves room for `n_predict` generated tokens.
+    ///
+    /// # Panics
+    ///
+    /// If `n_ctx` does not fit into a `usize`.
+    #[must_use]
+    pub fn for_context(ctx: &LlamaContext, n_predict: u32) -> Self {
+        let available = ctx.n_ctx().saturating_sub(n_predict);
+        Self::new(usize::try_from(available).expect("n_ctx does not fit into a usize"))
+    }
+
+    /// Set the [`TruncationPolicy`].
+    #[must_use]
+    pub fn with_policy(mut self, policy: TruncationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set whether the opening tag of the assistant is appended to the prompt. See
+    /// [`LlamaModel::apply_chat_template`].
+    #[must_use]
+    pub fn with_add_ass(mut self, add_ass: bool) -> Self {
+        self.add_ass = add_ass;
+        self
+    }
+
+    /// Set whether a BOS token is added when tokenizing the rendered prompt.
+    #[must_use]
+    pub fn with_add_bos(mut self, add_bos: AddBos) -> Self {
+        self.add_bos = add_bos;
+        self
+    }
+
+    /// The maximum number of prompt tokens.
+    #[must_use]
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// The [`TruncationPolicy`] applied when the prompt is too long.
+    #[must_use]
+    pub fn policy(&self) -> TruncationPolicy {
+        self.policy
+    }
+
+    /// Whether the opening tag of the assistant is appended to the prompt.
+    #[must_use]
+    pub fn add_ass(&self) -> bool {
+        self.add_ass
+    }
+
+    /// Whether a BOS token is added when tokenizing the rendered prompt.
+    #[must_use]
+    pub fn add_bos(&self) -> AddBos {
+        self.add_bos
+    }
+}
+
+/// The number of prompt tokens a single message contributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTokens {
+    /// The index of the message in the chat passed to [`LlamaModel::fit_chat_prompt`].
+    pub index: usize,
+    /// The number of tokens the message adds to the prompt, including the tokens the template
+    /// wraps it in. Templates that merge messages (e.g. a system message into the first user
+    /// message) attribute the merged tokens to the later message.
+    pub n_tokens: usize,
+    /// Whether the content of the message was shortened to fit the budget.
+    pub shortened: bool,
+}
+
+/// A rendered and tokenized chat prompt that fits a [`PromptBudget`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FittedChatPrompt {
+    /// The rendered prompt.
+    pub prompt: String,
+    /// The tokenized prompt.
+    pub tokens: Vec<LlamaToken>,
+    /// The messages that were rendered, after truncation.
+    pub chat: Vec<LlamaChatMessage>,
+    /// How many tokens each message in [`Self::chat`] contributes, in the same order.
+    pub message_tokens: Vec<MessageTokens>,
+    /// The indices of the messages that were dropped, in ascending order.
+    pub dropped: Vec<usize>,
+    /// The number of tokens that are not attributed to any message, i.e. the BOS token and the
+    /// assistant prompt.
+    pub overhead_tokens: usize,
+}
+
+/// Failed to fit a chat into a [`PromptBudget`].
+#[derive(Debug, thiserror::Error)]
+pub enum FitChatPromptError {
+    /// Even after applying the [`TruncationPolicy`] the prompt does not fit.
+    #[error("prompt is {n_tokens} tokens long, but only {max_tokens} are available")]
+    PromptTooLong {
+        /// The number of tokens in the smallest prompt that could be produced.
+        n_tokens: usize,
+        /// The maximum number of tokens allowed by the budget.
+        max_tokens: usize,
+    },
+    /// The chat template could not be applied.
+    #[error("{0}")]
+    ApplyChatTemplateError(#[from] ApplyChatTemplateError),
+    /// The rendered prompt could not be tokenized.
+    #[error("{0}")]
+    StringToTokenError(#[from] StringToTokenError),
+    /// A shortened message could not be converted back to a string.
+    #[error("{0}")]
+    TokenToStringError(#[from] TokenToStringError),
+    /// A shortened message could not be created.
+    #[error("{0}")]
+    NewLlamaChatMessageError(#[from] NewLlamaChatMessageError),
+    /// The Jinja chat template could not be rendered.
+    #[cfg(feature = "jinja")]
+    #[error("{0}")]
+    JinjaTemplateError(#[from] jinja::JinjaTemplateError),
+}
+
+impl LlamaModel {
+    /// Render `chat` with `tmpl` and tokenize the result, truncating the oldest non-system
+    /// messages according to the [`TruncationPolicy`] of `budget` until the prompt fits.
+    ///
+    /// Use [`PromptBudget::for_context`] to leave room for the generated tokens in a context.
+    ///
+    /// # Errors
+    ///
+    /// See [`FitChatPromptError`] for more information.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::chat::{PromptBudget, TruncationPolicy};
+    /// use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let template = model.chat_template(None)?;
+    /// let chat = vec![
+    ///     LlamaChatMessage::new("system".into(), "You are a helpful assistant.".into())?,
+    ///     LlamaChatMessage::new("user".into(), "Hello!".into())?,
+    /// ];
+    /// let fitted = model.fit_chat_prompt(&template, &chat, &PromptBudget::new(2048))?;
+    /// for message in &fitted.message_tokens {
+    ///     println!("message {} uses {} tokens", message.index, message.n_tokens);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fit_chat_prompt(
+        &self,
+        tmpl: &LlamaChatTemplate,
+        chat: &[LlamaChatMessage],
+        budget: &PromptBudget,
+    ) -> Result<FittedChatPrompt, FitChatPromptError> {
+        fit_chat_prompt_with(self, chat, budget, |chat, add_ass| {
+            self.apply_chat_template(tmpl, chat, add_ass)
+        })
+    }
+
+    /// Like [`Self::fit_chat_prompt`] but renders the chat with a [`jinja::JinjaChatTemplate`].
+    ///
+    /// Jinja templates usually render the BOS token themselves, so `budget` should generally use
+    /// [`AddBos::Never`].
+    ///
+    /// # Errors
+    ///
+    /// See [`FitChatPromptError`] for more information.
+    #[cfg(feature = "jinja")]
+    pub fn fit_chat_prompt_jinja(
+        &self,
+        tmpl: &jinja::JinjaChatTemplate,
+        chat: &[LlamaChatMessage],
+        budget: &PromptBudget,
+    ) -> Result<FittedChatPrompt, FitChatPromptError> {
+        fit_chat_prompt_with(self, chat, budget, |chat, add_ass| {
+            tmpl.render(&jinja::JinjaChatInputs::new(chat).with_add_generation_prompt(add_ass))
+        })
+    }
+}
+
+/// Converts between text and tokens for [`fit_chat_prompt_with`], so that fitting does not
+/// depend on a model.
+pub(crate) trait Tokenizer {
+    /// Tokenize `text`.
+    fn tokenize(&self, text: &str, add_bos: AddBos) -> Result<Vec<LlamaToken>, StringToTokenError>;
+
+    /// The bytes of `tokens`, which may end in the middle of a character.
+    fn detokenize(&self, tokens: &[LlamaToken]) -> Result<Vec<u8>, TokenToStringError>;
+}
+
+impl Tokenizer for LlamaModel {
+    fn tokenize(&self, text: &str, add_bos: AddBos) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.str_to_token(text, add_bos)
+    }
+
+    fn detokenize(&self, tokens: &[LlamaToken]) -> Result<Vec<u8>, TokenToStringError> {
+        let mut bytes = Vec::with_capacity(tokens.len() * 4);
+        for &token in tokens {
+            bytes.extend(self.token_to_bytes(token, Special::Tokenize)?);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Like [`LlamaModel::fit_chat_prompt`] but renders the chat with `render`, which receives the
+/// messages and whether to append the assistant prompt, and tokenizes it with `tokenizer`.
+pub(crate) fn fit_chat_prompt_with<E>(
+    tokenizer: &impl Tokenizer,
+    chat: &[LlamaChatMessage],
+    budget: &PromptBudget,
+    render: impl Fn(&[LlamaChatMessage], bool) -> Result<String, E>,
+) -> Result<FittedChatPrompt, FitChatPromptError>
+where
+    FitChatPromptError: From<E>,
+{
+    // (original index, message, shortened)
+    let mut kept: Vec<(usize, LlamaChatMessage, bool)> = chat
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(i, message)| (i, message, false))
+        .collect();
+    let mut dropped = Vec::new();
+    // the prompt length before the oldest message was last shortened
+    let mut before_shortening = None;
+
+    let (prompt, tokens) = loop {
+        let messages: Vec<LlamaChatMessage> = kept.iter().map(|(_, m, _)| m.clone()).collect();
+        let prompt = render(&messages, budget.add_ass)?;
+        let tokens = tokenizer.tokenize(&prompt, budget.add_bos)?;
+        if tokens.len() <= budget.max_tokens {
+            break (prompt, tokens);
+        }
+
+        let too_long = FitChatPromptError::PromptTooLong {
+            n_tokens: tokens.len(),
+            max_tokens: budget.max_tokens,
+        };
+        // the last message is what we are responding to and is never truncated.
+        let Some(oldest) = kept
+            .iter()
+            .take(kept.len().saturating_sub(1))
+            .position(|(_, m, _)| m.role.as_bytes() != b"system")
+        else {
+            return Err(too_long);
+        };
+
+        match budget.policy {
+            TruncationPolicy::Error => return Err(too_long),
+            TruncationPolicy::DropOldest => {
+                dropped.push(kept.remove(oldest).0);
+            }
+            TruncationPolicy::ShortenOldest { min_tokens } => {
+                let overflow = tokens.len() - budget.max_tokens;
+                let (_, message, shortened) = &mut kept[oldest];
+                let content = String::from_utf8_lossy(message.content.as_bytes()).into_owned();
+                // the rendered length is checked again on the next pass, as the cut text may
+                // tokenize differently within the prompt
+                let stuck = before_shortening.is_some_and(|n_tokens| tokens.len() >= n_tokens);
+                let cut = if stuck {
+                    None
+                } else {
+                    shorten(tokenizer, &content, overflow, min_tokens)?
+                };
+                if let Some(content) = cut {
+                    let role = String::from_utf8_lossy(message.role.as_bytes()).into_owned();
+                    *message = LlamaChatMessage::new(role, content)?;
+                    *shortened = true;
+                    before_shortening = Some(tokens.len());
+                } else {
+                    dropped.push(kept.remove(oldest).0);
+                    before_shortening = None;
+                }
+            }
+        }
+    };
+    dropped.sort_unstable();
+
+    let chat: Vec<LlamaChatMessage> = kept.iter().map(|(_, m, _)| m.clone()).collect();
+    let mut message_tokens = Vec::with_capacity(kept.len());
+    let mut previous = 0;
+    for (n, (index, _, shortened)) in kept.iter().enumerate() {
+        let prefix = render(&chat[..=n], false)?;
+        let n_prefix = tokenizer.tokenize(&prefix, AddBos::Never)?.len();
+        message_tokens.push(MessageTokens {
+            index: *index,
+            n_tokens: n_prefix.saturating_sub(previous),
+            shortened: *shortened,
+        });
+        previous = n_prefix;
+    }
+
+    Ok(FittedChatPrompt {
+        prompt,
+        overhead_tokens: tokens.len().saturating_sub(previous),
+        tokens,
+        chat,
+        message_tokens,
+        dropped,
+    })
+}
+
+/// Cut `overflow` tokens from the end of `content`. Returns `None` if fewer than `min_tokens`
+/// tokens would be left or the cut does not make the content shorter.
+fn shorten(
+    tokenizer: &impl Tokenizer,
+    content: &str,
+    overflow: usize,
+    min_tokens: usize,
+) -> Result<Option<String>, FitChatPromptError> {
+    let content_tokens = tokenizer.tokenize(content, AddBos::Never)?;
+    let keep = content_tokens.len().saturating_sub(overflow);
+    if keep < min_tokens.max(1) {
+        return Ok(None);
+    }
+    // a cut between the tokens of a multibyte character leaves an incomplete character behind,
+    // which is dropped
+    let mut bytes = tokenizer.detokenize(&content_tokens[..keep])?;
+    let cut = take_utf8(&mut bytes, false);
+    // tokenizers that add a leading space to the text give it back when detokenizing
+    let cut = match cut.strip_prefix(' ') {
+        Some(rest) if !content.starts_with(' ') => rest,
+        _ => cut.as_str(),
+    };
+    Ok((!cut.is_empty() && cut.len() < content.len()).then(|| cut.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOS: LlamaToken = LlamaToken(1000);
+
+    /// One token per byte. `space_prefix` adds a leading space like sentencepiece tokenizers do.
+    struct ByteTokenizer {
+        space_prefix: bool,
+    }
+
+    impl Tokenizer for ByteTokenizer {
+        fn tokenize(
+            &self,
+            text: &str,
+            add_bos: AddBos,
+        ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+            let bos = (add_bos == AddBos::Always).then_some(BOS);
+            let space =
+                (self.space_prefix && !text.is_empty()).then_some(LlamaToken(i32::from(b' ')));
+            let bytes = text.bytes().map(|byte| LlamaToken(i32::from(byte)));
+            Ok(bos.into_iter().chain(space).chain(bytes).collect())
+        }
+
+        fn detokenize(&self, tokens: &[LlamaToken]) -> Result<Vec<u8>, TokenToStringError> {
+            Ok(tokens
+                .iter()
+                .filter(|&&token| token != BOS)
+                .map(|token| u8::try_from(token.0).expect("a byte token"))
+                .collect())
+        }
+    }
+
+    fn message(role: &str, content: &str) -> LlamaChatMessage {
+        LlamaChatMessage::new(role.to_string(), content.to_string()).unwrap()
+    }
+
+    /// `role:content` per line, `>` for the assistant prompt.
+    fn render(chat: &[LlamaChatMessage], add_ass: bool) -> String {
+        let mut prompt = String::new();
+        for message in chat {
+            prompt.push_str(message.role.to_str().unwrap());
+            prompt.push(':');
+            prompt.push_str(message.content.to_str().unwrap());
+            prompt.push('\n');
+        }
+        if add_ass {
+            prompt.push('>');
+        }
+        prompt
+    }
+
+    fn fit(
+        chat: &[LlamaChatMessage],
+        budget: &PromptBudget,
+        space_prefix: bool,
+    ) -> Result<FittedChatPrompt, FitChatPromptError> {
+        let tokenizer = ByteTokenizer { space_prefix };
+        fit_chat_prompt_with(&tokenizer, chat, budget, |chat, add_ass| {
+            Ok::<_, StringToTokenError>(render(chat, add_ass))
+        })
+    }
+
+    fn contents(fitted: &FittedChatPrompt) -> Vec<&str> {
+        fitted
+            .chat
+            .iter()
+            .map(|message| message.content.to_str().unwrap())
+            .collect()
+    }
+
+    fn chat() -> Vec<LlamaChatMessage> {
+        vec![
+            message("system", "be brief"),
+            message("user", "first question"),
+            message("assistant", "first answer"),
+            message("user", "second"),
+        ]
+    }
+
+    #[test]
+    fn a_fitting_chat_is_unchanged() {
+        let fitted = fit(&chat(), &PromptBudget::new(1000), false).unwrap();
+        assert_eq!(
+            contents(&fitted),
+            ["be brief", "first question", "first answer", "second"]
+        );
+        assert!(fitted.dropped.is_empty());
+        // the BOS token and the assistant prompt
+        assert_eq!(fitted.overhead_tokens, 2);
+        let n_tokens = fitted
+            .message_tokens
+            .iter()
+            .map(|m| m.n_tokens)
+            .sum::<usize>();
+        assert_eq!(n_tokens + fitted.overhead_tokens, fitted.tokens.len());
+    }
+
+    #[test]
+    fn drop_oldest_keeps_system_messages() {
+        // the full prompt is 73 tokens, without the first question 53
+        let budget = PromptBudget::new(55);
+        let fitted = fit(&chat(), &budget, false).unwrap();
+        assert_eq!(contents(&fitted), ["be brief", "first answer", "second"]);
+        assert_eq!(fitted.dropped, [1]);
+        assert!(fitted.tokens.len() <= 55);
+        assert_eq!(fitted.message_tokens[1].index, 2);
+    }
+
+    #[test]
+    fn system_messages_and_the_last_message_are_never_dropped() {
+        let chat = [
+            message("system", "be brief"),
+            message("user", "a long question"),
+        ];
+        let err = fit(&chat, &PromptBudget::new(10), false).unwrap_err();
+        assert!(matches!(
+            err,
+            FitChatPromptError::PromptTooLong { max_tokens: 10, .. }
+        ));
+
+        let budget = PromptBudget::new(10).with_policy(TruncationPolicy::Error);
+        assert!(fit(&self::chat(), &budget, false).is_err());
+    }
+
+    #[test]
+    fn shorten_oldest_cuts_the_oldest_message() {
+        let budget =
+            PromptBudget::new(66).with_policy(TruncationPolicy::ShortenOldest { min_tokens: 4 });
+        let fitted = fit(&chat(), &budget, false).unwrap();
+        assert_eq!(
+            contents(&fitted),
+            ["be brief", "first q", "first answer", "second"]
+        );
+        assert_eq!(fitted.tokens.len(), 66);
+        assert!(fitted.message_tokens[1].shortened);
+        assert!(!fitted.message_tokens[2].shortened);
+
+        // too little would be left of the first question, so it is dropped
+        let budget =
+            PromptBudget::new(55).with_policy(TruncationPolicy::ShortenOldest { min_tokens: 4 });
+        let fitted = fit(&chat(), &budget, false).unwrap();
+        assert_eq!(contents(&fitted), ["be brief", "first answer", "second"]);
+        assert_eq!(fitted.dropped, [1]);
+    }
+
+    #[test]
+    fn shortening_drops_a_cut_character() {
+        let chat = [message("user", "日本語です"), message("user", "ok")];
+        let budget =
+            PromptBudget::new(24).with_policy(TruncationPolicy::ShortenOldest { min_tokens: 1 });
+        // 31 tokens, the cut at 8 bytes of content ends inside the third character
+        let fitted = fit(&chat, &budget, false).unwrap();
+        assert_eq!(contents(&fitted), ["日本", "ok"]);
+        assert_eq!(fitted.tokens.len(), 22);
+    }
+
+    #[test]
+    fn shortening_removes_the_added_leading_space() {
+        let budget =
+            PromptBudget::new(67).with_policy(TruncationPolicy::ShortenOldest { min_tokens: 4 });
+        let fitted = fit(&chat(), &budget, true).unwrap();
+        assert_eq!(contents(&fitted)[1], "first q");
+    }
+}
@@ -0,0 +1,493 @@
+//! A chat bound to one sequence of a context that reuses its kv cache across turns.
+
+use crate::chat::reasoning::ReasoningSplitter;
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::generation::stop::StopCondition;
+use crate::generation::{FinishReason, GenerateError, Generator};
+use crate::llama_batch::BatchAddError;
+use crate::model::{AddBos, LlamaChatMessage, LlamaChatTemplate};
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{ApplyChatTemplateError, DecodeError, StringToTokenError};
+
+/// Failed to bring a [`Conversation`] up to date with a prompt.
+#[derive(Debug, thiserror::Error)]
+pub enum ConversationError {
+    /// The prompt does not fit into the context.
+    #[error("the prompt has {n_tokens} tokens but the context only fits {n_ctx}")]
+    ContextFull {
+        /// The number of tokens in the prompt.
+        n_tokens: usize,
+        /// The size of the context.
+        n_ctx: usize,
+    },
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// See [`KvCacheConversionError`].
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+    /// See [`ApplyChatTemplateError`].
+    #[error("{0}")]
+    ApplyChatTemplateError(#[from] ApplyChatTemplateError),
+    /// See [`StringToTokenError`].
+    #[error("{0}")]
+    StringToTokenError(#[from] StringToTokenError),
+    /// See [`GenerateError`].
+    #[error("{0}")]
+    GenerateError(#[from] GenerateError),
+    /// A response was requested before any prompt was decoded.
+    #[error("nothing was decoded yet, there are no logits to sample from")]
+    NoLogits,
+}
+
+/// How much of a prompt was served from the kv cache by [`Conversation::update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptReuse {
+    /// The number of leading prompt tokens that were already in the kv cache.
+    pub reused: usize,
+    /// The number of tokens that had to be decoded.
+    pub decoded: usize,
+}
+
+/// The answer generated by [`Conversation::respond`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatResponse {
+    /// The sampled tokens, without the end of generation token.
+    pub tokens: Vec<LlamaToken>,
+    /// The text of the reasoning block. Empty if no [`ReasoningSplitter`] was used.
+    pub reasoning: String,
+    /// The answer.
+    pub content: String,
+    /// Whether the reasoning block was closed because its budget ran out.
+    pub reasoning_forced_close: bool,
+    /// Why generation stopped.
+    pub finish_reason: FinishReason,
+}
+
+/// A chat bound to a context and sequence id.
+///
+/// The conversation remembers which tokens are in the kv cache of its sequence. Each time the
+/// history is re-rendered, only the part of the prompt after the longest common prefix with those
+/// tokens is decoded and the divergent tail of the cache is removed with
+/// [`LlamaContext::clear_kv_cache_seq`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use llama_cpp_2::chat::conversation::Conversation;
+/// use llama_cpp_2::context::params::LlamaContextParams;
+/// use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+/// use llama_cpp_2::sampling::LlamaSampler;
+/// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+/// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+/// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+/// let template = model.chat_template(None)?;
+/// let mut conversation = Conversation::new(&mut ctx, 0)?;
+///
+/// let mut chat = vec![LlamaChatMessage::new("user".into(), "Hello!".into())?];
+/// let reuse = conversation.update_chat(&template, &chat, true)?;
+/// assert_eq!(reuse.reused, 0);
+///
+/// let mut sampler = LlamaSampler::greedy();
+/// let token = sampler.sample(conversation.context(), conversation.logits_index().unwrap());
+/// conversation.push(&[token])?;
+///
+/// // the next turn only decodes the new messages
+/// chat.push(LlamaChatMessage::new("assistant".into(), "Hi".into())?);
+/// chat.push(LlamaChatMessage::new("user".into(), "How are you?".into())?);
+/// let reuse = conversation.update_chat(&template, &chat, true)?;
+/// println!("reused {} tokens, decoded {}", reuse.reused, reuse.decoded);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Conversation<'ctx, 'model> {
+    ctx: &'ctx mut LlamaContext<'model>,
+    seq_id: i32,
+    tokens: Vec<LlamaToken>,
+    add_bos: AddBos,
+    logits_index: Option<i32>,
+}
+
+impl<'ctx, 'model> Conversation<'ctx, 'model> {
+    /// Bind a new conversation to `seq_id` of `ctx`. Anything already cached for the sequence is
+    /// removed.
+    ///
+    /// # Errors
+    ///
+    /// If `seq_id` is negative.
+    pub fn new(
+        ctx: &'ctx mut LlamaContext<'model>,
+        seq_id: i32,
+    ) -> Result<Self, KvCacheConversionError> {
+        let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        ctx.clear_kv_cache_seq(Some(seq), None, None)?;
+        Ok(Self {
+            ctx,
+            seq_id,
+            tokens: Vec::new(),
+            add_bos: AddBos::Always,
+            logits_index: None,
+        })
+    }
+
+    /// Whether [`Self::update_chat`] adds a BOS token when tokenizing the rendered prompt.
+    /// Defaults to [`AddBos::Always`].
+    #[must_use]
+    pub fn with_add_bos(mut self, add_bos: AddBos) -> Self {
+        self.add_bos = add_bos;
+        self
+    }
+
+    /// The sequence id the conversation decodes into.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// The tokens currently in the kv cache of the sequence.
+    #[must_use]
+    pub fn tokens(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+
+    /// The batch index of the logits of the last token, to be passed to
+    /// [`crate::sampling::LlamaSampler::sample`]. `None` if nothing was decoded yet.
+    #[must_use]
+    pub fn logits_index(&self) -> Option<i32> {
+        self.logits_index
+    }
+
+    /// The context the conversation decodes into.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// Mutable access to the context. Modifying the kv cache of [`Self::seq_id`] through it will
+    /// desynchronize the conversation; call [`Self::reset`] if that happens.
+    pub fn context_mut(&mut self) -> &mut LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// Remove everything from the sequence.
+    ///
+    /// # Errors
+    ///
+    /// If the sequence id cannot be converted.
+    pub fn reset(&mut self) -> Result<(), KvCacheConversionError> {
+        self.truncate(0)?;
+        Ok(())
+    }
+
+    /// Render `chat` with `tmpl`, tokenize it and [`Self::update`] the cache with the result.
+    ///
+    /// Use [`Self::update`] with the tokens of a [`crate::chat::FittedChatPrompt`] to keep long
+    /// chats within the context.
+    ///
+    /// # Errors
+    ///
+    /// If the template could not be applied, the prompt could not be tokenized or decoding failed.
+    pub fn update_chat(
+        &mut self,
+        tmpl: &LlamaChatTemplate,
+        chat: &[LlamaChatMessage],
+        add_ass: bool,
+    ) -> Result<PromptReuse, ConversationError> {
+        let prompt = self.ctx.model.apply_chat_template(tmpl, chat, add_ass)?;
+        let tokens = self.ctx.model.str_to_token(&prompt, self.add_bos)?;
+        self.update(&tokens)
+    }
+
+    /// Make the sequence hold exactly `prompt`.
+    ///
+    /// The longest common prefix of `prompt` and [`Self::tokens`] is kept, the rest of the cache is
+    /// removed and the remaining prompt tokens are decoded in batches of at most
+    /// [`LlamaContext::n_batch`] tokens. The last token is always decoded so its logits are
+    /// available at [`Self::logits_index`]. Models whose cache cannot be partially removed, such as
+    /// recurrent models, fall back to decoding the whole prompt.
+    ///
+    /// # Errors
+    ///
+    /// If the prompt does not fit into the context or decoding failed. After a failed decode
+    /// [`Self::tokens`] still reflects the state of the cache.
+    pub fn update(&mut self, prompt: &[LlamaToken]) -> Result<PromptReuse, ConversationError> {
+        let n_ctx = self.ctx.n_ctx() as usize;
+        if prompt.len() > n_ctx {
+            return Err(ConversationError::ContextFull {
+                n_tokens: prompt.len(),
+                n_ctx,
+            });
+        }
+
+        let reused = self.truncate(common_prefix(&self.tokens, prompt))?;
+        self.decode(&prompt[reused..])?;
+        Ok(PromptReuse {
+            reused,
+            decoded: prompt.len() - reused,
+        })
+    }
+
+    /// Decode `tokens` after the tokens already in the sequence, e.g. a token that was just
+    /// sampled.
+    ///
+    /// # Errors
+    ///
+    /// If the tokens do not fit into the context or decoding failed.
+    pub fn push(&mut self, tokens: &[LlamaToken]) -> Result<(), ConversationError> {
+        let n_ctx = self.ctx.n_ctx() as usize;
+        if self.tokens.len() + tokens.len() > n_ctx {
+            return Err(ConversationError::ContextFull {
+                n_tokens: self.tokens.len() + tokens.len(),
+                n_ctx,
+            });
+        }
+        self.decode(tokens)
+    }
+
+    /// Sample an answer to the decoded prompt, decoding each token into the sequence.
+    ///
+    /// Generation stops at an end of generation token or after `max_tokens` tokens. With a
+    /// `reasoning` splitter the text is sorted into [`ChatResponse::reasoning`] and
+    /// [`ChatResponse::content`]. When its budget runs out, the end tag of the reasoning block is
+    /// decoded so the model continues with its answer.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::chat::conversation::Conversation;
+    /// use llama_cpp_2::chat::reasoning::{ReasoningFormat, ReasoningSplitter};
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let template = model.chat_template(None)?;
+    /// let format = template
+    ///     .family()
+    ///     .and_then(ReasoningFormat::for_family)
+    ///     .unwrap_or_else(ReasoningFormat::think);
+    ///
+    /// let mut conversation = Conversation::new(&mut ctx, 0)?;
+    /// let chat = vec![LlamaChatMessage::new("user".into(), "What is 2 + 2?".into())?];
+    /// conversation.update_chat(&template, &chat, true)?;
+    /// let splitter = ReasoningSplitter::new(format).with_budget(512);
+    /// let response = conversation.respond(&mut LlamaSampler::greedy(), 1024, Some(splitter))?;
+    /// println!("thought about: {}", response.reasoning);
+    /// println!("answer: {}", response.content);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If nothing was decoded yet, a token could not be converted to text or decoding failed.
+    pub fn respond(
+        &mut self,
+        sampler: &mut LlamaSampler,
+        max_tokens: usize,
+        mut reasoning: Option<ReasoningSplitter>,
+    ) -> Result<ChatResponse, ConversationError> {
+        let index = self.logits_index.ok_or(ConversationError::NoLogits)?;
+        let mut generator = Generator::resume(self.ctx, sampler, index, self.tokens.len())
+            .with_seq_id(self.seq_id)
+            .with_max_tokens(max_tokens);
+        let mut response = ChatResponse {
+            tokens: Vec::new(),
+            reasoning: String::new(),
+            content: String::new(),
+            reasoning_forced_close: false,
+            finish_reason: FinishReason::MaxTokens,
+        };
+        let result = collect_response(&mut generator, &mut response, reasoning.as_mut());
+        self.tokens.extend_from_slice(generator.tokens());
+        self.logits_index = generator.logits_index();
+        if let Some(reason) = generator.finish_reason() {
+            response.finish_reason = reason.clone();
+        }
+        if result.is_err() {
+            // drop whatever a failed decode may have left in the cache
+            self.clear_from(self.tokens.len())?;
+        }
+        result.map(|()| response)
+    }
+
+    /// Remove everything from position `len` onwards, returning the number of tokens left.
+    fn truncate(&mut self, len: usize) -> Result<usize, KvCacheConversionError> {
+        let n_cached = self.tokens.len();
+        if len >= n_cached {
+            return Ok(n_cached);
+        }
+        let len = truncated_len(n_cached, len, |pos| self.clear_from(pos))?;
+        self.tokens.truncate(len);
+        self.logits_index = None;
+        Ok(len)
+    }
+
+    fn clear_from(&mut self, pos: usize) -> Result<bool, KvCacheConversionError> {
+        let seq = u32::try_from(self.seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let p0 = u32::try_from(pos).map_err(KvCacheConversionError::P0TooLarge)?;
+        self.ctx.clear_kv_cache_seq(Some(seq), Some(p0), None)
+    }
+
+    fn decode(&mut self, tokens: &[LlamaToken]) -> Result<(), ConversationError> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let start_pos = i32::try_from(self.tokens.len()).expect("position fits into an i32");
+        match self.ctx.prefill(tokens, self.seq_id, start_pos) {
+            Ok(prefill) => {
+                self.tokens.extend_from_slice(tokens);
+                self.logits_index = Some(prefill.logits_index);
+                Ok(())
+            }
+            Err(err) => {
+                // drop whatever the decoded batches left in the cache
+                self.logits_index = None;
+                self.clear_from(self.tokens.len())?;
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// The number of leading `cached` tokens that can be kept for `prompt`: their longest common
+/// prefix, leaving at least the last prompt token to be decoded so its logits are computed.
+fn common_prefix(cached: &[LlamaToken], prompt: &[LlamaToken]) -> usize {
+    cached
+        .iter()
+        .zip(prompt)
+        .take_while(|(cached, new)| cached == new)
+        .count()
+        .min(prompt.len().saturating_sub(1))
+}
+
+/// The number of tokens left after cutting a cache of `n_cached` tokens to `len` tokens with
+/// `clear_from`, which removes everything from a position onwards and returns `false` if the cache
+/// cannot be partially removed. Then the whole cache is removed instead.
+fn truncated_len<E>(
+    n_cached: usize,
+    len: usize,
+    mut clear_from: impl FnMut(usize) -> Result<bool, E>,
+) -> Result<usize, E> {
+    if len >= n_cached {
+        return Ok(n_cached);
+    }
+    if clear_from(len)? {
+        Ok(len)
+    } else {
+        clear_from(0)?;
+        Ok(0)
+    }
+}
+
+/// Sort the pieces yielded by `generator` into `response`, closing the reasoning block when the
+/// splitter asks for it.
+fn collect_response(
+    generator: &mut Generator,
+    response: &mut ChatResponse,
+    mut reasoning: Option<&mut ReasoningSplitter>,
+) -> Result<(), ConversationError> {
+    while let Some(generated) = generator.next() {
+        let generated = generated?;
+        // the end of generation or stop token that ends generation is not part of the answer
+        if !matches!(
+            generator.finish_reason(),
+            Some(FinishReason::EndOfGeneration | FinishReason::Stop(StopCondition::Token(_)))
+        ) {
+            response.tokens.push(generated.token);
+        }
+        let Some(splitter) = reasoning.as_deref_mut() else {
+            response.content.push_str(&generated.piece);
+            continue;
+        };
+        let delta = splitter.push(&generated.piece);
+        response.reasoning.push_str(&delta.reasoning);
+        response.content.push_str(&delta.content);
+        if delta.close_reasoning {
+            response.reasoning_forced_close = true;
+            let close = format!("{}\n\n", splitter.format().end());
+            let close = generator
+                .context()
+                .model
+                .str_to_token(&close, AddBos::Never)?;
+            generator.push_tokens(&close)?;
+        }
+    }
+    if let Some(splitter) = reasoning {
+        let delta = splitter.finish();
+        response.reasoning.push_str(&delta.reasoning);
+        response.content.push_str(&delta.content);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().copied().map(LlamaToken).collect()
+    }
+
+    /// Truncate `cached` for `prompt` like [`Conversation::update`], returning the number of
+    /// reused tokens and the positions passed to `clear_from`.
+    fn reuse(cached: &[i32], prompt: &[i32], partial: bool) -> (usize, Vec<usize>) {
+        let mut cleared = Vec::new();
+        let common = common_prefix(&tokens(cached), &tokens(prompt));
+        let reused = truncated_len(cached.len(), common, |pos| {
+            cleared.push(pos);
+            Ok::<_, ()>(partial || pos == 0)
+        })
+        .unwrap();
+        (reused, cleared)
+    }
+
+    #[test]
+    fn identical_history_decodes_the_last_token_again() {
+        assert_eq!(reuse(&[1, 2, 3, 4], &[1, 2, 3, 4], true), (3, vec![3]));
+    }
+
+    #[test]
+    fn extended_history_keeps_the_cache() {
+        assert_eq!(reuse(&[1, 2, 3], &[1, 2, 3, 4, 5], true), (3, vec![]));
+    }
+
+    #[test]
+    fn edited_middle_message_keeps_the_prefix() {
+        assert_eq!(
+            reuse(&[1, 2, 3, 4, 5], &[1, 2, 9, 4, 5, 6], true),
+            (2, vec![2])
+        );
+    }
+
+    #[test]
+    fn shorter_history_keeps_all_but_its_last_token() {
+        assert_eq!(reuse(&[1, 2, 3, 4, 5], &[1, 2, 3], true), (2, vec![2]));
+        assert_eq!(reuse(&[1, 2, 3], &[7], true), (0, vec![0]));
+    }
+
+    #[test]
+    fn no_partial_removal_falls_back_to_an_empty_cache() {
+        assert_eq!(reuse(&[1, 2, 3, 4, 5], &[1, 2, 9], false), (0, vec![2, 0]));
+        // nothing has to be removed, so the cache is kept
+        assert_eq!(reuse(&[1, 2], &[1, 2, 3], false), (2, vec![]));
+    }
+
+    #[test]
+    fn truncation_errors_are_returned() {
+        assert_eq!(truncated_len(4, 1, |_| Err("failed")), Err("failed"));
+        assert_eq!(
+            truncated_len(4, 1, |pos| if pos == 0 { Err("failed") } else { Ok(false) }),
+            Err("failed")
+        );
+    }
+}
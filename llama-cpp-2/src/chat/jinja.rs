@@ -0,0 +1,515 @@
+//! Render chat templates with [minijinja](https://github.com/mitsuhiko/minijinja) instead of the
+//! fixed set of templates built into llama.cpp.
+//!
+//! Requires the `jinja` feature.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use minijinja::value::Kwargs;
+use minijinja::{Environment, ErrorKind, Value};
+use serde::Serialize;
+
+use crate::model::{LlamaChatMessage, LlamaModel};
+use crate::token::LlamaToken;
+use crate::ChatTemplateError;
+
+const TEMPLATE_NAME: &str = "chat_template";
+
+/// Failed to create or render a [`JinjaChatTemplate`].
+#[derive(Debug, thiserror::Error)]
+pub enum JinjaTemplateError {
+    /// The template could not be loaded from the model.
+    #[error("{0}")]
+    ChatTemplateError(#[from] ChatTemplateError),
+    /// The text of the BOS or EOS token was not valid utf8.
+    #[error(transparent)]
+    Utf8Error(#[from] std::str::Utf8Error),
+    /// The template failed to compile or render. This includes calls to `raise_exception`.
+    #[error("{0}")]
+    RenderError(#[from] minijinja::Error),
+}
+
+/// The values a [`JinjaChatTemplate`] is rendered with.
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::chat::jinja::JinjaChatInputs;
+/// use llama_cpp_2::model::LlamaChatMessage;
+///
+/// let chat = [LlamaChatMessage::new("user".into(), "What is the weather?".into()).unwrap()];
+/// let inputs = JinjaChatInputs::new(&chat)
+///     .with_tools(&[serde_json::json!({
+///         "type": "function",
+///         "function": { "name": "get_weather", "parameters": {} }
+///     })])
+///     .with_add_generation_prompt(true);
+/// assert!(inputs.add_generation_prompt());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct JinjaChatInputs {
+    messages: Value,
+    tools: Option<Value>,
+    add_generation_prompt: bool,
+    extra: BTreeMap<String, Value>,
+}
+
+impl JinjaChatInputs {
+    /// Create inputs from plain role/content messages.
+    #[must_use]
+    pub fn new(chat: &[LlamaChatMessage]) -> Self {
+        let messages = chat
+            .iter()
+            .map(|message| {
+                minijinja::context! {
+                    role => String::from_utf8_lossy(message.role.as_bytes()),
+                    content => String::from_utf8_lossy(message.content.as_bytes()),
+                }
+            })
+            .collect();
+        Self::from_messages_value(messages)
+    }
+
+    /// Create inputs from any serializable list of messages, e.g. a `serde_json::Value` containing
+    /// messages with `tool_calls` or `tool_call_id` fields.
+    #[must_use]
+    pub fn from_messages(messages: &impl Serialize) -> Self {
+        Self::from_messages_value(Value::from_serialize(messages))
+    }
+
+    fn from_messages_value(messages: Value) -> Self {
+        Self {
+            messages,
+            ..Self::default()
+        }
+    }
+
+    /// Set the tool definitions exposed to the template as `tools`.
+    #[must_use]
+    pub fn with_tools(mut self, tools: &impl Serialize) -> Self {
+        self.tools = Some(Value::from_serialize(tools));
+        self
+    }
+
+    /// Set whether the template should end with the opening of an assistant message.
+    #[must_use]
+    pub fn with_add_generation_prompt(mut self, add_generation_prompt: bool) -> Self {
+        self.add_generation_prompt = add_generation_prompt;
+        self
+    }
+
+    /// Set an additional variable used by some templates, e.g. `documents` or
+    /// `enable_thinking`. Variables set here take precedence over the built-in ones.
+    #[must_use]
+    pub fn with_extra(mut self, name: impl Into<String>, value: &impl Serialize) -> Self {
+        self.extra.insert(name.into(), Value::from_serialize(value));
+        self
+    }
+
+    /// Whether the template should end with the opening of an assistant message.
+    #[must_use]
+    pub fn add_generation_prompt(&self) -> bool {
+        self.add_generation_prompt
+    }
+}
+
+/// A chat template rendered with a Jinja engine, supporting the variables and helpers used by
+/// Hugging Face style templates: `messages`, `tools`, `add_generation_prompt`, `bos_token`,
+/// `eos_token`, `raise_exception` and `strftime_now`, as well as the python string and dict
+/// methods templates commonly call.
+#[derive(Debug)]
+pub struct JinjaChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl JinjaChatTemplate {
+    /// Compile a template from its source.
+    ///
+    /// # Errors
+    ///
+    /// If the template is not valid Jinja.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::chat::jinja::{JinjaChatInputs, JinjaChatTemplate};
+    /// use llama_cpp_2::model::LlamaChatMessage;
+    ///
+    /// let template = JinjaChatTemplate::new(
+    ///     "{{ bos_token }}{% for m in messages %}<|{{ m.role }}|>{{ m.content.strip() }}\n{% endfor %}\
+    ///      {% if add_generation_prompt %}<|assistant|>{% endif %}",
+    ///     "<s>",
+    ///     "</s>",
+    /// ).unwrap();
+    /// let chat = [LlamaChatMessage::new("user".into(), " Hi! ".into()).unwrap()];
+    /// let prompt = template
+    ///     .render(&JinjaChatInputs::new(&chat).with_add_generation_prompt(true))
+    ///     .unwrap();
+    /// assert_eq!(prompt, "<s><|user|>Hi!\n<|assistant|>");
+    /// ```
+    pub fn new(
+        source: impl Into<String>,
+        bos_token: impl Into<String>,
+        eos_token: impl Into<String>,
+    ) -> Result<Self, JinjaTemplateError> {
+        let mut env = Environment::new();
+        // the same settings transformers uses for chat templates.
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_filter("tojson", tojson);
+        env.add_function("raise_exception", raise_exception);
+        env.add_function("strftime_now", strftime_now);
+        env.add_template_owned(TEMPLATE_NAME, source.into())?;
+
+        Ok(Self {
+            env,
+            bos_token: bos_token.into(),
+            eos_token: eos_token.into(),
+        })
+    }
+
+    /// Load the chat template (by name) of a model, using the model's BOS and EOS token texts.
+    ///
+    /// # Errors
+    ///
+    /// See [`JinjaTemplateError`] for more information.
+    pub fn from_model(model: &LlamaModel, name: Option<&str>) -> Result<Self, JinjaTemplateError> {
+        let source = model.chat_template(name)?.to_string()?;
+        let token_text = |token: LlamaToken| {
+            if (0..model.n_vocab()).contains(&token.0) {
+                model.token_text(token).map(str::to_string)
+            } else {
+                Ok(String::new())
+            }
+        };
+        Self::new(
+            source,
+            token_text(model.token_bos())?,
+            token_text(model.token_eos())?,
+        )
+    }
+
+    /// Render the template.
+    ///
+    /// # Errors
+    ///
+    /// If rendering fails or the template raises an exception.
+    pub fn render(&self, inputs: &JinjaChatInputs) -> Result<String, JinjaTemplateError> {
+        let mut ctx = BTreeMap::new();
+        ctx.insert("messages".to_string(), inputs.messages.clone());
+        if let Some(tools) = &inputs.tools {
+            ctx.insert("tools".to_string(), tools.clone());
+        }
+        ctx.insert(
+            "add_generation_prompt".to_string(),
+            Value::from(inputs.add_generation_prompt),
+        );
+        ctx.insert(
+            "bos_token".to_string(),
+            Value::from(self.bos_token.as_str()),
+        );
+        ctx.insert(
+            "eos_token".to_string(),
+            Value::from(self.eos_token.as_str()),
+        );
+        ctx.extend(
+            inputs
+                .extra
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+
+        let template = self.env.get_template(TEMPLATE_NAME)?;
+        Ok(template.render(Value::from_iter(ctx))?)
+    }
+}
+
+fn raise_exception(message: &str) -> Result<Value, minijinja::Error> {
+    Err(minijinja::Error::new(
+        ErrorKind::InvalidOperation,
+        message.to_string(),
+    ))
+}
+
+/// `json.dumps` style serialization. Unlike the minijinja built-in this does not escape html
+/// characters and uses the python separators, matching what models were trained on. Object keys
+/// come out in the order minijinja keeps them, which is sorted.
+// minijinja requires the keyword arguments to be taken by value.
+#[allow(clippy::needless_pass_by_value)]
+fn tojson(value: &Value, kwargs: Kwargs) -> Result<Value, minijinja::Error> {
+    let indent: Option<usize> = kwargs.get("indent")?;
+    // accepted for compatibility, non-ascii characters are always kept.
+    let _: Option<bool> = kwargs.get("ensure_ascii")?;
+    kwargs.assert_all_used()?;
+
+    let mut out = Vec::new();
+    let result = match indent {
+        Some(indent) => {
+            let indent = " ".repeat(indent);
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+            value.serialize(&mut serde_json::Serializer::with_formatter(
+                &mut out, formatter,
+            ))
+        }
+        None => value.serialize(&mut serde_json::Serializer::with_formatter(
+            &mut out,
+            PythonFormatter,
+        )),
+    };
+    result.map_err(|e| minijinja::Error::new(ErrorKind::BadSerialization, e.to_string()))?;
+    let json = String::from_utf8(out)
+        .map_err(|e| minijinja::Error::new(ErrorKind::BadSerialization, e.to_string()))?;
+    Ok(Value::from_safe_string(json))
+}
+
+/// Formats json with `", "` and `": "` separators.
+struct PythonFormatter;
+
+impl serde_json::ser::Formatter for PythonFormatter {
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b": ")
+    }
+}
+
+fn strftime_now(format: &str) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    strftime(format, secs)
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// Format a UTC unix timestamp with the common `strftime` directives. Unknown directives are
+/// kept as is.
+#[allow(clippy::many_single_char_names)]
+fn strftime(format: &str, unix_secs: u64) -> String {
+    let days = unix_secs / 86_400;
+    let secs_of_day = unix_secs % 86_400;
+    let (hour, minute, second) = (secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60);
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let is_leap = matches!((year % 4, year % 100, year % 400), (0, 1.., _) | (_, _, 0));
+    let day_of_year = if month > 2 {
+        doy + 60 + u64::from(is_leap)
+    } else {
+        doy - 305
+    };
+    // 1970-01-01 was a thursday
+    let weekday = WEEKDAYS[usize::try_from((days + 4) % 7).unwrap_or_default()];
+    let month_name = MONTHS[usize::try_from(month - 1).unwrap_or_default()];
+    let hour_12 = match hour % 12 {
+        0 => 12,
+        hour_12 => hour_12,
+    };
+
+    let mut out = String::with_capacity(format.len() * 2);
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => write!(out, "{year}"),
+            Some('y') => write!(out, "{:02}", year % 100),
+            Some('m') => write!(out, "{month:02}"),
+            Some('d') => write!(out, "{day:02}"),
+            Some('e') => write!(out, "{day:>2}"),
+            Some('j') => write!(out, "{day_of_year:03}"),
+            Some('H') => write!(out, "{hour:02}"),
+            Some('I') => write!(out, "{hour_12:02}"),
+            Some('M') => write!(out, "{minute:02}"),
+            Some('S') => write!(out, "{second:02}"),
+            Some('p') => out.write_str(if hour < 12 { "AM" } else { "PM" }),
+            Some('B') => out.write_str(month_name),
+            Some('b' | 'h') => out.write_str(&month_name[..3]),
+            Some('A') => out.write_str(weekday),
+            Some('a') => out.write_str(&weekday[..3]),
+            Some('%') | None => out.write_char('%'),
+            Some(other) => write!(out, "%{other}"),
+        }
+        .expect("writing to a string cannot fail");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tool of the `tojson` tests, with its keys sorted as minijinja keeps them.
+    fn tool() -> serde_json::Value {
+        serde_json::json!({
+            "n": [1, 2.5, true, null, [], {}],
+            "name": "get_weather",
+            "parameters": {
+                "properties": {
+                    "city": { "description": "Zürich \"old\" town\n", "type": "string" }
+                },
+                "required": ["city"],
+                "type": "object"
+            }
+        })
+    }
+
+    fn render(source: &str) -> Result<String, JinjaTemplateError> {
+        let template = JinjaChatTemplate::new(source, "<s>", "</s>")?;
+        template.render(&JinjaChatInputs::new(&[]).with_extra("tool", &tool()))
+    }
+
+    #[test]
+    fn tojson_matches_python() {
+        // json.dumps(tool, ensure_ascii=False)
+        assert_eq!(
+            render("{{ tool | tojson }}").unwrap(),
+            r#"{"n": [1, 2.5, true, null, [], {}], "name": "get_weather", "parameters": {"properties": {"city": {"description": "Zürich \"old\" town\n", "type": "string"}}, "required": ["city"], "type": "object"}}"#
+        );
+        assert_eq!(
+            render("{{ tool | tojson(ensure_ascii=False) }}").unwrap(),
+            render("{{ tool | tojson }}").unwrap()
+        );
+    }
+
+    #[test]
+    fn tojson_with_indent_matches_python() {
+        // json.dumps(tool, ensure_ascii=False, indent=2)
+        let expected = r#"{
+  "n": [
+    1,
+    2.5,
+    true,
+    null,
+    [],
+    {}
+  ],
+  "name": "get_weather",
+  "parameters": {
+    "properties": {
+      "city": {
+        "description": "Zürich \"old\" town\n",
+        "type": "string"
+      }
+    },
+    "required": [
+      "city"
+    ],
+    "type": "object"
+  }
+}"#;
+        assert_eq!(render("{{ tool | tojson(indent=2) }}").unwrap(), expected);
+    }
+
+    #[test]
+    fn tojson_rejects_unknown_arguments() {
+        assert!(render("{{ tool | tojson(sort_keys=true) }}").is_err());
+    }
+
+    #[test]
+    fn raise_exception_fails_rendering() {
+        let err = render("{{ raise_exception('only user and assistant roles') }}").unwrap_err();
+        assert!(err.to_string().contains("only user and assistant roles"));
+    }
+
+    #[test]
+    fn strftime_leap_day() {
+        // 2024-02-29 13:05:09 UTC
+        assert_eq!(
+            strftime(
+                "%Y-%m-%d %j %a %A %b %B %H:%M:%S %I %p %y %e %%",
+                1_709_211_909
+            ),
+            "2024-02-29 060 Thu Thursday Feb February 13:05:09 01 PM 24 29 %"
+        );
+    }
+
+    #[test]
+    fn strftime_last_day_of_a_leap_century() {
+        // 2000-12-31 23:59:59 UTC
+        assert_eq!(
+            strftime(
+                "%Y-%m-%d %j %a %A %b %B %H:%M:%S %I %p %y %e %%",
+                978_307_199
+            ),
+            "2000-12-31 366 Sun Sunday Dec December 23:59:59 11 PM 00 31 %"
+        );
+    }
+
+    #[test]
+    fn strftime_epoch() {
+        assert_eq!(
+            strftime("%Y-%m-%d %j %a %A %b %B %H:%M:%S %I %p %y %e %%", 0),
+            "1970-01-01 001 Thu Thursday Jan January 00:00:00 12 AM 70  1 %"
+        );
+        assert_eq!(strftime("%Q 100%", 0), "%Q 100%");
+    }
+
+    #[test]
+    fn strftime_now_is_available_to_templates() {
+        let year = render("{{ strftime_now('%Y') }}").unwrap();
+        assert_eq!(year.len(), 4);
+        assert!(year.chars().all(|c| c.is_ascii_digit()));
+    }
+}
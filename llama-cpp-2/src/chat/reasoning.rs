@@ -0,0 +1,379 @@
+//! Separate the reasoning ("thinking") block of reasoning models from their answer.
+
+use crate::chat::template::ChatTemplateFamily;
+use crate::generation::stop::partial_match_len;
+
+/// The tags that delimit the reasoning block of a model.
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::chat::reasoning::{ReasoningFormat, ReasoningSplitter};
+///
+/// let mut splitter = ReasoningSplitter::new(ReasoningFormat::think());
+/// let mut reasoning = String::new();
+/// let mut content = String::new();
+/// for piece in ["<th", "ink>", "Two plus", " two.</thi", "nk>\n\n", "4"] {
+///     let delta = splitter.push(piece);
+///     reasoning.push_str(&delta.reasoning);
+///     content.push_str(&delta.content);
+/// }
+/// let delta = splitter.finish();
+/// content.push_str(&delta.content);
+///
+/// assert_eq!(reasoning, "Two plus two.");
+/// assert_eq!(content, "4");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ReasoningFormat {
+    start: String,
+    end: String,
+    starts_in_reasoning: bool,
+}
+
+impl ReasoningFormat {
+    /// A reasoning block delimited by `start` and `end`.
+    #[must_use]
+    pub fn new(start: impl Into<String>, end: impl Into<String>) -> Self {
+        Self {
+            start: start.into(),
+            end: end.into(),
+            starts_in_reasoning: false,
+        }
+    }
+
+    /// `<think>...</think>`, used by `DeepSeek` R1, Qwen 3, `QwQ` and most other reasoning models.
+    #[must_use]
+    pub fn think() -> Self {
+        Self::new("<think>", "</think>")
+    }
+
+    /// The reasoning format of a template family, if the family has a known one.
+    ///
+    /// `DeepSeek` V3 templates open the reasoning block in the prompt, so generation for them
+    /// starts in the reasoning block.
+    #[must_use]
+    pub fn for_family(family: ChatTemplateFamily) -> Option<Self> {
+        match family {
+            ChatTemplateFamily::ChatMl | ChatTemplateFamily::Exaone => Some(Self::think()),
+            ChatTemplateFamily::DeepSeek3 => Some(Self::think().with_starts_in_reasoning(true)),
+            ChatTemplateFamily::CommandR => {
+                Some(Self::new("<|START_THINKING|>", "<|END_THINKING|>"))
+            }
+            ChatTemplateFamily::SeedOss => Some(Self::new("<seed:think>", "</seed:think>")),
+            _ => None,
+        }
+    }
+
+    /// Whether the generated text starts inside the reasoning block because the prompt already
+    /// opened it. A start tag the model generates anyway is ignored.
+    #[must_use]
+    pub fn with_starts_in_reasoning(mut self, starts_in_reasoning: bool) -> Self {
+        self.starts_in_reasoning = starts_in_reasoning;
+        self
+    }
+
+    /// The tag that opens the reasoning block.
+    #[must_use]
+    pub fn start(&self) -> &str {
+        &self.start
+    }
+
+    /// The tag that closes the reasoning block.
+    #[must_use]
+    pub fn end(&self) -> &str {
+        &self.end
+    }
+
+    /// See [`Self::with_starts_in_reasoning`].
+    #[must_use]
+    pub fn starts_in_reasoning(&self) -> bool {
+        self.starts_in_reasoning
+    }
+}
+
+/// The output of [`ReasoningSplitter::push`] and [`ReasoningSplitter::finish`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReasoningDelta {
+    /// Text that belongs to the reasoning block, without the tags.
+    pub reasoning: String,
+    /// Text that belongs to the answer.
+    pub content: String,
+    /// The reasoning budget was exhausted and the splitter closed the reasoning block. The end tag
+    /// should be fed to the model so it starts answering.
+    pub close_reasoning: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Only whitespace was generated so far.
+    Start,
+    /// Inside the reasoning block, before any reasoning text.
+    ReasoningStart,
+    Reasoning,
+    /// Right after the end tag, where whitespace is dropped.
+    ContentStart,
+    Content,
+}
+
+/// Incrementally sorts generated text into reasoning and content.
+///
+/// A reasoning block is only recognized at the very start of the generated text, so tags that
+/// are mentioned later in the answer stay part of the content. Text that may be the beginning of
+/// a tag split across tokens is held back until it is known whether it is a tag.
+#[derive(Debug, Clone)]
+pub struct ReasoningSplitter {
+    format: ReasoningFormat,
+    budget: Option<usize>,
+    state: State,
+    buffer: String,
+    reasoning_tokens: usize,
+    forced_close: bool,
+}
+
+impl ReasoningSplitter {
+    /// Create a new splitter for `format` without a reasoning budget.
+    #[must_use]
+    pub fn new(format: ReasoningFormat) -> Self {
+        let state = if format.starts_in_reasoning {
+            State::ReasoningStart
+        } else {
+            State::Start
+        };
+        Self {
+            format,
+            budget: None,
+            state,
+            buffer: String::new(),
+            reasoning_tokens: 0,
+            forced_close: false,
+        }
+    }
+
+    /// Close the reasoning block after `max_tokens` calls to [`Self::push`] inside it. The
+    /// splitter expects to be fed one token at a time when a budget is set.
+    #[must_use]
+    pub fn with_budget(mut self, max_tokens: usize) -> Self {
+        self.budget = Some(max_tokens);
+        self
+    }
+
+    /// The format the splitter looks for.
+    #[must_use]
+    pub fn format(&self) -> &ReasoningFormat {
+        &self.format
+    }
+
+    /// The number of pushes that happened inside the reasoning block.
+    #[must_use]
+    pub fn reasoning_tokens(&self) -> usize {
+        self.reasoning_tokens
+    }
+
+    /// Whether the reasoning block was closed because the budget ran out.
+    #[must_use]
+    pub fn forced_close(&self) -> bool {
+        self.forced_close
+    }
+
+    /// Whether the splitter is currently inside the reasoning block.
+    #[must_use]
+    pub fn in_reasoning(&self) -> bool {
+        matches!(self.state, State::ReasoningStart | State::Reasoning)
+    }
+
+    /// Feed the next piece of generated text.
+    pub fn push(&mut self, text: &str) -> ReasoningDelta {
+        let mut delta = ReasoningDelta::default();
+        if self.in_reasoning() {
+            self.reasoning_tokens += 1;
+        }
+        self.buffer.push_str(text);
+        self.drain(&mut delta, false);
+
+        if self.in_reasoning()
+            && self
+                .budget
+                .is_some_and(|budget| self.reasoning_tokens >= budget)
+        {
+            // whatever is held back can no longer turn into the end tag
+            delta.reasoning.push_str(&self.buffer);
+            self.buffer.clear();
+            self.state = State::ContentStart;
+            self.forced_close = true;
+            delta.close_reasoning = true;
+        }
+        delta
+    }
+
+    /// Signal the end of the generated text, flushing anything that was held back. The splitter
+    /// is reset and can be reused.
+    pub fn finish(&mut self) -> ReasoningDelta {
+        let mut delta = ReasoningDelta::default();
+        self.drain(&mut delta, true);
+        *self = Self {
+            budget: self.budget,
+            ..Self::new(self.format.clone())
+        };
+        delta
+    }
+
+    fn drain(&mut self, delta: &mut ReasoningDelta, end: bool) {
+        loop {
+            match self.state {
+                State::Start => {
+                    let text = self.buffer.trim_start();
+                    if text.starts_with(&self.format.start) {
+                        let skip = self.buffer.len() - text.len() + self.format.start.len();
+                        self.buffer.drain(..skip);
+                        self.reasoning_tokens += 1;
+                        self.state = State::ReasoningStart;
+                    } else if !end && self.format.start.starts_with(text) {
+                        return;
+                    } else {
+                        self.state = State::Content;
+                    }
+                }
+                State::ReasoningStart => {
+                    let text = self.buffer.trim_start();
+                    if self.format.starts_in_reasoning && text.starts_with(&self.format.start) {
+                        let skip = self.buffer.len() - text.len() + self.format.start.len();
+                        self.buffer.drain(..skip);
+                    } else if !end
+                        && self.format.starts_in_reasoning
+                        && self.format.start.starts_with(text)
+                    {
+                        return;
+                    } else {
+                        let skip = self.buffer.len() - text.len();
+                        self.buffer.drain(..skip);
+                        if self.buffer.is_empty() && !end {
+                            return;
+                        }
+                        self.state = State::Reasoning;
+                    }
+                }
+                State::Reasoning => {
+                    if let Some(idx) = self.buffer.find(&self.format.end) {
+                        delta.reasoning.push_str(self.buffer[..idx].trim_end());
+                        self.buffer.drain(..idx + self.format.end.len());
+                        self.state = State::ContentStart;
+                    } else {
+                        let keep = if end {
+                            0
+                        } else {
+                            held_back_len(&self.buffer, &self.format.end)
+                        };
+                        let emit = self.buffer.len() - keep;
+                        delta.reasoning.push_str(&self.buffer[..emit]);
+                        self.buffer.drain(..emit);
+                        return;
+                    }
+                }
+                State::ContentStart => {
+                    let skip = self.buffer.len() - self.buffer.trim_start().len();
+                    self.buffer.drain(..skip);
+                    if self.buffer.is_empty() {
+                        return;
+                    }
+                    self.state = State::Content;
+                }
+                State::Content => {
+                    delta.content.push_str(&self.buffer);
+                    self.buffer.clear();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The number of bytes at the end of `text` that must be held back because they may be the
+/// beginning of `tag`, including trailing whitespace that is dropped if the tag follows.
+fn held_back_len(text: &str, tag: &str) -> usize {
+    let partial = partial_match_len(text, tag);
+    let before = &text[..text.len() - partial];
+    partial + before.len() - before.trim_end().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `pieces` one after another and collect the reasoning and the content.
+    fn split<'a>(
+        splitter: &mut ReasoningSplitter,
+        pieces: impl IntoIterator<Item = &'a str>,
+    ) -> (String, String) {
+        let mut reasoning = String::new();
+        let mut content = String::new();
+        for piece in pieces {
+            let delta = splitter.push(piece);
+            reasoning.push_str(&delta.reasoning);
+            content.push_str(&delta.content);
+        }
+        let delta = splitter.finish();
+        reasoning.push_str(&delta.reasoning);
+        content.push_str(&delta.content);
+        (reasoning, content)
+    }
+
+    fn chars(text: &str) -> Vec<&str> {
+        text.char_indices()
+            .map(|(i, c)| &text[i..i + c.len_utf8()])
+            .collect()
+    }
+
+    #[test]
+    fn tags_split_across_pieces() {
+        let text = "\n<think>\nAdd them: 2 + 2 = 4.\n</think>\n\nIt is 4. ✓";
+        let mut splitter = ReasoningSplitter::new(ReasoningFormat::think());
+        assert_eq!(
+            split(&mut splitter, chars(text)),
+            ("Add them: 2 + 2 = 4.".to_string(), "It is 4. ✓".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_end_tag() {
+        let mut splitter = ReasoningSplitter::new(ReasoningFormat::think());
+        let (reasoning, content) = split(&mut splitter, ["<think>", "still", " thinking </th"]);
+        assert_eq!(reasoning, "still thinking </th");
+        assert_eq!(content, "");
+    }
+
+    #[test]
+    fn starts_in_reasoning() {
+        let format = ReasoningFormat::think().with_starts_in_reasoning(true);
+        let mut splitter = ReasoningSplitter::new(format);
+        assert!(splitter.in_reasoning());
+        let (reasoning, content) = split(&mut splitter, ["Plan", " it.</think>", "Done."]);
+        assert_eq!(reasoning, "Plan it.");
+        assert_eq!(content, "Done.");
+
+        // a start tag the model generates anyway is dropped
+        let (reasoning, content) = split(&mut splitter, chars("<think>Plan.</think>Done."));
+        assert_eq!(reasoning, "Plan.");
+        assert_eq!(content, "Done.");
+    }
+
+    #[test]
+    fn tags_after_the_start_are_content() {
+        let mut splitter = ReasoningSplitter::new(ReasoningFormat::think());
+        let (reasoning, content) = split(&mut splitter, ["<th", "e end> uses <think>"]);
+        assert_eq!(reasoning, "");
+        assert_eq!(content, "<the end> uses <think>");
+    }
+
+    #[test]
+    fn budget_closes_the_block() {
+        let mut splitter = ReasoningSplitter::new(ReasoningFormat::think()).with_budget(3);
+        assert!(!splitter.push("<think>").close_reasoning);
+        assert_eq!(splitter.push("one").reasoning, "one");
+        let delta = splitter.push(" two</th");
+        assert!(delta.close_reasoning);
+        assert_eq!(delta.reasoning, " two</th");
+        assert!(splitter.forced_close());
+        assert_eq!(splitter.push("\nanswer").content, "answer");
+    }
+}
@@ -0,0 +1,424 @@
+//! Classify chat templates into the families llama.cpp knows how to render.
+
+use std::ffi::{c_char, CStr};
+
+use crate::model::{LlamaChatTemplate, LlamaModel};
+use crate::tool_calls::ToolCallFormat;
+use crate::ChatTemplateError;
+
+/// A family of chat templates that share a prompt format.
+///
+/// This mirrors the template detection of llama.cpp (`llm_chat_detect_template` in
+/// `src/llama-chat.cpp`), merging the variants it distinguishes only for rendering details, such
+/// as the different Mistral versions. llama.cpp does not expose its detection, so
+/// [`Self::detect`] and [`Self::from_builtin_name`] have to be kept in sync with it when the
+/// llama.cpp submodule is updated. The tests of this module fail for builtin templates that are
+/// missing here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ChatTemplateFamily {
+    /// `<|im_start|>role\n...<|im_end|>`, used by Qwen, Hermes, Yi and many fine-tunes.
+    ChatMl,
+    /// Llama 2 `[INST] ... [/INST]`, with or without a `<<SYS>>` block.
+    Llama2,
+    /// Mistral `[INST]` templates (v1, v3, v3-tekken and v7).
+    Mistral,
+    /// Phi 3 `<|user|>...<|end|>`.
+    Phi3,
+    /// Phi 4, a `ChatML` variant with `<|im_sep|>`.
+    Phi4,
+    /// Falcon 3.
+    Falcon3,
+    /// Zephyr `<|user|>...<|endoftext|>`.
+    Zephyr,
+    /// Monarch.
+    Monarch,
+    /// Gemma `<start_of_turn>...<end_of_turn>`.
+    Gemma,
+    /// Orion.
+    Orion,
+    /// `OpenChat` `GPT4 Correct User:`.
+    OpenChat,
+    /// Vicuna `USER: ... ASSISTANT:`, with or without the Orca `SYSTEM:` prefix.
+    Vicuna,
+    /// `DeepSeek` Coder `### Instruction:`.
+    DeepSeek,
+    /// `DeepSeek` V2.
+    DeepSeek2,
+    /// `DeepSeek` V3 and R1.
+    DeepSeek3,
+    /// Cohere Command-R.
+    CommandR,
+    /// Llama 3 `<|start_header_id|>...<|eot_id|>`.
+    Llama3,
+    /// Llama 4 `<|header_start|>...<|eot|>`.
+    Llama4,
+    /// `ChatGLM` 3.
+    ChatGlm3,
+    /// `ChatGLM` 4 and GLM 4.
+    ChatGlm4,
+    /// GLM Edge.
+    GlmEdge,
+    /// `MiniCPM`.
+    MiniCpm,
+    /// EXAONE 3 and 4.
+    Exaone,
+    /// RWKV World.
+    RwkvWorld,
+    /// IBM Granite `<|start_of_role|>`.
+    Granite,
+    /// `GigaChat`.
+    GigaChat,
+    /// Megrez.
+    Megrez,
+    /// `YandexGPT`.
+    Yandex,
+    /// Ling (Bailing).
+    Bailing,
+    /// `SmolVLM`.
+    SmolVlm,
+    /// Hunyuan mixture of experts.
+    HunyuanMoe,
+    /// Hunyuan dense.
+    HunyuanDense,
+    /// gpt-oss harmony `<|start|>...<|channel|>`.
+    GptOss,
+    /// Kimi K2.
+    KimiK2,
+    /// Seed-OSS.
+    SeedOss,
+    /// Grok 2.
+    Grok2,
+    /// dots.llm1.
+    Dots1,
+}
+
+impl ChatTemplateFamily {
+    /// Classify a chat template, which can be either the source of a Jinja template or the name of
+    /// a template built into llama.cpp. Returns `None` for templates llama.cpp does not recognize.
+    ///
+    /// ```rust
+    /// use llama_cpp_2::chat::template::ChatTemplateFamily;
+    ///
+    /// let chatml = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}";
+    /// assert_eq!(ChatTemplateFamily::detect(chatml), Some(ChatTemplateFamily::ChatMl));
+    /// assert_eq!(ChatTemplateFamily::detect("llama3"), Some(ChatTemplateFamily::Llama3));
+    /// assert_eq!(ChatTemplateFamily::detect("{{ messages }}"), None);
+    /// ```
+    #[must_use]
+    #[allow(clippy::too_many_lines)]
+    pub fn detect(template: &str) -> Option<Self> {
+        if let Some(family) = Self::from_builtin_name(template) {
+            return Some(family);
+        }
+        let contains = |needle: &str| template.contains(needle);
+        let family = if contains("<|im_start|>") {
+            if contains("<|im_sep|>") {
+                Self::Phi4
+            } else if contains("<end_of_utterance>") {
+                Self::SmolVlm
+            } else {
+                Self::ChatMl
+            }
+        } else if template.starts_with("mistral") || contains("[INST]") {
+            if contains("[SYSTEM_PROMPT]")
+                || contains("' [INST] ' + system_message")
+                || contains("[AVAILABLE_TOOLS]")
+            {
+                Self::Mistral
+            } else {
+                Self::Llama2
+            }
+        } else if contains("<|assistant|>") && contains("<|end|>") {
+            Self::Phi3
+        } else if contains("[gMASK]<sop>") {
+            Self::ChatGlm4
+        } else if contains("<|assistant|>") && contains("<|user|>") {
+            if contains("</s>") {
+                Self::Falcon3
+            } else {
+                Self::GlmEdge
+            }
+        } else if contains("<|{{ item['role'] }}|>") && contains("<|begin_of_image|>") {
+            Self::GlmEdge
+        } else if contains("<|user|>") && contains("<|endoftext|>") {
+            Self::Zephyr
+        } else if contains("bos_token + message['role']") {
+            Self::Monarch
+        } else if contains("<start_of_turn>") {
+            Self::Gemma
+        } else if contains("'\\n\\nAssistant: ' + eos_token") {
+            Self::Orion
+        } else if contains("GPT4 Correct ") {
+            Self::OpenChat
+        } else if contains("USER: ") && contains("ASSISTANT: ") {
+            Self::Vicuna
+        } else if contains("### Instruction:") && contains("<|EOT|>") {
+            Self::DeepSeek
+        } else if contains("<|START_OF_TURN_TOKEN|>") && contains("<|USER_TOKEN|>") {
+            Self::CommandR
+        } else if contains("<|start_header_id|>") && contains("<|end_header_id|>") {
+            Self::Llama3
+        } else if contains("[gMASK]sop") {
+            Self::ChatGlm3
+        } else if contains("<用户>") {
+            Self::MiniCpm
+        } else if contains("'Assistant: ' + message['content'] + eos_token") {
+            Self::DeepSeek2
+        } else if contains("<｜Assistant｜>")
+            && contains("<｜User｜>")
+            && contains("<｜end▁of▁sentence｜>")
+        {
+            Self::DeepSeek3
+        } else if contains("[|system|]") && contains("[|assistant|]") && contains("[|endofturn|]")
+        {
+            Self::Exaone
+        } else if contains("rwkv-world")
+            || contains("{{- 'User: ' + message['content']|trim + '\\n\\n' -}}")
+        {
+            Self::RwkvWorld
+        } else if contains("<|start_of_role|>") {
+            Self::Granite
+        } else if contains(
+            "message['role'] + additional_special_tokens[0] + message['content'] + additional_special_tokens[1]",
+        ) {
+            Self::GigaChat
+        } else if contains("<|role_start|>") {
+            Self::Megrez
+        } else if contains(" Ассистент:") {
+            Self::Yandex
+        } else if contains("<role>ASSISTANT</role>") && contains("'HUMAN'") {
+            Self::Bailing
+        } else if contains("<|header_start|>") && contains("<|header_end|>") {
+            Self::Llama4
+        } else if contains("<|endofuserprompt|>") {
+            Self::Dots1
+        } else if contains("<|extra_0|>") && contains("<|extra_4|>") {
+            Self::HunyuanMoe
+        } else if contains("<|start|>") && contains("<|channel|>") {
+            Self::GptOss
+        } else if contains("<｜hy_Assistant｜>") && contains("<｜hy_place▁holder▁no▁3｜>") {
+            Self::HunyuanDense
+        } else if contains("<|im_assistant|>assistant<|im_middle|>") {
+            Self::KimiK2
+        } else if contains("<seed:bos>") {
+            Self::SeedOss
+        } else if contains("'Assistant: '  + message['content'] + '<|separator|>") {
+            Self::Grok2
+        } else {
+            return None;
+        };
+        Some(family)
+    }
+
+    /// Map the name of a template built into llama.cpp (see [`builtin_chat_templates`]) to its
+    /// family.
+    #[must_use]
+    pub fn from_builtin_name(name: &str) -> Option<Self> {
+        let family = match name {
+            "chatml" => Self::ChatMl,
+            "llama2" | "llama2-sys" | "llama2-sys-bos" | "llama2-sys-strip" => Self::Llama2,
+            "mistral-v1" | "mistral-v3" | "mistral-v3-tekken" | "mistral-v7"
+            | "mistral-v7-tekken" => Self::Mistral,
+            "phi3" => Self::Phi3,
+            "phi4" => Self::Phi4,
+            "falcon3" => Self::Falcon3,
+            "zephyr" => Self::Zephyr,
+            "monarch" => Self::Monarch,
+            "gemma" => Self::Gemma,
+            "orion" => Self::Orion,
+            "openchat" => Self::OpenChat,
+            "vicuna" | "vicuna-orca" => Self::Vicuna,
+            "deepseek" => Self::DeepSeek,
+            "deepseek2" => Self::DeepSeek2,
+            "deepseek3" => Self::DeepSeek3,
+            "command-r" => Self::CommandR,
+            "llama3" => Self::Llama3,
+            "llama4" => Self::Llama4,
+            "chatglm3" => Self::ChatGlm3,
+            "chatglm4" => Self::ChatGlm4,
+            "glmedge" => Self::GlmEdge,
+            "minicpm" => Self::MiniCpm,
+            "exaone3" | "exaone4" => Self::Exaone,
+            "rwkv-world" => Self::RwkvWorld,
+            "granite" => Self::Granite,
+            "gigachat" => Self::GigaChat,
+            "megrez" => Self::Megrez,
+            "yandex" => Self::Yandex,
+            "bailing" | "bailing-think" | "bailing2" => Self::Bailing,
+            "smolvlm" => Self::SmolVlm,
+            "hunyuan-moe" => Self::HunyuanMoe,
+            "hunyuan-dense" => Self::HunyuanDense,
+            "gpt-oss" => Self::GptOss,
+            "kimi-k2" => Self::KimiK2,
+            "seed_oss" => Self::SeedOss,
+            "grok-2" => Self::Grok2,
+            "dots1" => Self::Dots1,
+            _ => return None,
+        };
+        Some(family)
+    }
+
+    /// Strings that end an assistant turn in this family, for models whose end of turn token is
+    /// not marked as end of generation. Empty if the family has no such marker or it is unknown.
+    #[must_use]
+    pub fn stop_sequences(self) -> &'static [&'static str] {
+        match self {
+            Self::ChatMl | Self::Phi4 | Self::SmolVlm | Self::KimiK2 => &["<|im_end|>"],
+            Self::Llama2 | Self::Mistral => &["</s>"],
+            Self::Phi3 => &["<|end|>"],
+            Self::Zephyr | Self::Falcon3 => &["<|endoftext|>"],
+            Self::Gemma => &["<end_of_turn>"],
+            Self::CommandR => &["<|END_OF_TURN_TOKEN|>"],
+            Self::Llama3 => &["<|eot_id|>", "<|eom_id|>"],
+            Self::Llama4 => &["<|eot|>"],
+            Self::DeepSeek => &["<|EOT|>"],
+            Self::DeepSeek3 => &["<｜end▁of▁sentence｜>"],
+            Self::Exaone => &["[|endofturn|]"],
+            Self::Granite => &["<|end_of_text|>"],
+            Self::GptOss => &["<|return|>", "<|call|>"],
+            _ => &[],
+        }
+    }
+
+    /// The tool call format models of this family are usually trained on. Prefer
+    /// [`ToolCallFormat::from_template`] when the template source is available, as it can tell
+    /// fine-tunes apart.
+    #[must_use]
+    pub fn tool_call_format(self) -> Option<ToolCallFormat> {
+        match self {
+            Self::ChatMl => Some(ToolCallFormat::Hermes),
+            Self::Llama3 => Some(ToolCallFormat::Llama3),
+            Self::Mistral => Some(ToolCallFormat::Mistral),
+            _ => None,
+        }
+    }
+}
+
+impl LlamaChatTemplate {
+    /// Classify the template, see [`ChatTemplateFamily::detect`].
+    #[must_use]
+    pub fn family(&self) -> Option<ChatTemplateFamily> {
+        self.to_str().ok().and_then(ChatTemplateFamily::detect)
+    }
+}
+
+impl LlamaModel {
+    /// Classify the chat template `name` of the model, or the default template if `name` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// If the model has no chat template by that name.
+    pub fn chat_template_family(
+        &self,
+        name: Option<&str>,
+    ) -> Result<Option<ChatTemplateFamily>, ChatTemplateError> {
+        Ok(self.chat_template(name)?.family())
+    }
+}
+
+/// The names of the chat templates built into llama.cpp, such as `chatml` or `llama3`. These can
+/// be passed to [`LlamaChatTemplate::new`] in place of a Jinja template.
+#[must_use]
+pub fn builtin_chat_templates() -> Vec<String> {
+    let len = unsafe { llama_cpp_sys_2::llama_chat_builtin_templates(std::ptr::null_mut(), 0) };
+    let mut names: Vec<*const c_char> = vec![std::ptr::null(); usize::try_from(len).unwrap_or(0)];
+    let len =
+        unsafe { llama_cpp_sys_2::llama_chat_builtin_templates(names.as_mut_ptr(), names.len()) };
+    names.truncate(usize::try_from(len).unwrap_or(0));
+    names
+        .into_iter()
+        .map(|name| {
+            unsafe { CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_names_are_known() {
+        let names = builtin_chat_templates();
+        assert!(!names.is_empty());
+        for name in names {
+            assert!(
+                ChatTemplateFamily::from_builtin_name(&name).is_some(),
+                "llama.cpp has a new builtin template {name}, add it to ChatTemplateFamily"
+            );
+        }
+    }
+
+    /// One template per branch of [`ChatTemplateFamily::detect`], in the order llama.cpp checks
+    /// them. Templates that contain the markers of several families pin that order.
+    #[test]
+    fn detects_template_sources() {
+        use ChatTemplateFamily as F;
+
+        let cases = [
+            ("<|im_start|>user<|im_end|>", F::ChatMl),
+            ("<|im_start|>user<|im_sep|>", F::Phi4),
+            ("<|im_start|>User:<end_of_utterance>", F::SmolVlm),
+            ("mistral-v7", F::Mistral),
+            ("[INST] {{ m }} [/INST]", F::Llama2),
+            ("[SYSTEM_PROMPT][INST] {{ m }} [/INST]", F::Mistral),
+            ("[AVAILABLE_TOOLS][INST] {{ m }} [/INST]", F::Mistral),
+            ("<|user|>{{ m }}<|end|><|assistant|>", F::Phi3),
+            ("[gMASK]<sop><|user|>{{ m }}<|assistant|>", F::ChatGlm4),
+            ("<|user|>{{ m }}</s><|assistant|>", F::Falcon3),
+            ("<|user|>{{ m }}<|assistant|>", F::GlmEdge),
+            ("<|{{ item['role'] }}|><|begin_of_image|>", F::GlmEdge),
+            ("<|user|>{{ m }}<|endoftext|>", F::Zephyr),
+            ("{{ bos_token + message['role'] }}", F::Monarch),
+            ("<start_of_turn>user", F::Gemma),
+            ("{{ '\\n\\nAssistant: ' + eos_token }}", F::Orion),
+            ("GPT4 Correct User: {{ m }}", F::OpenChat),
+            ("USER: {{ m }} ASSISTANT: ", F::Vicuna),
+            ("### Instruction:\n{{ m }}<|EOT|>", F::DeepSeek),
+            ("<|START_OF_TURN_TOKEN|><|USER_TOKEN|>", F::CommandR),
+            ("<|start_header_id|>user<|end_header_id|>", F::Llama3),
+            ("[gMASK]sop<|user|>", F::ChatGlm3),
+            ("<用户>{{ m }}<AI>", F::MiniCpm),
+            ("{{ 'Assistant: ' + message['content'] + eos_token }}", F::DeepSeek2),
+            (
+                "<｜User｜>{{ m }}<｜Assistant｜><｜end▁of▁sentence｜>",
+                F::DeepSeek3,
+            ),
+            ("[|system|][|assistant|][|endofturn|]", F::Exaone),
+            ("rwkv-world", F::RwkvWorld),
+            ("<|start_of_role|>user<|end_of_role|>", F::Granite),
+            (
+                "{{ message['role'] + additional_special_tokens[0] + message['content'] + additional_special_tokens[1] }}",
+                F::GigaChat,
+            ),
+            ("<|role_start|>user<|role_end|>", F::Megrez),
+            (" Пользователь: {{ m }}\n\n Ассистент:", F::Yandex),
+            ("<role>ASSISTANT</role>{{ 'HUMAN' }}", F::Bailing),
+            ("<|header_start|>user<|header_end|>", F::Llama4),
+            ("<|endofuserprompt|>", F::Dots1),
+            ("<|extra_0|>{{ m }}<|extra_4|>", F::HunyuanMoe),
+            ("<|start|>assistant<|channel|>final", F::GptOss),
+            (
+                "<｜hy_Assistant｜><｜hy_place▁holder▁no▁3｜>",
+                F::HunyuanDense,
+            ),
+            ("<|im_assistant|>assistant<|im_middle|>", F::KimiK2),
+            ("<seed:bos>user", F::SeedOss),
+            (
+                "{{ 'Assistant: '  + message['content'] + '<|separator|>' }}",
+                F::Grok2,
+            ),
+        ];
+        for (template, family) in cases {
+            assert_eq!(
+                ChatTemplateFamily::detect(template),
+                Some(family),
+                "{template}"
+            );
+        }
+        assert_eq!(ChatTemplateFamily::detect("{{ messages }}"), None);
+    }
+}
@@ -1,10 +1,17 @@
 //! Safe wrapper around `llama_context`.
 
+use std::ffi::c_void;
 use std::fmt::{Debug, Formatter};
 use std::num::NonZeroI32;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::ptr::NonNull;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use crate::context::outputs::{BatchOutput, DecodeOutputs};
+use crate::context::params::LlamaPoolingType;
+use crate::context::threadpool::ThreadPool;
 use crate::llama_batch::LlamaBatch;
 use crate::model::{LlamaLoraAdapter, LlamaModel};
 use crate::timing::LlamaTimings;
@@ -17,8 +24,10 @@ use crate::{
 };
 
 pub mod kv_cache;
+pub mod outputs;
 pub mod params;
 pub mod session;
+pub mod threadpool;
 
 /// Safe wrapper around `llama_context`.
 #[allow(clippy::module_name_repetitions)]
@@ -27,7 +36,33 @@ pub struct LlamaContext<'a> {
     /// a reference to the contexts model.
     pub model: &'a LlamaModel,
     initialized_logits: Vec<i32>,
+    outputs: Vec<BatchOutput>,
     embeddings_enabled: bool,
+    abort_callback: Option<Box<AbortCallback>>,
+    threadpools: Option<(ThreadPool, Option<ThreadPool>)>,
+}
+
+type AbortCallback = Box<dyn Fn() -> bool + Send + Sync>;
+
+/// The result of [`LlamaContext::prefill`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefillResult {
+    /// The number of decoded tokens.
+    pub n_tokens: usize,
+    /// The number of batches the tokens were split into.
+    pub n_batches: usize,
+    /// The batch index of the logits of the last token, e.g. for
+    /// [`crate::sampling::LlamaSampler::sample`].
+    pub logits_index: i32,
+    /// The position after the last token.
+    pub next_pos: i32,
+}
+
+/// Calls the [`AbortCallback`] `data` points to.
+unsafe extern "C" fn abort_callback(data: *mut c_void) -> bool {
+    let callback = unsafe { &*data.cast::<AbortCallback>() };
+    // unwinding into llama.cpp is undefined behavior
+    catch_unwind(AssertUnwindSafe(callback)).unwrap_or(true)
 }
 
 impl Debug for LlamaContext<'_> {
@@ -48,7 +83,10 @@ impl<'model> LlamaContext<'model> {
             context: llama_context,
             model: llama_model,
             initialized_logits: Vec::new(),
+            outputs: Vec::new(),
             embeddings_enabled,
+            abort_callback: None,
+            threadpools: None,
         }
     }
 
@@ -70,7 +108,14 @@ impl<'model> LlamaContext<'model> {
         unsafe { llama_cpp_sys_2::llama_n_ctx(self.context.as_ptr()) }
     }
 
-    /// Decodes the batch.
+    /// Gets the max number of sequences the context can hold.
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_n_seq_max(self.context.as_ptr()) }
+    }
+
+    /// Decodes the batch, returning a handle to the logits and embeddings of the tokens that
+    /// requested their output. The handle can be obtained again later with [`Self::outputs`].
     ///
     /// # Errors
     ///
@@ -79,20 +124,195 @@ impl<'model> LlamaContext<'model> {
     /// # Panics
     ///
     /// - the returned [`std::ffi::c_int`] from llama-cpp does not fit into a i32 (this should never happen on most systems)
-    pub fn decode(&mut self, batch: &mut LlamaBatch) -> Result<(), DecodeError> {
+    pub fn decode(
+        &mut self,
+        batch: &mut LlamaBatch,
+    ) -> Result<DecodeOutputs<'_, 'model>, DecodeError> {
+        // where llama.cpp places the tokens of a batch without positions
+        let next_pos = if batch.has_positions() {
+            0
+        } else {
+            self.kv_cache_seq_pos_max(0) + 1
+        };
         let result =
             unsafe { llama_cpp_sys_2::llama_decode(self.context.as_ptr(), batch.llama_batch) };
 
         match NonZeroI32::new(result) {
             None => {
-                self.initialized_logits
-                    .clone_from(&batch.initialized_logits);
-                Ok(())
+                self.record_outputs(batch, next_pos);
+                Ok(self.outputs())
             }
             Some(error) => Err(DecodeError::from(error)),
         }
     }
 
+    fn record_outputs(&mut self, batch: &LlamaBatch, next_pos: i32) {
+        self.initialized_logits
+            .clone_from(&batch.initialized_logits);
+        // llama.cpp outputs every token of the batch when computing embeddings
+        let indices = if self.embeddings_enabled {
+            (0..batch.n_tokens()).collect()
+        } else {
+            batch.initialized_logits.clone()
+        };
+        self.outputs = indices
+            .into_iter()
+            .map(|batch_index| batch.output(batch_index, next_pos))
+            .collect();
+        self.outputs.sort_by_key(|output| output.batch_index);
+    }
+
+    /// Abort [`Self::decode`] and [`Self::encode`] once `flag` is set, e.g. when the client that
+    /// waits for the result disconnects. The flag is not reset, clear it before decoding again.
+    ///
+    /// See [`Self::set_abort_callback`] for what is left in the kv cache after an abort.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// use llama_cpp_2::DecodeError;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let abort = Arc::new(AtomicBool::new(false));
+    /// ctx.set_abort_flag(Arc::clone(&abort));
+    ///
+    /// // e.g. from the task that watches the connection
+    /// abort.store(true, Ordering::Relaxed);
+    ///
+    /// let tokens = model.str_to_token("a long prompt", AddBos::Always)?;
+    /// assert_eq!(ctx.prefill(&tokens, 0, 0), Err(DecodeError::Aborted));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_abort_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.set_abort_callback(move || flag.load(Ordering::Relaxed));
+    }
+
+    /// Call `callback` while decoding and abort as soon as it returns `true`. It is called
+    /// frequently, possibly from the backend's worker threads, so it should be cheap. A panic in
+    /// `callback` aborts decoding.
+    ///
+    /// An aborted [`Self::decode`] returns [`DecodeError::Aborted`]. llama.cpp splits a batch into
+    /// ubatches of at most [`Self::n_ubatch`] tokens, and the ubatches that were processed before
+    /// the abort remain in the kv cache while the rest of the batch is not decoded. The logits
+    /// of the batch are not available. Remove the positions of the batch from the sequences with
+    /// [`Self::clear_kv_cache_seq`] before decoding them again. An aborted [`Self::encode`] returns
+    /// [`EncodeError::Aborted`].
+    pub fn set_abort_callback(&mut self, callback: impl Fn() -> bool + Send + Sync + 'static) {
+        let mut callback: Box<AbortCallback> = Box::new(Box::new(callback));
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                Some(abort_callback),
+                std::ptr::addr_of_mut!(*callback).cast::<c_void>(),
+            );
+        }
+        // the previous callback is dropped only after llama.cpp no longer refers to it
+        self.abort_callback = Some(callback);
+    }
+
+    /// Remove the abort callback set with [`Self::set_abort_callback`] or [`Self::set_abort_flag`].
+    pub fn clear_abort_callback(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+            );
+        }
+        self.abort_callback = None;
+    }
+
+    /// Decodes `tokens` into sequence `seq_id` starting at position `start_pos`, splitting them
+    /// into batches of at most [`Self::n_batch`] tokens. Only the logits of the last token are
+    /// computed.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let tokens = model.str_to_token(&"a very long prompt ".repeat(1000), AddBos::Always)?;
+    /// let prefill = ctx.prefill(&tokens, 0, 0)?;
+    /// let token = LlamaSampler::greedy().sample(&ctx, prefill.logits_index);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// - [`DecodeError::NTokensZero`] if `tokens` is empty.
+    /// - `DecodeError` if decoding a batch failed. The batches before it stay in the kv cache.
+    ///
+    /// # Panics
+    ///
+    /// - a position does not fit into an i32
+    pub fn prefill(
+        &mut self,
+        tokens: &[LlamaToken],
+        seq_id: i32,
+        start_pos: i32,
+    ) -> Result<PrefillResult, DecodeError> {
+        self.prefill_with_progress(tokens, seq_id, start_pos, |_, _| {})
+    }
+
+    /// Like [`Self::prefill`], calling `progress` with the number of decoded tokens and the total
+    /// number of tokens after each batch.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::prefill`].
+    ///
+    /// # Panics
+    ///
+    /// - a position does not fit into an i32
+    pub fn prefill_with_progress(
+        &mut self,
+        tokens: &[LlamaToken],
+        seq_id: i32,
+        start_pos: i32,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<PrefillResult, DecodeError> {
+        if tokens.is_empty() {
+            return Err(DecodeError::NTokensZero);
+        }
+        let n_batch = self.n_batch() as usize;
+        let mut batch = LlamaBatch::new(n_batch.min(tokens.len()), 1);
+        let mut n_batches = 0;
+        let mut n_decoded = 0;
+        for chunk in tokens.chunks(n_batch) {
+            batch.clear();
+            for (i, &token) in chunk.iter().enumerate() {
+                let index = n_decoded + i;
+                let pos = start_pos + i32::try_from(index).expect("position fits into an i32");
+                batch
+                    .add(token, pos, &[seq_id], index == tokens.len() - 1)
+                    .expect("the batch fits a chunk");
+            }
+            self.decode(&mut batch)?;
+            n_batches += 1;
+            n_decoded += chunk.len();
+            progress(n_decoded, tokens.len());
+        }
+        Ok(PrefillResult {
+            n_tokens: tokens.len(),
+            n_batches,
+            logits_index: batch.n_tokens() - 1,
+            next_pos: start_pos + i32::try_from(tokens.len()).expect("position fits into an i32"),
+        })
+    }
+
     /// Encodes the batch.
     ///
     /// # Errors
@@ -108,14 +328,66 @@ impl<'model> LlamaContext<'model> {
 
         match NonZeroI32::new(result) {
             None => {
-                self.initialized_logits
-                    .clone_from(&batch.initialized_logits);
+                // the encoder has no memory, its positions start at 0
+                self.record_outputs(batch, 0);
                 Ok(())
             }
             Some(error) => Err(EncodeError::from(error)),
         }
     }
 
+    /// Switch embeddings on or off, overriding
+    /// [`crate::context::params::LlamaContextParams::with_embeddings`]. This lets one context
+    /// both generate text and embed documents.
+    ///
+    /// Takes effect with the next batch. The getters such as [`Self::embeddings_seq_ith`] follow
+    /// the new setting.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    ///
+    /// ctx.set_embeddings(true);
+    /// let tokens = model.str_to_token("a document", AddBos::Always)?;
+    /// ctx.prefill(&tokens, 1, 0)?;
+    /// let embedding = ctx.embeddings_seq_ith(1)?.to_vec();
+    /// ctx.clear_kv_cache_seq(Some(1), None, None)?;
+    ///
+    /// // back to generating text
+    /// ctx.set_embeddings(false);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_embeddings(&mut self, embeddings: bool) {
+        unsafe { llama_cpp_sys_2::llama_set_embeddings(self.context.as_ptr(), embeddings) }
+        self.embeddings_enabled = embeddings;
+    }
+
+    /// Whether the context computes embeddings.
+    #[must_use]
+    pub fn embeddings_enabled(&self) -> bool {
+        self.embeddings_enabled
+    }
+
+    /// Switch causal attention on or off. Generative models need causal attention, while
+    /// embedding models built on top of them may want every token to attend to every other
+    /// token.
+    pub fn set_causal_attn(&mut self, causal_attn: bool) {
+        unsafe { llama_cpp_sys_2::llama_set_causal_attn(self.context.as_ptr(), causal_attn) }
+    }
+
+    /// Switch warmup mode on or off. In warmup mode every expert of a mixture of experts model
+    /// is loaded, which is useful for a first dummy decode that pages in the weights.
+    pub fn set_warmup(&mut self, warmup: bool) {
+        unsafe { llama_cpp_sys_2::llama_set_warmup(self.context.as_ptr(), warmup) }
+    }
+
     /// Get the embeddings for the `i`th sequence in the current context.
     ///
     /// # Returns
@@ -152,6 +424,46 @@ impl<'model> LlamaContext<'model> {
         }
     }
 
+    /// Get the scores of the classifier head for the `i`th sequence in the current context, which
+    /// must use [`LlamaPoolingType::Rank`].
+    ///
+    /// # Returns
+    ///
+    /// A slice containing one score per output of the classifier head for the last decoded
+    /// batch, see [`LlamaModel::n_cls_out`]. Models that do not set the number have one output.
+    ///
+    /// # Errors
+    ///
+    /// - When the current context was constructed without enabling embeddings.
+    /// - If the context does not use [`LlamaPoolingType::Rank`].
+    /// - If the given sequence index exceeds the max sequence id.
+    ///
+    /// # Panics
+    ///
+    /// * `n_cls_out` does not fit into a usize
+    pub fn rank_scores_seq_ith(&self, i: i32) -> Result<&[f32], EmbeddingsError> {
+        if !self.embeddings_enabled {
+            return Err(EmbeddingsError::NotEnabled);
+        }
+        if self.pooling_type() != LlamaPoolingType::Rank {
+            return Err(EmbeddingsError::NotRankPoolType);
+        }
+
+        // llama.cpp stores `n_cls_out` floats per sequence when ranking, not `n_embd`
+        let n_cls_out = usize::try_from(self.model.n_cls_out().max(1))
+            .expect("n_cls_out does not fit into a usize");
+
+        unsafe {
+            let scores = llama_cpp_sys_2::llama_get_embeddings_seq(self.context.as_ptr(), i);
+
+            if scores.is_null() {
+                Err(EmbeddingsError::NonePoolType)
+            } else {
+                Ok(slice::from_raw_parts(scores, n_cls_out))
+            }
+        }
+    }
+
     /// Get the embeddings for the `i`th token in the current context.
     ///
     /// # Returns
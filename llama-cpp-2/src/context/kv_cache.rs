@@ -96,6 +96,24 @@ impl LlamaContext<'_> {
         Ok(unsafe { llama_cpp_sys_2::llama_memory_seq_rm(mem, src, p0, p1) })
     }
 
+    /// Remove every position from `n_keep` on from sequence `seq_id`, e.g. the draft tokens the
+    /// target model rejected during speculative decoding.
+    ///
+    /// Returns `false` if the cache cannot remove part of a sequence, which is the case for
+    /// recurrent models.
+    ///
+    /// # Errors
+    /// If `n_keep` exceeds [`i32::MAX`].
+    pub fn rollback_kv_cache_seq(
+        &mut self,
+        seq_id: i32,
+        n_keep: usize,
+    ) -> Result<bool, KvCacheConversionError> {
+        let p0 = i32::try_from(n_keep).map_err(KvCacheConversionError::P0TooLarge)?;
+        let mem = unsafe { llama_cpp_sys_2::llama_get_memory(self.context.as_ptr()) };
+        Ok(unsafe { llama_cpp_sys_2::llama_memory_seq_rm(mem, seq_id, p0, -1) })
+    }
+
     /// Clear the KV cache
     pub fn clear_kv_cache(&mut self) {
         let mem = unsafe { llama_cpp_sys_2::llama_get_memory(self.context.as_ptr()) };
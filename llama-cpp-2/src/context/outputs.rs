@@ -0,0 +1,375 @@
+//! Access the logits and embeddings of the last decoded batch without tracking output indices by
+//! hand.
+use std::slice;
+
+use crate::context::params::LlamaPoolingType;
+use crate::context::LlamaContext;
+use crate::token::data::LlamaTokenData;
+use crate::token::LlamaToken;
+use crate::EmbeddingsError;
+
+/// Failed to look up an output of a decoded batch.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum OutputError {
+    /// The token at this batch index was added without requesting its output.
+    #[error("the token at batch index {0} has no output")]
+    NoOutput(i32),
+    /// The token at the batch index does not belong to the sequence.
+    #[error("the token at batch index {batch_index} does not belong to sequence {seq_id}")]
+    WrongSequence {
+        /// The batch index that was looked up.
+        batch_index: i32,
+        /// The sequence it was expected to belong to.
+        seq_id: i32,
+    },
+    /// No token of the sequence requested its output.
+    #[error("sequence {0} has no output in the batch")]
+    NoSequenceOutput(i32),
+    /// llama.cpp returned null for an output the batch requested.
+    #[error("null reference from llama.cpp")]
+    NullReturn,
+    /// See [`EmbeddingsError`].
+    #[error("{0}")]
+    EmbeddingsError(#[from] EmbeddingsError),
+}
+
+/// A token of the last batch that requested its output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BatchOutput {
+    pub(crate) batch_index: i32,
+    pub(crate) pos: i32,
+    pub(crate) seq_ids: Vec<i32>,
+}
+
+/// The outputs of the last batch passed to [`LlamaContext::decode`].
+///
+/// llama.cpp stores one row of logits (or embeddings) for every token that requested its output,
+/// in batch order. This handle maps batch indices and sequences to those rows, and returns an
+/// error instead of panicking when asked for an output the batch did not produce.
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use llama_cpp_2::context::params::LlamaContextParams;
+/// use llama_cpp_2::llama_batch::LlamaBatch;
+/// use llama_cpp_2::model::{AddBos, LlamaModel};
+/// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+/// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+/// let mut ctx = model.new_context(&backend, LlamaContextParams::default().with_n_seq_max(2))?;
+/// let mut batch = LlamaBatch::new(512, 1);
+/// for (seq_id, prompt) in [(0, "Hello"), (1, "Bonjour")] {
+///     let tokens = model.str_to_token(prompt, AddBos::Always)?;
+///     batch.add_sequence(&tokens, seq_id, false)?;
+/// }
+///
+/// let outputs = ctx.decode(&mut batch)?;
+/// for seq_id in [0, 1] {
+///     let logits = outputs.seq_logits(seq_id)?;
+///     println!("sequence {seq_id}: {} logits", logits.len());
+/// }
+/// // the first token did not request logits
+/// assert!(outputs.logits(0).is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeOutputs<'a, 'model> {
+    ctx: &'a LlamaContext<'model>,
+}
+
+impl<'a, 'model> DecodeOutputs<'a, 'model> {
+    /// The context the batch was decoded in, e.g. to sample with
+    /// [`crate::sampling::LlamaSampler::sample`].
+    #[must_use]
+    pub fn context(&self) -> &'a LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// The number of output rows.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.ctx.outputs.len()
+    }
+
+    /// Whether no token requested its output.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ctx.outputs.is_empty()
+    }
+
+    /// The batch indices of the tokens that requested their output, in row order.
+    pub fn batch_indices(&self) -> impl Iterator<Item = i32> + 'a {
+        self.ctx.outputs.iter().map(|output| output.batch_index)
+    }
+
+    /// The sequences the token at `batch_index` belongs to.
+    ///
+    /// # Errors
+    ///
+    /// If the token did not request its output.
+    pub fn seq_ids(&self, batch_index: i32) -> Result<&'a [i32], OutputError> {
+        let row = self.row(batch_index)?;
+        Ok(&self.ctx.outputs[row].seq_ids)
+    }
+
+    /// The output row of the token at `batch_index`.
+    ///
+    /// # Errors
+    ///
+    /// If the token did not request its output.
+    pub fn row(&self, batch_index: i32) -> Result<usize, OutputError> {
+        self.ctx
+            .outputs
+            .binary_search_by_key(&batch_index, |output| output.batch_index)
+            .map_err(|_| OutputError::NoOutput(batch_index))
+    }
+
+    /// The output row of the token at `batch_index`, checking that it belongs to `seq_id`.
+    ///
+    /// # Errors
+    ///
+    /// If the token did not request its output or belongs to other sequences.
+    pub fn seq_row(&self, seq_id: i32, batch_index: i32) -> Result<usize, OutputError> {
+        let row = self.row(batch_index)?;
+        if self.ctx.outputs[row].seq_ids.contains(&seq_id) {
+            Ok(row)
+        } else {
+            Err(OutputError::WrongSequence {
+                batch_index,
+                seq_id,
+            })
+        }
+    }
+
+    /// The batch index of the last token of `seq_id` that requested its output, usually the one
+    /// to sample the next token of the sequence from.
+    ///
+    /// # Errors
+    ///
+    /// If no token of the sequence requested its output.
+    pub fn last_of_seq(&self, seq_id: i32) -> Result<i32, OutputError> {
+        self.ctx
+            .outputs
+            .iter()
+            .rev()
+            .find(|output| output.seq_ids.contains(&seq_id))
+            .map(|output| output.batch_index)
+            .ok_or(OutputError::NoSequenceOutput(seq_id))
+    }
+
+    /// The logits of the token at `batch_index`.
+    ///
+    /// # Errors
+    ///
+    /// If the token did not request its output or llama.cpp has no logits for it, e.g. because
+    /// the context only computes embeddings.
+    ///
+    /// # Panics
+    ///
+    /// - `n_vocab` does not fit into a usize
+    pub fn logits(&self, batch_index: i32) -> Result<&'a [f32], OutputError> {
+        self.row(batch_index)?;
+        let data = unsafe {
+            llama_cpp_sys_2::llama_get_logits_ith(self.ctx.context.as_ptr(), batch_index)
+        };
+        if data.is_null() {
+            return Err(OutputError::NullReturn);
+        }
+        let len =
+            usize::try_from(self.ctx.model.n_vocab()).expect("n_vocab does not fit into a usize");
+        Ok(unsafe { slice::from_raw_parts(data, len) })
+    }
+
+    /// The logits of the last token of `seq_id` that requested its output.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::last_of_seq`] and [`Self::logits`].
+    pub fn seq_logits(&self, seq_id: i32) -> Result<&'a [f32], OutputError> {
+        self.logits(self.last_of_seq(seq_id)?)
+    }
+
+    /// The logits of the token at `batch_index` as unsorted candidates.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::logits`].
+    pub fn candidates(
+        &self,
+        batch_index: i32,
+    ) -> Result<impl Iterator<Item = LlamaTokenData> + 'a, OutputError> {
+        let logits = self.logits(batch_index)?;
+        Ok((0_i32..)
+            .zip(logits)
+            .map(|(i, logit)| LlamaTokenData::new(LlamaToken::new(i), *logit, 0_f32)))
+    }
+
+    /// The embeddings of the token at `batch_index`.
+    ///
+    /// # Errors
+    ///
+    /// If the token did not request its output or the context does not compute embeddings.
+    pub fn embeddings(&self, batch_index: i32) -> Result<&'a [f32], OutputError> {
+        self.row(batch_index)?;
+        Ok(self.ctx.embeddings_ith(batch_index)?)
+    }
+
+    /// The pooled embeddings of `seq_id`.
+    ///
+    /// # Errors
+    ///
+    /// If the context does not compute embeddings or does not pool them.
+    pub fn seq_embeddings(&self, seq_id: i32) -> Result<&'a [f32], OutputError> {
+        Ok(self.ctx.embeddings_seq_ith(seq_id)?)
+    }
+}
+
+/// The unpooled embeddings of the tokens of one sequence in the last decoded batch, see
+/// [`LlamaContext::token_embeddings`].
+///
+/// Row `i` is the embedding of the token at [`Self::positions`]`[i]`, rows are in batch order.
+#[derive(Debug, Clone)]
+pub struct EmbeddingMatrix<'a> {
+    data: &'a [f32],
+    n_embd: usize,
+    /// (row in `data`, position)
+    rows: Vec<(usize, i32)>,
+}
+
+impl<'a> EmbeddingMatrix<'a> {
+    /// The number of rows.
+    #[must_use]
+    pub fn n_tokens(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The number of columns.
+    #[must_use]
+    pub fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    /// Whether the sequence had no tokens in the batch.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The embedding in row `i`, or `None` if `i` is out of range.
+    #[must_use]
+    pub fn row(&self, i: usize) -> Option<&'a [f32]> {
+        let (row, _) = *self.rows.get(i)?;
+        Some(&self.data[row * self.n_embd..(row + 1) * self.n_embd])
+    }
+
+    /// The position of the token in each row.
+    #[must_use]
+    pub fn positions(&self) -> impl ExactSizeIterator<Item = i32> + '_ {
+        self.rows.iter().map(|&(_, pos)| pos)
+    }
+
+    /// The embedding of the token at position `pos`, if it is part of the matrix.
+    #[must_use]
+    pub fn at_pos(&self, pos: i32) -> Option<&'a [f32]> {
+        let i = self.rows.iter().position(|&(_, p)| p == pos)?;
+        self.row(i)
+    }
+
+    /// The rows together with the positions of their tokens.
+    #[must_use]
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (i32, &'a [f32])> + '_ {
+        let (data, n_embd) = (self.data, self.n_embd);
+        self.rows
+            .iter()
+            .map(move |&(row, pos)| (pos, &data[row * n_embd..(row + 1) * n_embd]))
+    }
+
+    /// Copy the matrix into a row-major vector of `n_tokens * n_embd` values.
+    #[must_use]
+    pub fn to_vec(&self) -> Vec<f32> {
+        self.iter()
+            .flat_map(|(_, row)| row.iter().copied())
+            .collect()
+    }
+}
+
+impl<'model> LlamaContext<'model> {
+    /// The outputs of the last decoded batch, the same handle [`Self::decode`] returned.
+    #[must_use]
+    pub fn outputs(&self) -> DecodeOutputs<'_, 'model> {
+        DecodeOutputs { ctx: self }
+    }
+
+    /// The embedding of every token of `seq_id` in the last decoded batch, e.g. for late
+    /// interaction retrieval. Requires a context that computes embeddings without pooling them.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let params = LlamaContextParams::default()
+    ///     .with_embeddings(true)
+    ///     .with_pooling_type(LlamaPoolingType::None);
+    /// let mut ctx = model.new_context(&backend, params)?;
+    /// let tokens = model.str_to_token("late interaction", AddBos::Always)?;
+    /// ctx.prefill(&tokens, 0, 0)?;
+    ///
+    /// let matrix = ctx.token_embeddings(0)?;
+    /// assert_eq!(matrix.n_tokens(), tokens.len());
+    /// for (pos, embedding) in matrix.iter() {
+    ///     println!("{pos}: {:?}", &embedding[..4]);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// - When the current context was constructed without enabling embeddings.
+    /// - When the context pools the embeddings.
+    ///
+    /// # Panics
+    ///
+    /// - `n_embd` does not fit into a usize
+    pub fn token_embeddings(&self, seq_id: i32) -> Result<EmbeddingMatrix<'_>, EmbeddingsError> {
+        if !self.embeddings_enabled {
+            return Err(EmbeddingsError::NotEnabled);
+        }
+        if self.pooling_type() != LlamaPoolingType::None {
+            return Err(EmbeddingsError::PooledType);
+        }
+        let n_embd =
+            usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
+        let rows: Vec<_> = self
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| output.seq_ids.contains(&seq_id))
+            .map(|(row, output)| (row, output.pos))
+            .collect();
+
+        let data = unsafe { llama_cpp_sys_2::llama_get_embeddings(self.context.as_ptr()) };
+        let data = if data.is_null() {
+            if !rows.is_empty() {
+                return Err(EmbeddingsError::LogitsNotEnabled);
+            }
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(data, self.outputs.len() * n_embd) }
+        };
+        Ok(EmbeddingMatrix { data, n_embd, rows })
+    }
+
+    /// The pooling type the context uses for embeddings.
+    #[must_use]
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        LlamaPoolingType::from(unsafe {
+            llama_cpp_sys_2::llama_pooling_type(self.context.as_ptr())
+        })
+    }
+}
@@ -2,6 +2,8 @@
 use std::fmt::Debug;
 use std::num::NonZeroU32;
 
+use crate::model::LlamaModel;
+
 /// A rusty wrapper around `rope_scaling_type`.
 #[repr(i8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -88,6 +90,41 @@ impl From<LlamaPoolingType> for i32 {
     }
 }
 
+/// A rusty wrapper around `llama_attention_type`.
+#[repr(i8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LlamaAttentionType {
+    /// Use the attention type of the model
+    Unspecified = -1,
+    /// Tokens only attend to earlier tokens
+    Causal = 0,
+    /// Tokens attend to all tokens of their sequence
+    NonCausal = 1,
+}
+
+/// Create a `LlamaAttentionType` from a `c_int` - returns `LlamaAttentionType::Unspecified` if
+/// the value is not recognized.
+impl From<i32> for LlamaAttentionType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::Causal,
+            1 => Self::NonCausal,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+/// Create a `c_int` from a `LlamaAttentionType`.
+impl From<LlamaAttentionType> for i32 {
+    fn from(value: LlamaAttentionType) -> Self {
+        match value {
+            LlamaAttentionType::Causal => 0,
+            LlamaAttentionType::NonCausal => 1,
+            LlamaAttentionType::Unspecified => -1,
+        }
+    }
+}
+
 /// A rusty wrapper around `ggml_type` for KV cache types.
 #[allow(non_camel_case_types, missing_docs)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -625,6 +662,35 @@ impl LlamaContextParams {
         LlamaPoolingType::from(self.context_params.pooling_type)
     }
 
+    /// Set the type of attention, overriding the one of the model.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaAttentionType, LlamaContextParams};
+    /// let params = LlamaContextParams::default()
+    ///     .with_attention_type(LlamaAttentionType::NonCausal);
+    /// assert_eq!(params.attention_type(), LlamaAttentionType::NonCausal);
+    /// ```
+    #[must_use]
+    pub fn with_attention_type(mut self, attention_type: LlamaAttentionType) -> Self {
+        self.context_params.attention_type = i32::from(attention_type);
+        self
+    }
+
+    /// Get the type of attention.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.attention_type(), llama_cpp_2::context::params::LlamaAttentionType::Unspecified);
+    /// ```
+    #[must_use]
+    pub fn attention_type(&self) -> LlamaAttentionType {
+        LlamaAttentionType::from(self.context_params.attention_type)
+    }
+
     /// Set whether to use full sliding window attention
     ///
     /// # Examples
@@ -735,6 +801,57 @@ impl LlamaContextParams {
     pub fn type_v(&self) -> KvCacheType {
         KvCacheType::from(self.context_params.type_v)
     }
+
+    /// The default parameters, set up to compute embeddings with `model`.
+    ///
+    /// - embeddings are enabled
+    /// - the pooling type is the one of [`LlamaModel::pooling_type`], including
+    ///   [`LlamaPoolingType::None`] for models that only produce token embeddings. Models that do
+    ///   not declare one get [`LlamaPoolingType::Last`] if they use causal attention, as only the
+    ///   last token has seen the whole input, and [`LlamaPoolingType::Mean`] otherwise
+    /// - the attention type follows [`LlamaModel::causal_attention`]
+    /// - `n_ubatch` equals the default `n_batch`, as non-causal models must process an input in
+    ///   one ubatch. It is not updated by [`Self::with_n_batch`], set both to change the batch
+    ///   size
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::model::LlamaModel;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let params = LlamaContextParams::for_embeddings(&model)
+    ///     .with_n_seq_max(16)
+    ///     .with_n_batch(4096)
+    ///     .with_n_ubatch(4096);
+    /// assert!(params.embeddings());
+    /// let ctx = model.new_context(&backend, params)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn for_embeddings(model: &LlamaModel) -> Self {
+        let causal = model.causal_attention().unwrap_or(true);
+        let pooling_type = match model.pooling_type() {
+            LlamaPoolingType::Unspecified if causal => LlamaPoolingType::Last,
+            LlamaPoolingType::Unspecified => LlamaPoolingType::Mean,
+            pooling_type => pooling_type,
+        };
+        let attention_type = if causal {
+            LlamaAttentionType::Causal
+        } else {
+            LlamaAttentionType::NonCausal
+        };
+        let params = Self::default();
+        let n_batch = params.n_batch();
+        params
+            .with_embeddings(true)
+            .with_pooling_type(pooling_type)
+            .with_attention_type(attention_type)
+            .with_n_ubatch(n_batch)
+    }
 }
 
 /// Default parameters for `LlamaContext`. (as defined in llama.cpp by `llama_context_default_params`)
@@ -0,0 +1,327 @@
+//! Control the threads a context computes with: change their number at runtime or run the
+//! context on a dedicated [`ThreadPool`] pinned to chosen cores.
+use std::fmt::{Debug, Formatter};
+use std::ptr::NonNull;
+
+use crate::context::LlamaContext;
+
+/// A rusty wrapper around `ggml_sched_priority`.
+#[repr(i8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThreadPriority {
+    /// Below the default priority.
+    Low = -1,
+    /// The default priority of the process.
+    Normal = 0,
+    /// Medium priority
+    Medium = 1,
+    /// High priority
+    High = 2,
+    /// Realtime priority, usually requires elevated permissions.
+    Realtime = 3,
+}
+
+/// Create a `ThreadPriority` from a `c_int` - returns `ThreadPriority::Normal` if the value is
+/// not recognized.
+impl From<i32> for ThreadPriority {
+    fn from(value: i32) -> Self {
+        match value {
+            llama_cpp_sys_2::GGML_SCHED_PRIO_LOW => Self::Low,
+            llama_cpp_sys_2::GGML_SCHED_PRIO_MEDIUM => Self::Medium,
+            llama_cpp_sys_2::GGML_SCHED_PRIO_HIGH => Self::High,
+            llama_cpp_sys_2::GGML_SCHED_PRIO_REALTIME => Self::Realtime,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// Create a `c_int` from a `ThreadPriority`.
+impl From<ThreadPriority> for i32 {
+    fn from(value: ThreadPriority) -> Self {
+        match value {
+            ThreadPriority::Low => llama_cpp_sys_2::GGML_SCHED_PRIO_LOW,
+            ThreadPriority::Normal => llama_cpp_sys_2::GGML_SCHED_PRIO_NORMAL,
+            ThreadPriority::Medium => llama_cpp_sys_2::GGML_SCHED_PRIO_MEDIUM,
+            ThreadPriority::High => llama_cpp_sys_2::GGML_SCHED_PRIO_HIGH,
+            ThreadPriority::Realtime => llama_cpp_sys_2::GGML_SCHED_PRIO_REALTIME,
+        }
+    }
+}
+
+/// A safe wrapper around `ggml_threadpool_params`.
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::context::threadpool::{ThreadPoolParams, ThreadPriority};
+///
+/// // four threads on cores 4 to 7, one thread per core
+/// let params = ThreadPoolParams::new(4)
+///     .with_cpumask(4..8)
+///     .with_strict_cpu(true)
+///     .with_priority(ThreadPriority::High)
+///     .with_poll(0);
+/// assert_eq!(params.n_threads(), 4);
+/// assert_eq!(params.cpumask(), vec![4, 5, 6, 7]);
+/// assert!(params.strict_cpu());
+/// assert_eq!(params.priority(), ThreadPriority::High);
+/// assert_eq!(params.poll(), 0);
+/// ```
+#[derive(Clone)]
+pub struct ThreadPoolParams {
+    pub(crate) params: llama_cpp_sys_2::ggml_threadpool_params,
+}
+
+impl Debug for ThreadPoolParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadPoolParams")
+            .field("n_threads", &self.n_threads())
+            .field("cpumask", &self.cpumask())
+            .field("priority", &self.priority())
+            .field("poll", &self.poll())
+            .field("strict_cpu", &self.strict_cpu())
+            .field("paused", &self.paused())
+            .finish()
+    }
+}
+
+impl ThreadPoolParams {
+    /// The default parameters for a pool of `n_threads` threads: no cpu affinity, normal
+    /// priority and a poll level of 50.
+    ///
+    /// # Panics
+    ///
+    /// - `n_threads` does not fit into an i32
+    #[must_use]
+    pub fn new(n_threads: u32) -> Self {
+        let n_threads = i32::try_from(n_threads).expect("n_threads fits into an i32");
+        let params = unsafe { llama_cpp_sys_2::ggml_threadpool_params_default(n_threads) };
+        Self { params }
+    }
+
+    /// The number of threads in the pool.
+    #[must_use]
+    pub fn n_threads(&self) -> u32 {
+        u32::try_from(self.params.n_threads).unwrap_or(0)
+    }
+
+    /// Only run the threads on the cpus `cpus`. Without a mask the threads run on any cpu.
+    ///
+    /// # Panics
+    ///
+    /// - a cpu is not below the maximum number of threads ggml supports (512)
+    #[must_use]
+    pub fn with_cpumask(mut self, cpus: impl IntoIterator<Item = usize>) -> Self {
+        self.params.cpumask.fill(false);
+        for cpu in cpus {
+            assert!(
+                cpu < self.params.cpumask.len(),
+                "cpu {cpu} is out of range for the cpu mask"
+            );
+            self.params.cpumask[cpu] = true;
+        }
+        self
+    }
+
+    /// The cpus the threads may run on, empty if they may run on any cpu.
+    #[must_use]
+    pub fn cpumask(&self) -> Vec<usize> {
+        (0..self.params.cpumask.len())
+            .filter(|&cpu| self.params.cpumask[cpu])
+            .collect()
+    }
+
+    /// Set the scheduling priority of the threads.
+    #[must_use]
+    pub fn with_priority(mut self, priority: ThreadPriority) -> Self {
+        self.params.prio = priority.into();
+        self
+    }
+
+    /// The scheduling priority of the threads.
+    #[must_use]
+    pub fn priority(&self) -> ThreadPriority {
+        ThreadPriority::from(self.params.prio)
+    }
+
+    /// How much idle threads busy-wait for work instead of sleeping, from 0 (sleep right away) to
+    /// 100. Higher values lower latency at the cost of cpu time.
+    ///
+    /// # Panics
+    ///
+    /// - `poll` is larger than 100
+    #[must_use]
+    pub fn with_poll(mut self, poll: u32) -> Self {
+        assert!(poll <= 100, "poll must be between 0 and 100");
+        self.params.poll = poll;
+        self
+    }
+
+    /// The poll level, see [`Self::with_poll`].
+    #[must_use]
+    pub fn poll(&self) -> u32 {
+        self.params.poll
+    }
+
+    /// Pin each thread to a single cpu of the mask, instead of letting every thread run on any
+    /// cpu of the mask.
+    #[must_use]
+    pub fn with_strict_cpu(mut self, strict_cpu: bool) -> Self {
+        self.params.strict_cpu = strict_cpu;
+        self
+    }
+
+    /// Whether each thread is pinned to a single cpu.
+    #[must_use]
+    pub fn strict_cpu(&self) -> bool {
+        self.params.strict_cpu
+    }
+
+    /// Create the pool in the paused state, see [`ThreadPool::pause`].
+    #[must_use]
+    pub fn with_paused(mut self, paused: bool) -> Self {
+        self.params.paused = paused;
+        self
+    }
+
+    /// Whether the pool is created in the paused state.
+    #[must_use]
+    pub fn paused(&self) -> bool {
+        self.params.paused
+    }
+}
+
+/// Failed to create a [`ThreadPool`].
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ThreadPoolCreateError {
+    /// ggml returned null
+    #[error("null reference from ggml")]
+    NullReturn,
+}
+
+/// A safe wrapper around `ggml_threadpool`, see [`LlamaContext::attach_threadpool`].
+pub struct ThreadPool {
+    pool: NonNull<llama_cpp_sys_2::ggml_threadpool>,
+}
+
+impl Debug for ThreadPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("n_threads", &self.n_threads())
+            .finish()
+    }
+}
+
+unsafe impl Send for ThreadPool {}
+
+impl ThreadPool {
+    /// Start the threads of a new pool.
+    ///
+    /// # Errors
+    ///
+    /// If ggml could not create the pool.
+    pub fn new(params: &ThreadPoolParams) -> Result<Self, ThreadPoolCreateError> {
+        let mut params = params.params;
+        let pool = unsafe { llama_cpp_sys_2::ggml_threadpool_new(std::ptr::addr_of_mut!(params)) };
+        let pool = NonNull::new(pool).ok_or(ThreadPoolCreateError::NullReturn)?;
+        Ok(Self { pool })
+    }
+
+    /// The number of threads in the pool.
+    #[must_use]
+    pub fn n_threads(&self) -> u32 {
+        let n_threads =
+            unsafe { llama_cpp_sys_2::ggml_threadpool_get_n_threads(self.pool.as_ptr()) };
+        u32::try_from(n_threads).unwrap_or(0)
+    }
+
+    /// Put the threads to sleep until [`Self::resume`], e.g. while the context is idle.
+    pub fn pause(&mut self) {
+        unsafe { llama_cpp_sys_2::ggml_threadpool_pause(self.pool.as_ptr()) }
+    }
+
+    /// Wake the threads up after [`Self::pause`].
+    pub fn resume(&mut self) {
+        unsafe { llama_cpp_sys_2::ggml_threadpool_resume(self.pool.as_ptr()) }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        unsafe { llama_cpp_sys_2::ggml_threadpool_free(self.pool.as_ptr()) }
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Set the number of threads used to decode single tokens (`n_threads`) and batches of
+    /// prompt tokens (`n_threads_batch`), overriding
+    /// [`crate::context::params::LlamaContextParams::with_n_threads`]. These counts still apply
+    /// while a [`ThreadPool`] is attached: llama.cpp plans each graph for this many threads, and
+    /// only that many of the pool's threads take part, at most [`ThreadPool::n_threads`].
+    pub fn set_n_threads(&mut self, n_threads: i32, n_threads_batch: i32) {
+        unsafe {
+            llama_cpp_sys_2::llama_set_n_threads(self.context.as_ptr(), n_threads, n_threads_batch);
+        }
+    }
+
+    /// The number of threads used to decode single tokens.
+    #[must_use]
+    pub fn n_threads(&self) -> i32 {
+        unsafe { llama_cpp_sys_2::llama_n_threads(self.context.as_ptr()) }
+    }
+
+    /// The number of threads used to decode batches.
+    #[must_use]
+    pub fn n_threads_batch(&self) -> i32 {
+        unsafe { llama_cpp_sys_2::llama_n_threads_batch(self.context.as_ptr()) }
+    }
+
+    /// Compute on `threadpool` instead of the threads the context creates itself, and on
+    /// `threadpool_batch` for batches of prompt tokens if it is given. The context owns the
+    /// pools until they are detached. A previously attached pool is replaced and dropped. The
+    /// thread counts from [`Self::set_n_threads`] still decide how many of the pool's threads
+    /// are used, so match them to the pool sizes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::context::threadpool::{ThreadPool, ThreadPoolParams};
+    /// use llama_cpp_2::model::LlamaModel;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    ///
+    /// // keep cores 0 to 3 free for other services
+    /// let generate = ThreadPool::new(&ThreadPoolParams::new(4).with_cpumask(4..8))?;
+    /// let batch = ThreadPool::new(&ThreadPoolParams::new(8).with_cpumask(4..12))?;
+    /// ctx.attach_threadpool(generate, Some(batch));
+    /// ctx.set_n_threads(4, 8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn attach_threadpool(
+        &mut self,
+        threadpool: ThreadPool,
+        threadpool_batch: Option<ThreadPool>,
+    ) {
+        let batch = threadpool_batch
+            .as_ref()
+            .map_or(std::ptr::null_mut(), |pool| pool.pool.as_ptr());
+        unsafe {
+            llama_cpp_sys_2::llama_attach_threadpool(
+                self.context.as_ptr(),
+                threadpool.pool.as_ptr(),
+                batch,
+            );
+        }
+        self.threadpools = Some((threadpool, threadpool_batch));
+    }
+
+    /// Go back to the threads the context creates itself and hand back the attached pools.
+    pub fn detach_threadpool(&mut self) -> Option<(ThreadPool, Option<ThreadPool>)> {
+        unsafe { llama_cpp_sys_2::llama_detach_threadpool(self.context.as_ptr()) }
+        self.threadpools.take()
+    }
+}
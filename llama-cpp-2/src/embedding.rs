@@ -0,0 +1,674 @@
+//! Compute embeddings for many inputs at once.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::embedding::Embedder;
+//! use llama_cpp_2::model::LlamaModel;
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::for_embeddings(&model).with_n_seq_max(16);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let mut embedder = Embedder::new(&mut ctx).with_normalize(true);
+//! let embeddings = embedder.embed_str(&["first document", "second document"])?;
+//! assert_eq!(embeddings.len(), 2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Documents longer than a batch are embedded in overlapping windows with
+//! [`Embedder::embed_long`], or split into chunks after a single pass over the whole document with
+//! [`Embedder::embed_late_chunks`]. To embed the parts of a document separately, split its text
+//! with a [`splitter::TextSplitter`].
+
+use std::ops::Range;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{AddBos, LlamaModel};
+use crate::token::LlamaToken;
+use crate::{DecodeError, EmbeddingsError, StringToTokenError};
+
+pub mod splitter;
+
+/// Failed to compute embeddings.
+#[derive(Debug, thiserror::Error)]
+pub enum EmbedError {
+    /// An input or chunk has no tokens.
+    #[error("input {0} is empty")]
+    EmptyInput(usize),
+    /// A chunk ends past the end of its document.
+    #[error("chunk {index} ends at {end}, but the document has {n_tokens} tokens")]
+    ChunkOutOfRange {
+        /// The index of the chunk.
+        index: usize,
+        /// The end of the chunk.
+        end: usize,
+        /// The number of tokens of the document.
+        n_tokens: usize,
+    },
+    /// A window of [`Embedder::embed_long`] has no room for content.
+    #[error(
+        "a window of {size} tokens overlapping by {overlap} has no room next to {n_special} special tokens"
+    )]
+    WindowTooSmall {
+        /// The size of the window.
+        size: usize,
+        /// The overlap of the window.
+        overlap: usize,
+        /// The number of special tokens added to each window.
+        n_special: usize,
+    },
+    /// An input does not fit into a single batch.
+    #[error("input {index} has {n_tokens} tokens, but a batch holds at most {max_tokens}")]
+    InputTooLong {
+        /// The index of the input.
+        index: usize,
+        /// The number of tokens of the input.
+        n_tokens: usize,
+        /// The number of tokens that fit into a batch.
+        max_tokens: usize,
+    },
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// See [`EmbeddingsError`].
+    #[error("{0}")]
+    EmbeddingsError(#[from] EmbeddingsError),
+    /// See [`KvCacheConversionError`].
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+    /// See [`StringToTokenError`].
+    #[error("{0}")]
+    StringToTokenError(#[from] StringToTokenError),
+}
+
+/// Scale `embedding` to unit length. An all zero embedding is left as is.
+pub fn normalize(embedding: &mut [f32]) {
+    let norm = embedding
+        .iter()
+        .fold(0.0, |acc: f32, &val| val.mul_add(val, acc))
+        .sqrt();
+    if norm > 0.0 {
+        for val in embedding {
+            *val /= norm;
+        }
+    }
+}
+
+/// How [`Embedder::embed_long`] combines the embeddings of the windows of a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// The average of the window embeddings.
+    Mean,
+    /// The element-wise maximum of the window embeddings.
+    Max,
+    /// The average of the window embeddings, weighted by the number of tokens in each window, so
+    /// a short last window counts less.
+    Weighted,
+}
+
+impl Aggregation {
+    /// Combine `embeddings` of windows with `n_tokens` tokens each. `embeddings` must not be
+    /// empty.
+    #[allow(clippy::cast_precision_loss)] // token counts are far below the precision of an f32
+    fn aggregate(self, embeddings: &[Vec<f32>], n_tokens: &[usize]) -> Vec<f32> {
+        let mut combined = vec![0.0; embeddings[0].len()];
+        match self {
+            Self::Max => {
+                combined.fill(f32::NEG_INFINITY);
+                for embedding in embeddings {
+                    for (acc, &val) in combined.iter_mut().zip(embedding) {
+                        *acc = acc.max(val);
+                    }
+                }
+            }
+            Self::Mean | Self::Weighted => {
+                let weights = n_tokens.iter().map(|&n| match self {
+                    Self::Weighted => n as f32,
+                    _ => 1.0,
+                });
+                let mut total = 0.0;
+                for (embedding, weight) in embeddings.iter().zip(weights) {
+                    for (acc, &val) in combined.iter_mut().zip(embedding) {
+                        *acc = val.mul_add(weight, *acc);
+                    }
+                    total += weight;
+                }
+                for acc in &mut combined {
+                    *acc /= total;
+                }
+            }
+        }
+        combined
+    }
+}
+
+/// Splits a token sequence into windows of at most `size` tokens, each starting `size - overlap`
+/// tokens after the previous one.
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::embedding::SlidingWindow;
+///
+/// let window = SlidingWindow::new(4, 1);
+/// assert_eq!(window.windows(10), vec![0..4, 3..7, 6..10]);
+/// assert_eq!(window.windows(3), vec![0..3]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingWindow {
+    size: usize,
+    overlap: usize,
+}
+
+impl SlidingWindow {
+    /// Windows of `size` tokens that share `overlap` tokens with their neighbours.
+    ///
+    /// # Panics
+    ///
+    /// - `overlap` is not smaller than `size`
+    #[must_use]
+    pub fn new(size: usize, overlap: usize) -> Self {
+        assert!(
+            overlap < size,
+            "the overlap must be smaller than the window"
+        );
+        Self { size, overlap }
+    }
+
+    /// The maximum number of tokens in a window.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of tokens a window shares with the next one.
+    #[must_use]
+    pub fn overlap(&self) -> usize {
+        self.overlap
+    }
+
+    /// The token ranges of the windows of a sequence of `n_tokens` tokens. Only the last window
+    /// may be shorter than [`Self::size`].
+    #[must_use]
+    pub fn windows(&self, n_tokens: usize) -> Vec<Range<usize>> {
+        let mut windows = Vec::new();
+        let mut start = 0;
+        while start < n_tokens {
+            let end = (start + self.size).min(n_tokens);
+            windows.push(start..end);
+            if end == n_tokens {
+                break;
+            }
+            start += self.size - self.overlap;
+        }
+        windows
+    }
+}
+
+/// The special tokens `model` adds before and after an input when tokenizing with
+/// [`AddBos::Always`].
+fn special_tokens(model: &LlamaModel) -> (Vec<LlamaToken>, Vec<LlamaToken>) {
+    let mut prefix = Vec::new();
+    let mut suffix = Vec::new();
+    if model.add_bos_token() {
+        prefix.push(model.token_bos());
+    }
+    if model.add_eos_token() {
+        suffix.push(model.token_eos());
+    }
+    if model.add_sep_token() {
+        suffix.push(model.token_sep());
+    }
+    (prefix, suffix)
+}
+
+/// What is read for each sequence of a decoded batch.
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    Embedding,
+    RankScores,
+}
+
+/// Computes pooled embeddings, packing as many inputs into each batch as the context allows.
+///
+/// Each input of a batch gets its own sequence, so a batch holds at most
+/// [`LlamaContext::n_seq_max`] inputs and at most [`LlamaContext::n_batch`] tokens (or
+/// [`LlamaContext::n_ubatch`] if it is smaller, as non-causal models need the whole batch in one
+/// ubatch). The sequences are cleared before and after use. The context needs embeddings enabled
+/// and a pooling type other than [`crate::context::params::LlamaPoolingType::None`], as set up
+/// by [`crate::context::params::LlamaContextParams::for_embeddings`].
+#[derive(Debug)]
+pub struct Embedder<'a, 'model> {
+    ctx: &'a mut LlamaContext<'model>,
+    normalize: bool,
+}
+
+impl<'a, 'model> Embedder<'a, 'model> {
+    /// Create an embedder that decodes into `ctx`.
+    #[must_use]
+    pub fn new(ctx: &'a mut LlamaContext<'model>) -> Self {
+        Self {
+            ctx,
+            normalize: false,
+        }
+    }
+
+    /// Scale every embedding to unit length, as needed for cosine similarity by dot product.
+    /// [`Self::embed_long`] only scales the combined embedding, not the windows. Off by default.
+    #[must_use]
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// The context the embedder decodes into.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// The number of tokens that fit into one batch.
+    #[must_use]
+    pub fn max_tokens(&self) -> usize {
+        self.ctx.n_batch().min(self.ctx.n_ubatch()) as usize
+    }
+
+    /// Tokenize `texts` with a leading BOS token and embed them.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::embed`].
+    pub fn embed_str(&mut self, texts: &[impl AsRef<str>]) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let inputs = texts
+            .iter()
+            .map(|text| self.ctx.model.str_to_token(text.as_ref(), AddBos::Always))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.embed(&inputs)
+    }
+
+    /// Embed every input, returning the embeddings in input order.
+    ///
+    /// # Errors
+    ///
+    /// - an input is empty or does not fit into a batch
+    /// - the context does not compute pooled embeddings
+    /// - decoding failed
+    pub fn embed(
+        &mut self,
+        inputs: &[impl AsRef<[LlamaToken]>],
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let mut embeddings = self.decode_inputs(inputs, Output::Embedding)?;
+        if self.normalize {
+            for embedding in &mut embeddings {
+                normalize(embedding);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    /// Score every input with the classifier head of a context that uses
+    /// [`crate::context::params::LlamaPoolingType::Rank`], returning the scores in input order.
+    pub(crate) fn rank_scores(
+        &mut self,
+        inputs: &[impl AsRef<[LlamaToken]>],
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        self.decode_inputs(inputs, Output::RankScores)
+    }
+
+    fn decode_inputs(
+        &mut self,
+        inputs: &[impl AsRef<[LlamaToken]>],
+        output: Output,
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        if !self.ctx.embeddings_enabled() {
+            return Err(EmbeddingsError::NotEnabled.into());
+        }
+        let max_tokens = self.max_tokens();
+        for (index, input) in inputs.iter().enumerate() {
+            let n_tokens = input.as_ref().len();
+            if n_tokens == 0 {
+                return Err(EmbedError::EmptyInput(index));
+            }
+            if n_tokens > max_tokens {
+                return Err(EmbedError::InputTooLong {
+                    index,
+                    n_tokens,
+                    max_tokens,
+                });
+            }
+        }
+
+        let max_seqs = self.ctx.n_seq_max() as usize;
+        let mut batch = LlamaBatch::new(max_tokens, 1);
+        let mut embeddings = Vec::with_capacity(inputs.len());
+        let mut start = 0;
+        while start < inputs.len() {
+            let mut end = start;
+            let mut n_tokens = 0;
+            while end < inputs.len()
+                && end - start < max_seqs
+                && n_tokens + inputs[end].as_ref().len() <= max_tokens
+            {
+                n_tokens += inputs[end].as_ref().len();
+                end += 1;
+            }
+            self.embed_batch(&mut batch, &inputs[start..end], output, &mut embeddings)?;
+            start = end;
+        }
+        Ok(embeddings)
+    }
+
+    /// Embed a document of any length: split `tokens` into `window`s, embed each of them and
+    /// combine the window embeddings with `aggregation`.
+    ///
+    /// `tokens` are the content of the document, tokenized with [`AddBos::Never`]. Every window
+    /// is wrapped in the special tokens the model adds to an input, such as CLS and SEP for
+    /// BERT-like models, so CLS and last token pooling see them in each window. They count
+    /// towards the size of the window.
+    ///
+    /// # Errors
+    ///
+    /// - `tokens` is empty
+    /// - the window leaves no room for content next to the special tokens and the overlap
+    /// - the window is larger than [`Self::max_tokens`]
+    /// - see [`Self::embed`]
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::embedding::{Aggregation, Embedder, SlidingWindow};
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let document = String::new();
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::for_embeddings(&model))?;
+    /// let mut embedder = Embedder::new(&mut ctx).with_normalize(true);
+    ///
+    /// let tokens = model.str_to_token(&document, AddBos::Never)?;
+    /// let window = SlidingWindow::new(512, 64);
+    /// let embedding = embedder.embed_long(&tokens, &window, Aggregation::Weighted)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn embed_long(
+        &mut self,
+        tokens: &[LlamaToken],
+        window: &SlidingWindow,
+        aggregation: Aggregation,
+    ) -> Result<Vec<f32>, EmbedError> {
+        if tokens.is_empty() {
+            return Err(EmbedError::EmptyInput(0));
+        }
+        let (prefix, suffix) = special_tokens(self.ctx.model);
+        let n_special = prefix.len() + suffix.len();
+        let size = window.size().saturating_sub(n_special);
+        if size <= window.overlap() {
+            return Err(EmbedError::WindowTooSmall {
+                size: window.size(),
+                overlap: window.overlap(),
+                n_special,
+            });
+        }
+        let ranges = SlidingWindow::new(size, window.overlap()).windows(tokens.len());
+        let windows = ranges
+            .iter()
+            .map(|range| [&prefix[..], &tokens[range.clone()], &suffix[..]].concat())
+            .collect::<Vec<_>>();
+        // aggregate the raw window embeddings, so only the document embedding is normalized
+        let embeddings = self.decode_inputs(&windows, Output::Embedding)?;
+        let n_tokens = ranges
+            .iter()
+            .map(ExactSizeIterator::len)
+            .collect::<Vec<_>>();
+        let mut embedding = aggregation.aggregate(&embeddings, &n_tokens);
+        if self.normalize {
+            normalize(&mut embedding);
+        }
+        Ok(embedding)
+    }
+
+    /// Late chunking: decode the whole document in a single pass, so every token embedding sees
+    /// the context of the entire document, then mean pool the token embeddings of each chunk.
+    /// Returns one embedding per chunk.
+    ///
+    /// The context must be created with [`crate::context::params::LlamaPoolingType::None`] and
+    /// the document must fit into [`Self::max_tokens`].
+    ///
+    /// # Errors
+    ///
+    /// - `tokens` or a chunk is empty, or a chunk ends past the end of `tokens`
+    /// - the document does not fit into a batch
+    /// - the context does not compute token embeddings
+    /// - decoding failed
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// use llama_cpp_2::embedding::{Embedder, SlidingWindow};
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let document = String::new();
+    /// let params = LlamaContextParams::for_embeddings(&model)
+    ///     .with_pooling_type(LlamaPoolingType::None)
+    ///     .with_n_batch(8192)
+    ///     .with_n_ubatch(8192);
+    /// let mut ctx = model.new_context(&backend, params)?;
+    /// let mut embedder = Embedder::new(&mut ctx).with_normalize(true);
+    ///
+    /// let tokens = model.str_to_token(&document, AddBos::Always)?;
+    /// let chunks = SlidingWindow::new(256, 0).windows(tokens.len());
+    /// let embeddings = embedder.embed_late_chunks(&tokens, &chunks)?;
+    /// assert_eq!(embeddings.len(), chunks.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn embed_late_chunks(
+        &mut self,
+        tokens: &[LlamaToken],
+        chunks: &[Range<usize>],
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        if !self.ctx.embeddings_enabled() {
+            return Err(EmbeddingsError::NotEnabled.into());
+        }
+        let max_tokens = self.max_tokens();
+        if tokens.is_empty() {
+            return Err(EmbedError::EmptyInput(0));
+        }
+        if tokens.len() > max_tokens {
+            return Err(EmbedError::InputTooLong {
+                index: 0,
+                n_tokens: tokens.len(),
+                max_tokens,
+            });
+        }
+        for (index, chunk) in chunks.iter().enumerate() {
+            if chunk.is_empty() {
+                return Err(EmbedError::EmptyInput(index));
+            }
+            if chunk.end > tokens.len() {
+                return Err(EmbedError::ChunkOutOfRange {
+                    index,
+                    end: chunk.end,
+                    n_tokens: tokens.len(),
+                });
+            }
+        }
+
+        let mut batch = LlamaBatch::new(tokens.len(), 1);
+        batch.add_sequence(tokens, 0, false)?;
+        self.clear_seq(0)?;
+        let result = self.pool_chunks(&mut batch, chunks);
+        self.clear_seq(0)?;
+        result
+    }
+
+    /// Decode `batch` and mean pool the token embeddings of sequence 0 over each chunk.
+    #[allow(clippy::cast_precision_loss)] // token counts are far below the precision of an f32
+    fn pool_chunks(
+        &mut self,
+        batch: &mut LlamaBatch,
+        chunks: &[Range<usize>],
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        self.ctx.decode(batch)?;
+        let matrix = self.ctx.token_embeddings(0)?;
+        let mut embeddings = vec![vec![0.0; matrix.n_embd()]; chunks.len()];
+        let mut counts = vec![0_usize; chunks.len()];
+        for (pos, row) in matrix.iter() {
+            let Ok(pos) = usize::try_from(pos) else {
+                continue;
+            };
+            for ((chunk, embedding), count) in chunks.iter().zip(&mut embeddings).zip(&mut counts) {
+                if chunk.contains(&pos) {
+                    for (acc, &val) in embedding.iter_mut().zip(row) {
+                        *acc += val;
+                    }
+                    *count += 1;
+                }
+            }
+        }
+        for (embedding, count) in embeddings.iter_mut().zip(counts) {
+            for val in embedding.iter_mut() {
+                *val /= count as f32;
+            }
+            if self.normalize {
+                normalize(embedding);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    /// Decode `inputs` as one batch, one sequence per input, and append their embeddings.
+    fn embed_batch(
+        &mut self,
+        batch: &mut LlamaBatch,
+        inputs: &[impl AsRef<[LlamaToken]>],
+        output: Output,
+        embeddings: &mut Vec<Vec<f32>>,
+    ) -> Result<(), EmbedError> {
+        batch.clear();
+        for (seq_id, input) in (0..).zip(inputs) {
+            self.clear_seq(seq_id)?;
+            batch.add_sequence(input.as_ref(), seq_id, false)?;
+        }
+        let n_seqs = i32::try_from(inputs.len()).expect("n_seq_max fits into an i32");
+        let result = self.decode_seqs(batch, n_seqs, output, embeddings);
+        for seq_id in 0..n_seqs {
+            self.clear_seq(seq_id)?;
+        }
+        result
+    }
+
+    fn decode_seqs(
+        &mut self,
+        batch: &mut LlamaBatch,
+        n_seqs: i32,
+        output: Output,
+        embeddings: &mut Vec<Vec<f32>>,
+    ) -> Result<(), EmbedError> {
+        self.ctx.decode(batch)?;
+        for seq_id in 0..n_seqs {
+            let embedding = match output {
+                Output::Embedding => self.ctx.embeddings_seq_ith(seq_id)?,
+                Output::RankScores => self.ctx.rank_scores_seq_ith(seq_id)?,
+            };
+            embeddings.push(embedding.to_vec());
+        }
+        Ok(())
+    }
+
+    fn clear_seq(&mut self, seq_id: i32) -> Result<(), EmbedError> {
+        let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        self.ctx.clear_kv_cache_seq(Some(seq), None, None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_mean() {
+        let embeddings = [vec![1.0, -2.0], vec![3.0, 4.0]];
+        assert_eq!(
+            Aggregation::Mean.aggregate(&embeddings, &[4, 1]),
+            [2.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn aggregate_max() {
+        let embeddings = [vec![1.0, -2.0, -5.0], vec![3.0, -4.0, -1.0]];
+        assert_eq!(
+            Aggregation::Max.aggregate(&embeddings, &[4, 1]),
+            [3.0, -2.0, -1.0]
+        );
+    }
+
+    #[test]
+    fn aggregate_weighted() {
+        let embeddings = [vec![1.0, -2.0], vec![4.0, 8.0]];
+        assert_eq!(
+            Aggregation::Weighted.aggregate(&embeddings, &[2, 1]),
+            [2.0, 4.0 / 3.0]
+        );
+    }
+
+    #[test]
+    fn aggregate_single_window() {
+        let embeddings = [vec![0.5, -1.5]];
+        for aggregation in [Aggregation::Mean, Aggregation::Max, Aggregation::Weighted] {
+            assert_eq!(aggregation.aggregate(&embeddings, &[3]), embeddings[0]);
+        }
+    }
+
+    #[test]
+    fn aggregate_then_normalize_once() {
+        // normalizing the windows first would weigh the short window as much as the long one
+        let embeddings = [vec![3.0, 0.0], vec![0.0, 1.0]];
+        let mut embedding = Aggregation::Mean.aggregate(&embeddings, &[1, 1]);
+        normalize(&mut embedding);
+        assert!((embedding[0] - 0.948_683_3).abs() < 1e-6);
+        assert!((embedding[1] - 0.316_227_76).abs() < 1e-6);
+    }
+
+    #[test]
+    fn windows_without_overlap() {
+        let window = SlidingWindow::new(3, 0);
+        assert_eq!(window.windows(7), [0..3, 3..6, 6..7]);
+        assert_eq!(window.windows(6), [0..3, 3..6]);
+    }
+
+    #[test]
+    fn windows_with_overlap() {
+        let window = SlidingWindow::new(5, 2);
+        assert_eq!(window.windows(11), [0..5, 3..8, 6..11]);
+        assert_eq!(window.windows(12), [0..5, 3..8, 6..11, 9..12]);
+        assert_eq!(window.windows(5), vec![0..5]);
+    }
+
+    #[test]
+    fn windows_of_nothing() {
+        assert!(SlidingWindow::new(4, 1).windows(0).is_empty());
+    }
+
+    #[test]
+    #[should_panic = "the overlap must be smaller than the window"]
+    fn window_overlap_too_large() {
+        let _ = SlidingWindow::new(4, 4);
+    }
+}
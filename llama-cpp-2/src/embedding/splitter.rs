@@ -0,0 +1,338 @@
+//! Split text into chunks that fit a token budget, e.g. to embed documents for retrieval.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::embedding::splitter::TextSplitter;
+//! use llama_cpp_2::model::LlamaModel;
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let document = String::new();
+//! let splitter = TextSplitter::new(&model, 256).with_overlap(32);
+//! for chunk in splitter.split(&document)? {
+//!     assert_eq!(chunk.text, &document[chunk.span.clone()]);
+//!     println!("{} tokens: {}", chunk.n_tokens, chunk.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::Range;
+
+use crate::model::{AddBos, LlamaModel};
+use crate::StringToTokenError;
+
+/// A piece of the text passed to [`TextSplitter::split`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextChunk {
+    /// The text of the chunk, without leading or trailing whitespace.
+    pub text: String,
+    /// The byte range of [`Self::text`] in the split text.
+    pub span: Range<usize>,
+    /// The number of tokens of [`Self::text`], not counting special tokens.
+    pub n_tokens: usize,
+}
+
+/// The boundaries text is split at, from the most to the least preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Boundary {
+    Paragraph,
+    Line,
+    Sentence,
+    Word,
+    Char,
+}
+
+impl Boundary {
+    /// The next less preferred boundary.
+    fn next(self) -> Option<Self> {
+        match self {
+            Self::Paragraph => Some(Self::Line),
+            Self::Line => Some(Self::Sentence),
+            Self::Sentence => Some(Self::Word),
+            Self::Word => Some(Self::Char),
+            Self::Char => None,
+        }
+    }
+
+    /// The byte offsets in `text` at which a new piece starts. Whitespace stays with the piece
+    /// before it, so the pieces cover `text` without gaps.
+    fn split_points(self, text: &str) -> Vec<usize> {
+        if self == Self::Char {
+            return text.char_indices().skip(1).map(|(i, _)| i).collect();
+        }
+        let mut points = Vec::new();
+        // the start of the current whitespace run and the character before it
+        let mut run: Option<(usize, Option<char>)> = None;
+        let mut prev = None;
+        for (i, c) in text.char_indices() {
+            if c.is_whitespace() {
+                run.get_or_insert((i, prev));
+            } else if let Some((start, before)) = run.take() {
+                let whitespace = &text[start..i];
+                let newlines = whitespace.matches('\n').count();
+                let split = before.is_some()
+                    && match self {
+                        Self::Paragraph => newlines >= 2,
+                        Self::Line => newlines >= 1,
+                        Self::Sentence => matches!(before, Some('.' | '!' | '?')),
+                        Self::Word | Self::Char => true,
+                    };
+                if split {
+                    points.push(i);
+                }
+            }
+            prev = Some(c);
+        }
+        points
+    }
+}
+
+/// A piece of text that is not split any further.
+#[derive(Debug)]
+struct Segment {
+    span: Range<usize>,
+    n_tokens: usize,
+}
+
+/// Splits text into chunks of at most `max_tokens` tokens of a model, cutting at paragraph, line,
+/// sentence or word boundaries if possible and only falling back to cutting between characters
+/// for words that are too long on their own.
+///
+/// Consecutive chunks share up to [`Self::with_overlap`] tokens, so text near a cut appears in
+/// full in at least one chunk. Token counts do not include special tokens such as BOS, leave
+/// room for them when the chunks are embedded.
+#[derive(Debug, Clone, Copy)]
+pub struct TextSplitter<'a> {
+    model: &'a LlamaModel,
+    max_tokens: usize,
+    overlap: usize,
+}
+
+impl<'a> TextSplitter<'a> {
+    /// Split into chunks of at most `max_tokens` tokens of `model`, without overlap.
+    ///
+    /// # Panics
+    ///
+    /// - `max_tokens` is 0
+    #[must_use]
+    pub fn new(model: &'a LlamaModel, max_tokens: usize) -> Self {
+        assert!(max_tokens > 0, "a chunk must hold at least one token");
+        Self {
+            model,
+            max_tokens,
+            overlap: 0,
+        }
+    }
+
+    /// Let consecutive chunks share up to `overlap` tokens.
+    ///
+    /// # Panics
+    ///
+    /// - `overlap` is not smaller than the maximum number of tokens of a chunk
+    #[must_use]
+    pub fn with_overlap(mut self, overlap: usize) -> Self {
+        assert!(
+            overlap < self.max_tokens,
+            "the overlap must be smaller than a chunk"
+        );
+        self.overlap = overlap;
+        self
+    }
+
+    /// The maximum number of tokens of a chunk.
+    #[must_use]
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// The maximum number of tokens consecutive chunks share.
+    #[must_use]
+    pub fn overlap(&self) -> usize {
+        self.overlap
+    }
+
+    /// Split `text` into chunks, in order. Whitespace only text has no chunks.
+    ///
+    /// A chunk only has more than [`Self::max_tokens`] tokens if a single character does.
+    ///
+    /// # Errors
+    ///
+    /// - `text` contains a null byte
+    pub fn split(&self, text: &str) -> Result<Vec<TextChunk>, StringToTokenError> {
+        split(text, self.max_tokens, self.overlap, &|text| {
+            Ok(self.model.str_to_token(text, AddBos::Never)?.len())
+        })
+    }
+}
+
+/// Counts the tokens of a piece of text.
+type Count<'a> = dyn Fn(&str) -> Result<usize, StringToTokenError> + 'a;
+
+/// See [`TextSplitter::split`].
+fn split(
+    text: &str,
+    max_tokens: usize,
+    overlap: usize,
+    count: &Count,
+) -> Result<Vec<TextChunk>, StringToTokenError> {
+    let count = |text: &str| if text.is_empty() { Ok(0) } else { count(text) };
+    let mut segments = Vec::new();
+    segment(
+        text,
+        0..text.len(),
+        Boundary::Paragraph,
+        max_tokens,
+        &count,
+        &mut segments,
+    )?;
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < segments.len() {
+        // estimate the size of a chunk from the sizes of its segments
+        let mut end = start + 1;
+        let mut estimate = segments[start].n_tokens;
+        while end < segments.len() && estimate + segments[end].n_tokens <= max_tokens {
+            estimate += segments[end].n_tokens;
+            end += 1;
+        }
+        // then shrink it until the exact size fits
+        let chunk = loop {
+            let span = trim(text, segments[start].span.start..segments[end - 1].span.end);
+            let n_tokens = count(&text[span.clone()])?;
+            if n_tokens <= max_tokens || end == start + 1 {
+                break TextChunk {
+                    text: text[span.clone()].to_string(),
+                    span,
+                    n_tokens,
+                };
+            }
+            end -= 1;
+        };
+        if !chunk.span.is_empty() {
+            chunks.push(chunk);
+        }
+        if end == segments.len() {
+            break;
+        }
+
+        // step back over the segments the next chunk shares with this one
+        let mut next = end;
+        let mut shared = 0;
+        while next > start + 1 && shared + segments[next - 1].n_tokens <= overlap {
+            shared += segments[next - 1].n_tokens;
+            next -= 1;
+        }
+        start = next;
+    }
+    Ok(chunks)
+}
+
+/// Split `span` of `text` at `boundary`, and the pieces at the following boundaries, until every
+/// segment fits into a chunk or is a single character.
+fn segment(
+    text: &str,
+    span: Range<usize>,
+    boundary: Boundary,
+    max_tokens: usize,
+    count: &Count,
+    segments: &mut Vec<Segment>,
+) -> Result<(), StringToTokenError> {
+    let n_tokens = count(&text[span.clone()])?;
+    if n_tokens <= max_tokens || text[span.clone()].chars().nth(1).is_none() {
+        segments.push(Segment { span, n_tokens });
+        return Ok(());
+    }
+    // pieces between characters are single characters, which are not split any further
+    let next = boundary.next().unwrap_or(Boundary::Char);
+    let points = boundary.split_points(&text[span.clone()]);
+    if points.is_empty() {
+        return segment(text, span, next, max_tokens, count, segments);
+    }
+    let mut start = span.start;
+    for point in points {
+        segment(
+            text,
+            start..span.start + point,
+            next,
+            max_tokens,
+            count,
+            segments,
+        )?;
+        start = span.start + point;
+    }
+    segment(text, start..span.end, next, max_tokens, count, segments)
+}
+
+/// `span` without the leading and trailing whitespace of its text.
+fn trim(text: &str, span: Range<usize>) -> Range<usize> {
+    let slice = &text[span.clone()];
+    let start = span.start + (slice.len() - slice.trim_start().len());
+    let end = span.end - (slice.len() - slice.trim_end().len());
+    start..end.max(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split with one token per character.
+    fn split_chars(text: &str, max_tokens: usize, overlap: usize) -> Vec<TextChunk> {
+        split(text, max_tokens, overlap, &|text| Ok(text.chars().count())).unwrap()
+    }
+
+    fn texts(chunks: &[TextChunk]) -> Vec<&str> {
+        chunks.iter().map(|chunk| chunk.text.as_str()).collect()
+    }
+
+    #[test]
+    fn prefers_paragraphs() {
+        let text = "One two.\n\nThree four.";
+        let chunks = split_chars(text, 12, 0);
+        assert_eq!(texts(&chunks), ["One two.", "Three four."]);
+        for chunk in &chunks {
+            assert_eq!(chunk.text, &text[chunk.span.clone()]);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_words() {
+        let chunks = split_chars("alpha beta gamma", 11, 0);
+        assert_eq!(texts(&chunks), ["alpha beta", "gamma"]);
+    }
+
+    #[test]
+    fn splits_an_oversized_word() {
+        let text = "a supercalifragilistic word";
+        let chunks = split_chars(text, 5, 0);
+        assert!(chunks.iter().all(|chunk| chunk.n_tokens <= 5));
+        assert_eq!(
+            texts(&chunks),
+            ["a sup", "ercal", "ifrag", "ilist", "ic", "word"]
+        );
+    }
+
+    #[test]
+    fn splits_text_without_whitespace() {
+        let text = "東京は日本の首都です";
+        let chunks = split_chars(text, 3, 0);
+        assert!(chunks.iter().all(|chunk| chunk.n_tokens <= 3));
+        assert_eq!(texts(&chunks), ["東京は", "日本の", "首都で", "す"]);
+        for chunk in &chunks {
+            assert_eq!(chunk.text, &text[chunk.span.clone()]);
+        }
+    }
+
+    #[test]
+    fn overlaps_chunks() {
+        let chunks = split_chars("a b c d e", 5, 2);
+        assert_eq!(texts(&chunks), ["a b", "b c", "c d e"]);
+    }
+
+    #[test]
+    fn whitespace_has_no_chunks() {
+        assert!(split_chars(" \n\n\t ", 4, 0).is_empty());
+    }
+}
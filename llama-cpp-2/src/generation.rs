@@ -0,0 +1,475 @@
+//! A high level loop that decodes a prompt and samples tokens until a stop condition is met.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::Generator;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//! use llama_cpp_2::sampling::LlamaSampler;
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! let prompt = model.str_to_token("The capital of France is", AddBos::Always)?;
+//! let mut sampler = LlamaSampler::greedy();
+//!
+//! let generator = Generator::new(&mut ctx, &mut sampler, &prompt)
+//!     .with_max_tokens(32)
+//!     .with_stop_sequences(["\n"]);
+//! for generated in generator {
+//!     let generated = generated?;
+//!     print!("{}", generated.piece);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::generation::stop::{StopCondition, StopMatcher};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::Special;
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{DecodeError, EncodeError, TokenToStringError};
+
+pub mod beam;
+pub mod scheduler;
+pub mod speculative;
+pub mod stop;
+#[cfg(feature = "async")]
+pub mod stream;
+
+/// A token produced by a [`Generator`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedToken {
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// The text released with this token. Bytes of an incomplete utf8 character and text that
+    /// may be the start of a stop sequence are held back and released with a later token. Text
+    /// from a stop sequence onwards is never released.
+    pub piece: String,
+    /// The log probability of the token under the model, before any sampler transformations.
+    pub logprob: f32,
+}
+
+/// Why a [`Generator`] stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced an end of generation token.
+    EndOfGeneration,
+    /// The maximum number of tokens was generated.
+    MaxTokens,
+    /// A [`StopCondition`] fired.
+    Stop(StopCondition),
+    /// The sequence ran out of room in the context.
+    ContextFull,
+}
+
+/// Failed to generate a token.
+#[derive(Debug, thiserror::Error)]
+pub enum GenerateError {
+    /// There is nothing to sample from as no prompt was given.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt or the pushed tokens do not fit into the context. Generated tokens that do
+    /// not fit end generation with [`FinishReason::ContextFull`] instead.
+    #[error("{n_tokens} tokens do not fit into a context of {n_ctx}")]
+    ContextFull {
+        /// The number of tokens the sequence would hold.
+        n_tokens: usize,
+        /// The size of the context.
+        n_ctx: usize,
+    },
+    /// There is nothing to encode as the source of [`Generator::seq2seq`] is empty.
+    #[error("the source is empty")]
+    EmptySource,
+    /// The source of [`Generator::seq2seq`] does not fit into a single ubatch, which the encoder
+    /// requires.
+    #[error("the source has {n_tokens} tokens, but the encoder takes at most {n_ubatch}")]
+    SourceTooLong {
+        /// The number of tokens of the source.
+        n_tokens: usize,
+        /// The size of a ubatch, see [`LlamaContext::n_ubatch`].
+        n_ubatch: usize,
+    },
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// See [`EncodeError`].
+    #[error("{0}")]
+    EncodeError(#[from] EncodeError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// See [`TokenToStringError`].
+    #[error("{0}")]
+    TokenToStringError(#[from] TokenToStringError),
+}
+
+/// Decodes a prompt and yields sampled tokens until an end of generation token, the token limit,
+/// a stop condition or the end of the context is reached.
+///
+/// The prompt is decoded on the first call to [`Iterator::next`], in batches of at most
+/// [`LlamaContext::n_batch`] tokens. Every yielded token is decoded into the sequence, so the kv
+/// cache holds [`Self::tokens`] after the last yielded token. The exception is an end of
+/// generation token or a stop token: it is yielded last, without being decoded, to release the
+/// text that was held back. After an error the iterator ends.
+///
+/// Generation finishes with [`FinishReason::ContextFull`] once the sequence fills the context,
+/// see [`LlamaContext::n_ctx`]. Only a prompt or pushed tokens that do not fit are an error.
+#[derive(Debug)]
+pub struct Generator<'a, 'model> {
+    ctx: &'a mut LlamaContext<'model>,
+    sampler: &'a mut LlamaSampler,
+    prompt: Vec<LlamaToken>,
+    /// The input of the encoder, if it still has to be encoded.
+    source: Option<Vec<LlamaToken>>,
+    seq_id: i32,
+    start_pos: usize,
+    max_tokens: Option<usize>,
+    stop: StopMatcher,
+    tokens: Vec<LlamaToken>,
+    logits_index: Option<i32>,
+    n_generated: usize,
+    pending: Vec<u8>,
+    finish_reason: Option<FinishReason>,
+    failed: bool,
+}
+
+impl<'a, 'model> Generator<'a, 'model> {
+    /// Create a generator that decodes `prompt` into sequence 0, starting at position 0.
+    #[must_use]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+    ) -> Self {
+        Self {
+            ctx,
+            sampler,
+            prompt: prompt.to_vec(),
+            source: None,
+            seq_id: 0,
+            start_pos: 0,
+            max_tokens: None,
+            stop: StopMatcher::default(),
+            tokens: Vec::new(),
+            logits_index: None,
+            n_generated: 0,
+            pending: Vec::new(),
+            finish_reason: None,
+            failed: false,
+        }
+    }
+
+    /// Create a generator that translates `source` with an encoder-decoder model such as T5.
+    ///
+    /// If the model [has an encoder](crate::model::LlamaModel::has_encoder), `source` is encoded
+    /// on the first call to [`Iterator::next`] and decoding starts from
+    /// [`crate::model::LlamaModel::decode_start_token`], or from BOS if the model does not define
+    /// one. Otherwise `source` is the prompt, as with [`Self::new`]. Encoding replaces the
+    /// previous encoder output of the context, the decoder sequence should be empty.
+    ///
+    /// The encoder takes the whole source at once, so it must not be empty and must fit into
+    /// [`LlamaContext::n_ubatch`], otherwise the first call to [`Iterator::next`] fails with
+    /// [`GenerateError::EmptySource`] or [`GenerateError::SourceTooLong`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::generation::Generator;
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// let model = LlamaModel::load_from_file(&backend, "path/to/t5", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let source = model.str_to_token("translate English to German: Hello", AddBos::Always)?;
+    /// let mut sampler = LlamaSampler::greedy();
+    ///
+    /// for generated in Generator::seq2seq(&mut ctx, &mut sampler, &source).with_max_tokens(64) {
+    ///     print!("{}", generated?.piece);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn seq2seq(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        source: &[LlamaToken],
+    ) -> Self {
+        let model = ctx.model;
+        if !model.has_encoder() {
+            return Self::new(ctx, sampler, source);
+        }
+        let start = match model.decode_start_token() {
+            LlamaToken(-1) => model.token_bos(),
+            start => start,
+        };
+        let mut generator = Self::new(ctx, sampler, &[start]);
+        generator.source = Some(source.to_vec());
+        generator
+    }
+
+    /// Create a generator that continues from the logits at batch index `logits_index` of the last
+    /// decode, whose last token was at position `n_past - 1`.
+    #[must_use]
+    pub fn resume(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        logits_index: i32,
+        n_past: usize,
+    ) -> Self {
+        let mut generator = Self::new(ctx, sampler, &[]).with_start_pos(n_past);
+        generator.logits_index = Some(logits_index);
+        generator
+    }
+
+    /// The sequence to decode into. Defaults to 0.
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: i32) -> Self {
+        self.seq_id = seq_id;
+        self
+    }
+
+    /// The position of the first prompt token. Everything in the sequence from this position on
+    /// should have been removed. Defaults to 0.
+    #[must_use]
+    pub fn with_start_pos(mut self, start_pos: usize) -> Self {
+        self.start_pos = start_pos;
+        self
+    }
+
+    /// Stop after `max_tokens` tokens. Unlimited by default, in which case generation ends at an
+    /// end of generation token, a stop sequence or when the context is full.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Stop as soon as the generated text contains one of `stop_sequences`, even if it is split
+    /// across tokens. The stop sequence and anything after it is not part of the yielded text.
+    #[must_use]
+    pub fn with_stop_sequences(
+        self,
+        stop_sequences: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.with_stop_conditions(stop_sequences.into_iter().map(Into::<String>::into))
+    }
+
+    /// Stop before one of `stop_tokens` is generated, in addition to end of generation tokens.
+    #[must_use]
+    pub fn with_stop_tokens(self, stop_tokens: impl IntoIterator<Item = LlamaToken>) -> Self {
+        self.with_stop_conditions(stop_tokens)
+    }
+
+    /// Add stop conditions, see [`StopMatcher`].
+    #[must_use]
+    pub fn with_stop_conditions(
+        mut self,
+        conditions: impl IntoIterator<Item = impl Into<StopCondition>>,
+    ) -> Self {
+        for condition in conditions {
+            self.stop.add(condition);
+        }
+        self
+    }
+
+    /// Why generation stopped, or `None` while it is still running or after an error.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    /// The number of tokens generated so far.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    /// Every token this generator decoded into the sequence: the prompt, the sampled tokens and
+    /// tokens added with [`Self::push_tokens`].
+    #[must_use]
+    pub fn tokens(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+
+    /// The position the next decoded token will have.
+    #[must_use]
+    pub fn n_past(&self) -> usize {
+        self.start_pos + self.tokens.len()
+    }
+
+    /// The batch index of the logits the next token will be sampled from.
+    #[must_use]
+    pub fn logits_index(&self) -> Option<i32> {
+        self.logits_index
+    }
+
+    /// The context the generator decodes into.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// Decode `tokens` into the sequence, e.g. to force the model to continue with a given text.
+    /// The next token is sampled after them.
+    ///
+    /// # Errors
+    ///
+    /// If decoding failed.
+    pub fn push_tokens(&mut self, tokens: &[LlamaToken]) -> Result<(), GenerateError> {
+        let mut all = std::mem::take(&mut self.prompt);
+        all.extend_from_slice(tokens);
+        self.decode(&all)
+    }
+
+    fn step(&mut self) -> Result<Option<GeneratedToken>, GenerateError> {
+        if let Some(source) = self.source.take() {
+            self.encode(&source)?;
+        }
+        if !self.prompt.is_empty() {
+            let prompt = std::mem::take(&mut self.prompt);
+            self.decode(&prompt)?;
+        }
+        if self.max_tokens.is_some_and(|max| self.n_generated >= max) {
+            self.finish_reason = Some(FinishReason::MaxTokens);
+            return Ok(None);
+        }
+        let index = self.logits_index.ok_or(GenerateError::EmptyPrompt)?;
+        if self.context_full() {
+            self.finish_reason = Some(FinishReason::ContextFull);
+            return Ok(None);
+        }
+
+        let token = self.sampler.sample(self.ctx, index);
+        let logprob = logprob(self.ctx.get_logits_ith(index), token);
+        let model = self.ctx.model;
+        let stop_token = self.stop.stop_token(token);
+        if stop_token.is_some() || model.is_eog_token(token) {
+            self.finish_reason = Some(match stop_token {
+                Some(index) => FinishReason::Stop(self.stop.conditions()[index].clone()),
+                None => FinishReason::EndOfGeneration,
+            });
+            return Ok(Some(GeneratedToken {
+                token,
+                piece: self.flush(),
+                logprob,
+            }));
+        }
+
+        self.n_generated += 1;
+        self.pending
+            .extend(model.token_to_bytes(token, Special::Tokenize)?);
+        let text = take_utf8(&mut self.pending, false);
+        self.decode(&[token])?;
+
+        let stop = self.stop.push(token, &text);
+        let mut piece = stop.text;
+        if let Some(index) = stop.stopped {
+            self.pending.clear();
+            self.finish_reason = Some(FinishReason::Stop(self.stop.conditions()[index].clone()));
+        } else if self.max_tokens.is_some_and(|max| self.n_generated >= max) {
+            piece.push_str(&self.flush());
+            self.finish_reason = Some(FinishReason::MaxTokens);
+        } else if self.context_full() {
+            piece.push_str(&self.flush());
+            self.finish_reason = Some(FinishReason::ContextFull);
+        }
+        Ok(Some(GeneratedToken {
+            token,
+            piece,
+            logprob,
+        }))
+    }
+
+    /// Whether there is no room left to decode another token into the sequence.
+    fn context_full(&self) -> bool {
+        self.n_past() >= self.ctx.n_ctx() as usize
+    }
+
+    /// Release all text that was held back.
+    fn flush(&mut self) -> String {
+        let mut text = self.stop.flush();
+        text.push_str(&take_utf8(&mut self.pending, true));
+        text
+    }
+
+    /// Run the encoder over `source`, which must fit into a single ubatch.
+    fn encode(&mut self, source: &[LlamaToken]) -> Result<(), GenerateError> {
+        if source.is_empty() {
+            return Err(GenerateError::EmptySource);
+        }
+        let n_ubatch = self.ctx.n_ubatch() as usize;
+        if source.len() > n_ubatch {
+            return Err(GenerateError::SourceTooLong {
+                n_tokens: source.len(),
+                n_ubatch,
+            });
+        }
+        let mut batch = LlamaBatch::new(source.len(), 1);
+        batch.add_sequence(source, self.seq_id, false)?;
+        self.ctx.encode(&mut batch)?;
+        Ok(())
+    }
+
+    fn decode(&mut self, tokens: &[LlamaToken]) -> Result<(), GenerateError> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let n_ctx = self.ctx.n_ctx() as usize;
+        if self.n_past() + tokens.len() > n_ctx {
+            return Err(GenerateError::ContextFull {
+                n_tokens: self.n_past() + tokens.len(),
+                n_ctx,
+            });
+        }
+        let start_pos = i32::try_from(self.n_past()).expect("position fits into an i32");
+        self.logits_index = None;
+        let prefill = self.ctx.prefill(tokens, self.seq_id, start_pos)?;
+        self.tokens.extend_from_slice(tokens);
+        self.logits_index = Some(prefill.logits_index);
+        Ok(())
+    }
+}
+
+impl Iterator for Generator<'_, '_> {
+    type Item = Result<GeneratedToken, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.finish_reason.is_some() {
+            return None;
+        }
+        match self.step() {
+            Ok(generated) => generated.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// The log softmax of `logits` at `token`.
+pub(crate) fn logprob(logits: &[f32], token: LlamaToken) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    let index = usize::try_from(token.0).expect("sampled token is not negative");
+    logits[index] - max - sum.ln()
+}
+
+/// Take the longest valid utf8 prefix of `bytes`, keeping an incomplete trailing character unless
+/// `flush` is set. Invalid sequences are replaced.
+pub(crate) fn take_utf8(bytes: &mut Vec<u8>, flush: bool) -> String {
+    let valid = match std::str::from_utf8(bytes) {
+        Err(err) if err.error_len().is_none() && !flush => err.valid_up_to(),
+        _ => bytes.len(),
+    };
+    let text = String::from_utf8_lossy(&bytes[..valid]).into_owned();
+    bytes.drain(..valid);
+    text
+}
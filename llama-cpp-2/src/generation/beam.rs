@@ -0,0 +1,489 @@
+//! Beam search: keep the `width` most likely continuations at every step instead of committing
+//! to a single sampled token, as used for translation and constrained extraction.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::beam::{beam_search, BeamParams};
+//! use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(4);
+//! let mut ctx = model.new_context(&backend, params)?;
+//! let prompt = model.str_to_token("Translate to French: good morning\n", AddBos::Always)?;
+//!
+//! let beam_params = BeamParams {
+//!     width: 4,
+//!     max_len: 32,
+//!     ..BeamParams::default()
+//! };
+//! for hypothesis in beam_search(&mut ctx, &prompt, &beam_params)? {
+//!     let text = model.tokens_to_str(&hypothesis.tokens, Special::Plaintext)?;
+//!     println!("{:.3} {text}", hypothesis.logprob);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// What to do when a beam produces an end of generation token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EosHandling {
+    /// The beam is finished: it is set aside as a hypothesis and its place is taken by the next
+    /// best continuation.
+    Finish,
+    /// End of generation tokens are never chosen, so every hypothesis has `max_len` tokens.
+    Suppress,
+}
+
+/// The parameters of [`beam_search`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamParams {
+    /// The number of beams, each of which occupies a sequence of the context.
+    pub width: usize,
+    /// The maximum number of tokens of a hypothesis.
+    pub max_len: usize,
+    /// Hypotheses are ranked by their log probability divided by `length ^ length_penalty`.
+    /// 0 ranks by the log probability alone, which favors short hypotheses, larger values favor
+    /// longer ones.
+    pub length_penalty: f32,
+    /// What to do with end of generation tokens.
+    pub eos: EosHandling,
+    /// The number of hypotheses to return.
+    pub n_best: usize,
+}
+
+/// 4 beams of up to 64 tokens, a length penalty of 1, finishing beams at end of generation
+/// tokens and returning all 4 hypotheses.
+impl Default for BeamParams {
+    fn default() -> Self {
+        Self {
+            width: 4,
+            max_len: 64,
+            length_penalty: 1.0,
+            eos: EosHandling::Finish,
+            n_best: 4,
+        }
+    }
+}
+
+/// A result of [`beam_search`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, ending with the end of generation token if [`Self::finished`].
+    pub tokens: Vec<LlamaToken>,
+    /// The sum of the log probabilities of the tokens.
+    pub logprob: f32,
+    /// [`Self::logprob`] with the length penalty applied, which hypotheses are ranked by.
+    pub score: f32,
+    /// Whether the hypothesis ended with an end of generation token, rather than at `max_len`.
+    pub finished: bool,
+}
+
+impl Hypothesis {
+    #[allow(clippy::cast_precision_loss)] // token counts are far below the precision of an f32
+    fn new(tokens: Vec<LlamaToken>, logprob: f32, finished: bool, length_penalty: f32) -> Self {
+        let length = tokens.len().max(1) as f32;
+        Self {
+            score: logprob / length.powf(length_penalty),
+            tokens,
+            logprob,
+            finished,
+        }
+    }
+}
+
+/// Failed to run a beam search.
+#[derive(Debug, thiserror::Error)]
+pub enum BeamSearchError {
+    /// There is nothing to continue as no prompt was given.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The context has fewer sequences than beams.
+    #[error("{width} beams need {width} sequences, but the context has {n_seq_max}")]
+    TooManyBeams {
+        /// The number of beams.
+        width: usize,
+        /// The number of sequences of the context.
+        n_seq_max: u32,
+    },
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// See [`KvCacheConversionError`].
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// A continuation that is still being extended.
+#[derive(Debug)]
+struct Beam {
+    seq_id: i32,
+    tokens: Vec<LlamaToken>,
+    logprob: f32,
+    logits_index: i32,
+}
+
+/// Continue `prompt` with beam search and return the best [`BeamParams::n_best`] hypotheses,
+/// best first.
+///
+/// Every beam lives in its own sequence: the prompt is decoded into sequence 0 once, a beam that
+/// continues in several ways is forked with [`LlamaContext::copy_kv_cache_seq`], and beams that
+/// fall out of the top `width` are removed with [`LlamaContext::clear_kv_cache_seq`]. Sequences
+/// `0..width` are cleared before and after the search. All beams share the kv cache, so the
+/// context must have room for the prompt plus `width * max_len` tokens.
+///
+/// The search stops early once [`BeamParams::n_best`] hypotheses are finished and no active beam
+/// can still score better than the worst of them, however it continues.
+///
+/// # Errors
+///
+/// - the prompt is empty
+/// - the context has fewer than `width` sequences
+/// - decoding failed
+///
+/// # Panics
+///
+/// - the width is 0
+pub fn beam_search(
+    ctx: &mut LlamaContext,
+    prompt: &[LlamaToken],
+    params: &BeamParams,
+) -> Result<Vec<Hypothesis>, BeamSearchError> {
+    assert!(params.width > 0, "beam search needs at least one beam");
+    if prompt.is_empty() {
+        return Err(BeamSearchError::EmptyPrompt);
+    }
+    let n_seq_max = ctx.n_seq_max();
+    if params.width > n_seq_max as usize {
+        return Err(BeamSearchError::TooManyBeams {
+            width: params.width,
+            n_seq_max,
+        });
+    }
+    let width = i32::try_from(params.width).expect("the number of sequences fits into an i32");
+
+    for seq_id in 0..width {
+        clear_seq(ctx, seq_id)?;
+    }
+    let result = search(ctx, prompt, params, width);
+    for seq_id in 0..width {
+        // keep the result of the search, a sequence left behind is cleared by the next search
+        if let Err(err) = clear_seq(ctx, seq_id) {
+            tracing::warn!(seq_id, %err, "failed to clear a beam sequence");
+        }
+    }
+    result
+}
+
+fn search(
+    ctx: &mut LlamaContext,
+    prompt: &[LlamaToken],
+    params: &BeamParams,
+    width: i32,
+) -> Result<Vec<Hypothesis>, BeamSearchError> {
+    let model = ctx.model;
+    let suppress = params.eos == EosHandling::Suppress;
+    let mut beams = vec![Beam {
+        seq_id: 0,
+        tokens: Vec::new(),
+        logprob: 0.0,
+        logits_index: ctx.prefill(prompt, 0, 0)?.logits_index,
+    }];
+    let mut hypotheses = Vec::new();
+    let mut batch = LlamaBatch::new(params.width, 1);
+
+    for step in 0..params.max_len {
+        // the best continuations of every beam, more than needed as some may finish
+        let mut candidates = Vec::new();
+        for (parent, beam) in beams.iter().enumerate() {
+            let logits = ctx.get_logits_ith(beam.logits_index);
+            let top = top_logprobs(logits, 2 * params.width, |token| {
+                suppress && model.is_eog_token(token)
+            });
+            for (token, logprob) in top {
+                candidates.push((parent, token, beam.logprob + logprob));
+            }
+        }
+        candidates.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+
+        let mut next = Vec::with_capacity(params.width);
+        for (parent, token, logprob) in candidates {
+            if next.len() == params.width {
+                break;
+            }
+            if model.is_eog_token(token) {
+                let tokens = [beams[parent].tokens.as_slice(), &[token]].concat();
+                hypotheses.push(Hypothesis::new(
+                    tokens,
+                    logprob,
+                    true,
+                    params.length_penalty,
+                ));
+            } else {
+                next.push((parent, token, logprob));
+            }
+        }
+
+        let last_step = step + 1 == params.max_len;
+        if next.is_empty() || last_step || is_done(&hypotheses, &next, step + 1, params) {
+            if last_step {
+                for (parent, token, logprob) in next {
+                    let tokens = [beams[parent].tokens.as_slice(), &[token]].concat();
+                    hypotheses.push(Hypothesis::new(
+                        tokens,
+                        logprob,
+                        false,
+                        params.length_penalty,
+                    ));
+                }
+            }
+            beams.clear();
+            break;
+        }
+
+        beams = fork(ctx, &beams, next, width)?;
+        batch.clear();
+        let pos = i32::try_from(prompt.len() + step).expect("position fits into an i32");
+        for (index, beam) in (0..).zip(&mut beams) {
+            let token = *beam.tokens.last().expect("a beam has a token after a step");
+            batch.add(token, pos, &[beam.seq_id], true)?;
+            beam.logits_index = index;
+        }
+        ctx.decode(&mut batch)?;
+    }
+
+    // only reached with beams left if `max_len` is 0
+    hypotheses.extend(
+        beams
+            .into_iter()
+            .map(|beam| Hypothesis::new(beam.tokens, beam.logprob, false, params.length_penalty)),
+    );
+    hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hypotheses.truncate(params.n_best);
+    Ok(hypotheses)
+}
+
+/// Whether enough hypotheses are finished that no continuation of `next`, which is sorted best
+/// first and has `length` tokens, can make it into the top [`BeamParams::n_best`] anymore.
+///
+/// A log probability only decreases as tokens are added. With a positive length penalty a longer
+/// hypothesis is divided by more though, so the best reachable score is that of the best beam
+/// keeping its log probability until `max_len`.
+#[allow(clippy::cast_precision_loss)] // token counts are far below the precision of an f32
+fn is_done(
+    hypotheses: &[Hypothesis],
+    next: &[(usize, LlamaToken, f32)],
+    length: usize,
+    params: &BeamParams,
+) -> bool {
+    if params.n_best == 0 || hypotheses.len() < params.n_best {
+        return false;
+    }
+    let mut scores = hypotheses.iter().map(|h| h.score).collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.total_cmp(a));
+    let worst = scores[params.n_best - 1];
+    let length = if params.length_penalty > 0.0 {
+        params.max_len.max(length)
+    } else {
+        length
+    };
+    let best = next[0].2 / (length as f32).powf(params.length_penalty);
+    best < worst
+}
+
+/// Turn the chosen continuations into beams. The first child of a beam takes over its sequence,
+/// further children get a copy of it in a sequence no longer needed. Unused sequences are
+/// cleared.
+fn fork(
+    ctx: &mut LlamaContext,
+    beams: &[Beam],
+    next: Vec<(usize, LlamaToken, f32)>,
+    width: i32,
+) -> Result<Vec<Beam>, BeamSearchError> {
+    let parent_seqs = beams.iter().map(|beam| beam.seq_id).collect::<Vec<_>>();
+    let parents = next
+        .iter()
+        .map(|&(parent, _, _)| parent)
+        .collect::<Vec<_>>();
+    let (seqs, free) = assign_seqs(&parent_seqs, &parents, width);
+
+    let mut children = Vec::with_capacity(next.len());
+    for ((parent, token, logprob), seq_id) in next.into_iter().zip(seqs) {
+        let parent = &beams[parent];
+        if seq_id != parent.seq_id {
+            clear_seq(ctx, seq_id)?;
+            ctx.copy_kv_cache_seq(parent.seq_id, seq_id, None, None)?;
+        }
+        children.push(Beam {
+            seq_id,
+            tokens: [parent.tokens.as_slice(), &[token]].concat(),
+            logprob,
+            logits_index: 0,
+        });
+    }
+    for seq_id in free {
+        clear_seq(ctx, seq_id)?;
+    }
+    Ok(children)
+}
+
+/// The sequence of each child given the sequences of the beams and the beam each child
+/// continues, and the sequences of `0..width` no child uses.
+fn assign_seqs(parent_seqs: &[i32], parents: &[usize], width: i32) -> (Vec<i32>, Vec<i32>) {
+    let mut has_child = vec![false; parent_seqs.len()];
+    for &parent in parents {
+        has_child[parent] = true;
+    }
+    let mut free = (0..width)
+        .filter(|seq_id| {
+            !parent_seqs
+                .iter()
+                .zip(&has_child)
+                .any(|(parent_seq, &has_child)| has_child && parent_seq == seq_id)
+        })
+        .collect::<Vec<_>>();
+
+    let mut taken = vec![false; parent_seqs.len()];
+    let seqs = parents
+        .iter()
+        .map(|&parent| {
+            if taken[parent] {
+                free.pop().expect("there is a sequence for every beam")
+            } else {
+                taken[parent] = true;
+                parent_seqs[parent]
+            }
+        })
+        .collect();
+    (seqs, free)
+}
+
+/// The `k` tokens with the highest log probability under `logits`, leaving out `skip`ped ones.
+fn top_logprobs(
+    logits: &[f32],
+    k: usize,
+    skip: impl Fn(LlamaToken) -> bool,
+) -> Vec<(LlamaToken, f32)> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits
+        .iter()
+        .map(|logit| (logit - max).exp())
+        .sum::<f32>()
+        .ln();
+    let mut top = (0..)
+        .zip(logits)
+        .map(|(token, logit)| (LlamaToken(token), logit - max - log_sum))
+        .filter(|&(token, _)| !skip(token))
+        .collect::<Vec<_>>();
+    if top.len() > k {
+        top.select_nth_unstable_by(k, |(_, a), (_, b)| b.total_cmp(a));
+        top.truncate(k);
+    }
+    top
+}
+
+fn clear_seq(ctx: &mut LlamaContext, seq_id: i32) -> Result<(), KvCacheConversionError> {
+    let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+    ctx.clear_kv_cache_seq(Some(seq), None, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hypothesis(score: f32) -> Hypothesis {
+        Hypothesis {
+            tokens: vec![LlamaToken(0)],
+            logprob: score,
+            score,
+            finished: true,
+        }
+    }
+
+    #[test]
+    fn top_logprobs_are_normalized_and_best_first() {
+        let logits = [1.0, 3.0, 2.0, 3.0_f32.ln()];
+        let mut top = top_logprobs(&logits, 2, |_| false);
+        top.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        let tokens = top.iter().map(|&(token, _)| token).collect::<Vec<_>>();
+        assert_eq!(tokens, [LlamaToken(1), LlamaToken(2)]);
+
+        let all = top_logprobs(&logits, logits.len(), |_| false);
+        let total = all.iter().map(|(_, logprob)| logprob.exp()).sum::<f32>();
+        assert!((total - 1.0).abs() < 1e-6);
+        let log_sum = logits.iter().map(|logit| logit.exp()).sum::<f32>().ln();
+        assert!((top[0].1 - (3.0 - log_sum)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn skipped_tokens_are_left_out_but_still_normalize() {
+        let logits = [0.0, 5.0, 0.0];
+        let top = top_logprobs(&logits, 3, |token| token == LlamaToken(1));
+        assert_eq!(top.len(), 2);
+        assert!(top.iter().all(|&(token, _)| token != LlamaToken(1)));
+        // the probability of the skipped token is not redistributed
+        let total = top.iter().map(|(_, logprob)| logprob.exp()).sum::<f32>();
+        assert!(total < 0.05);
+    }
+
+    #[test]
+    fn forked_sequences_are_unique() {
+        // beam 0 in sequence 2 continues three ways, beam 1 in sequence 0 none, beam 2 in
+        // sequence 1 once
+        let (seqs, free) = assign_seqs(&[2, 0, 1], &[0, 2, 0, 0], 4);
+        assert_eq!(seqs[0], 2);
+        assert_eq!(seqs[1], 1);
+        let mut used = seqs.clone();
+        used.extend(&free);
+        used.sort_unstable();
+        assert_eq!(used, [0, 1, 2, 3]);
+        assert!(free.is_empty());
+
+        // a beam without children frees its sequence
+        let (seqs, free) = assign_seqs(&[0, 1], &[1], 2);
+        assert_eq!(seqs, [1]);
+        assert_eq!(free, [0]);
+    }
+
+    #[test]
+    fn done_once_no_beam_can_catch_up() {
+        let params = BeamParams {
+            n_best: 2,
+            length_penalty: 0.0,
+            max_len: 10,
+            ..BeamParams::default()
+        };
+        let finished = [hypothesis(-1.0), hypothesis(-2.0)];
+        let next = |logprob| [(0, LlamaToken(0), logprob)];
+        assert!(is_done(&finished, &next(-3.0), 2, &params));
+        assert!(!is_done(&finished, &next(-1.5), 2, &params));
+        // not enough hypotheses yet
+        assert!(!is_done(&finished[..1], &next(-3.0), 2, &params));
+    }
+
+    #[test]
+    fn length_penalty_bounds_by_max_len() {
+        let params = BeamParams {
+            n_best: 1,
+            length_penalty: 1.0,
+            max_len: 10,
+            ..BeamParams::default()
+        };
+        // -3 over 2 tokens scores worse than -1, but could reach -3 / 10 at max_len
+        let finished = [hypothesis(-1.0)];
+        assert!(!is_done(&finished, &[(0, LlamaToken(0), -3.0)], 2, &params));
+        assert!(is_done(&finished, &[(0, LlamaToken(0), -20.0)], 2, &params));
+    }
+}
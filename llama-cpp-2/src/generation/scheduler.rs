@@ -0,0 +1,683 @@
+//! Serve many requests from one context by decoding their sequences together (continuous
+//! batching).
+//!
+//! Every [`Scheduler::step`] packs a single [`LlamaBatch`] with the next token of every request
+//! that is generating and fills the remaining room with chunks of prompts that are still being
+//! decoded, so new requests start without waiting for the running ones to finish.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::scheduler::{Request, Scheduler, SchedulerEvent};
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//! use llama_cpp_2::sampling::LlamaSampler;
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(4);
+//! let mut ctx = model.new_context(&backend, params)?;
+//! let mut scheduler = Scheduler::new(&mut ctx);
+//!
+//! for prompt in ["The capital of France is", "1, 2, 3,", "Once upon a time"] {
+//!     let prompt = model.str_to_token(prompt, AddBos::Always)?;
+//!     scheduler.add(Request::new(prompt, LlamaSampler::greedy()).with_max_tokens(16))?;
+//! }
+//! while !scheduler.is_idle() {
+//!     for event in scheduler.step()? {
+//!         match event {
+//!             SchedulerEvent::Token { request, token } => println!("{request}: {}", token.piece),
+//!             SchedulerEvent::Finished { request, finish_reason } => {
+//!                 println!("{request} finished: {finish_reason:?}");
+//!             }
+//!         }
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::generation::stop::{StopCondition, StopMatcher};
+use crate::generation::{logprob, take_utf8, FinishReason, GeneratedToken};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::Special;
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{DecodeError, TokenToStringError};
+
+/// Identifies a request added to a [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(pub u64);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request {}", self.0)
+    }
+}
+
+/// A prompt to complete, together with the sampler that picks its tokens.
+#[derive(Debug)]
+pub struct Request {
+    prompt: Vec<LlamaToken>,
+    sampler: LlamaSampler,
+    max_tokens: Option<usize>,
+    stop: StopMatcher,
+}
+
+impl Request {
+    /// Complete `prompt`, sampling with `sampler`.
+    #[must_use]
+    pub fn new(prompt: Vec<LlamaToken>, sampler: LlamaSampler) -> Self {
+        Self {
+            prompt,
+            sampler,
+            max_tokens: None,
+            stop: StopMatcher::default(),
+        }
+    }
+
+    /// Stop after `max_tokens` tokens. Unlimited by default.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Add stop conditions, see [`StopMatcher`].
+    #[must_use]
+    pub fn with_stop_conditions(
+        mut self,
+        conditions: impl IntoIterator<Item = impl Into<StopCondition>>,
+    ) -> Self {
+        for condition in conditions {
+            self.stop.add(condition);
+        }
+        self
+    }
+}
+
+/// Something that happened to a request during [`Scheduler::step`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchedulerEvent {
+    /// A token was generated, see [`GeneratedToken`]. As with [`crate::generation::Generator`],
+    /// a final end of generation or stop token is reported without being decoded.
+    Token {
+        /// The request the token belongs to.
+        request: RequestId,
+        /// The generated token.
+        token: GeneratedToken,
+    },
+    /// The request is done and its sequence was freed. This is the last event for the request.
+    Finished {
+        /// The request that finished.
+        request: RequestId,
+        /// Why it finished.
+        finish_reason: FinishReason,
+    },
+}
+
+/// Failed to schedule or run requests.
+#[derive(Debug, thiserror::Error)]
+pub enum SchedulerError {
+    /// The request has no prompt to sample from.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the room a sequence has in the context.
+    #[error("{n_tokens} tokens do not fit into a sequence of {n_ctx_seq}")]
+    PromptTooLong {
+        /// The number of prompt tokens.
+        n_tokens: usize,
+        /// The number of tokens a sequence can hold.
+        n_ctx_seq: usize,
+    },
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// See [`TokenToStringError`].
+    #[error("{0}")]
+    TokenToStringError(#[from] TokenToStringError),
+    /// See [`KvCacheConversionError`].
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// How far a request got, restored when a step fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Progress {
+    /// The number of prompt tokens in the kv cache.
+    n_prefilled: usize,
+    /// The position of the next token.
+    n_past: usize,
+    /// The sampled token that still has to be decoded.
+    next: Option<LlamaToken>,
+    n_generated: usize,
+}
+
+impl Progress {
+    /// Account for `n_tokens` decoded tokens: the pending token or a chunk of the prompt.
+    fn advance(&mut self, n_tokens: usize) {
+        if self.next.take().is_none() {
+            self.n_prefilled += n_tokens;
+        }
+        self.n_past += n_tokens;
+    }
+}
+
+/// Turns the generated tokens of a request into text and decides when it is done.
+#[derive(Debug, Default)]
+struct Output {
+    stop: StopMatcher,
+    max_tokens: Option<usize>,
+    pending: Vec<u8>,
+}
+
+impl Output {
+    /// The text still held back, released as the request ends at an end of generation or stop
+    /// token.
+    fn finish(&mut self) -> String {
+        let mut piece = self.stop.flush();
+        piece.push_str(&take_utf8(&mut self.pending, true));
+        piece
+    }
+
+    /// Add a generated `token` with the text `bytes`, returning the text to release and why the
+    /// request finished if it did. A stop string wins over `max_tokens`, which wins over a full
+    /// sequence.
+    fn push(
+        &mut self,
+        progress: &mut Progress,
+        token: LlamaToken,
+        bytes: &[u8],
+        n_ctx_seq: usize,
+    ) -> (String, Option<FinishReason>) {
+        progress.n_generated += 1;
+        self.pending.extend_from_slice(bytes);
+        let text = take_utf8(&mut self.pending, false);
+        let stop = self.stop.push(token, &text);
+        let mut piece = stop.text;
+        let finish_reason = if let Some(index) = stop.stopped {
+            Some(FinishReason::Stop(self.stop.conditions()[index].clone()))
+        } else if self
+            .max_tokens
+            .is_some_and(|max| progress.n_generated >= max)
+        {
+            Some(FinishReason::MaxTokens)
+        } else if progress.n_past >= n_ctx_seq {
+            Some(FinishReason::ContextFull)
+        } else {
+            progress.next = Some(token);
+            None
+        };
+        if matches!(
+            finish_reason,
+            Some(FinishReason::MaxTokens | FinishReason::ContextFull)
+        ) {
+            piece.push_str(&self.finish());
+        }
+        (piece, finish_reason)
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    id: RequestId,
+    prompt: Vec<LlamaToken>,
+    sampler: LlamaSampler,
+    progress: Progress,
+    output: Output,
+    /// A token the sampler accepted that was not reported yet because a step failed.
+    sampled: Option<(LlamaToken, f32)>,
+}
+
+/// Runs many [`Request`]s on one context, one sequence per request.
+///
+/// A context created with [`crate::context::params::LlamaContextParams::with_n_seq_max`] `n`
+/// runs up to `n` requests at the same time, further requests wait in a queue. The cache is
+/// assumed to be split evenly between the sequences, so each request can hold
+/// [`Self::n_ctx_seq`] tokens.
+#[derive(Debug)]
+pub struct Scheduler<'a, 'model> {
+    ctx: &'a mut LlamaContext<'model>,
+    slots: Vec<Option<Slot>>,
+    queue: VecDeque<Slot>,
+    batch: LlamaBatch,
+    next_id: u64,
+    /// Events of a failed step that are reported by the next one.
+    events: Vec<SchedulerEvent>,
+}
+
+impl<'a, 'model> Scheduler<'a, 'model> {
+    /// Create a scheduler that uses every sequence of `ctx`. The sequences should be empty.
+    #[must_use]
+    pub fn new(ctx: &'a mut LlamaContext<'model>) -> Self {
+        let n_seq_max = ctx.n_seq_max() as usize;
+        let batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
+        Self {
+            ctx,
+            slots: (0..n_seq_max).map(|_| None).collect(),
+            queue: VecDeque::new(),
+            batch,
+            next_id: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /// The number of tokens a request can hold: its prompt and the generated tokens.
+    #[must_use]
+    pub fn n_ctx_seq(&self) -> usize {
+        self.ctx.n_ctx() as usize / self.slots.len()
+    }
+
+    /// The number of requests that own a sequence.
+    #[must_use]
+    pub fn n_active(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// The number of requests waiting for a free sequence.
+    #[must_use]
+    pub fn n_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether there is no request left to run.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.n_active() == 0
+    }
+
+    /// The context the scheduler decodes into.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// Queue `request`. It starts in the next [`Self::step`] with a free sequence.
+    ///
+    /// # Errors
+    ///
+    /// If the prompt is empty or does not fit into a sequence.
+    pub fn add(&mut self, request: Request) -> Result<RequestId, SchedulerError> {
+        let Request {
+            prompt,
+            sampler,
+            max_tokens,
+            stop,
+        } = request;
+        if prompt.is_empty() {
+            return Err(SchedulerError::EmptyPrompt);
+        }
+        let n_ctx_seq = self.n_ctx_seq();
+        if prompt.len() >= n_ctx_seq {
+            return Err(SchedulerError::PromptTooLong {
+                n_tokens: prompt.len(),
+                n_ctx_seq,
+            });
+        }
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.queue.push_back(Slot {
+            id,
+            prompt,
+            sampler,
+            progress: Progress::default(),
+            output: Output {
+                stop,
+                max_tokens,
+                pending: Vec::new(),
+            },
+            sampled: None,
+        });
+        Ok(id)
+    }
+
+    /// Drop a queued or running request and free its sequence. No further events are reported
+    /// for it. Returns whether the request was found.
+    ///
+    /// # Errors
+    ///
+    /// If the sequence could not be cleared.
+    pub fn cancel(&mut self, id: RequestId) -> Result<bool, SchedulerError> {
+        self.events.retain(|event| match event {
+            SchedulerEvent::Token { request, .. } | SchedulerEvent::Finished { request, .. } => {
+                *request != id
+            }
+        });
+        if let Some(index) = self.queue.iter().position(|slot| slot.id == id) {
+            self.queue.remove(index);
+            return Ok(true);
+        }
+        let Some(seq) = self
+            .slots
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(|slot| slot.id == id))
+        else {
+            return Ok(false);
+        };
+        self.free(seq)?;
+        Ok(true)
+    }
+
+    /// Decode one batch and sample the next token of every request whose logits it produced.
+    ///
+    /// Queued requests are first moved into free sequences. The batch holds the pending token of
+    /// every generating request and as many prompt tokens of prefilling requests as still fit
+    /// into [`LlamaContext::n_batch`], in sequence order. Returns no events if there is nothing
+    /// to do.
+    ///
+    /// # Errors
+    ///
+    /// If decoding or sampling failed. The requests whose tokens were not decoded or sampled are
+    /// left as they were and their tokens are removed from the cache, so the step can be
+    /// retried, e.g. after cancelling a request. A token that was sampled but could not be
+    /// turned into text is not sampled again, the next step reports it. Events of the requests
+    /// sampled before the failure are returned by the next step.
+    ///
+    /// # Panics
+    ///
+    /// - a position does not fit into an i32
+    pub fn step(&mut self) -> Result<Vec<SchedulerEvent>, SchedulerError> {
+        self.admit();
+        let mut events = std::mem::take(&mut self.events);
+
+        // tokens the samplers accepted in a failed step
+        for seq in 0..self.slots.len() {
+            if self.slots[seq]
+                .as_ref()
+                .is_some_and(|slot| slot.sampled.is_some())
+            {
+                if let Err(err) = self.report(seq, &mut events) {
+                    self.events = events;
+                    return Err(err);
+                }
+            }
+        }
+
+        // (sequence, number of tokens, logits index)
+        let mut scheduled = Vec::new();
+        self.batch.clear();
+        let n_batch = self.ctx.n_batch() as usize;
+        for (seq, slot) in self.slots.iter().enumerate() {
+            let Some(slot) = slot else { continue };
+            let Some(token) = slot.progress.next else {
+                continue;
+            };
+            if scheduled.len() == n_batch {
+                break;
+            }
+            self.batch
+                .add(token, pos(slot.progress.n_past), &[seq_id(seq)], true)?;
+            scheduled.push((seq, 1, Some(self.batch.n_tokens() - 1)));
+        }
+        for (seq, slot) in self.slots.iter().enumerate() {
+            let Some(slot) = slot else { continue };
+            let room = n_batch - scheduled.iter().map(|&(_, n, _)| n).sum::<usize>();
+            let remaining = &slot.prompt[slot.progress.n_prefilled..];
+            if room == 0 || remaining.is_empty() {
+                continue;
+            }
+            let chunk = &remaining[..room.min(remaining.len())];
+            for (i, &token) in chunk.iter().enumerate() {
+                let last = chunk.len() == remaining.len() && i == chunk.len() - 1;
+                self.batch
+                    .add(token, pos(slot.progress.n_past + i), &[seq_id(seq)], last)?;
+            }
+            let logits = (chunk.len() == remaining.len()).then(|| self.batch.n_tokens() - 1);
+            scheduled.push((seq, chunk.len(), logits));
+        }
+        if scheduled.is_empty() {
+            return Ok(events);
+        }
+
+        if let Err(err) = self.ctx.decode(&mut self.batch) {
+            self.events = events;
+            for &(seq, _, _) in &scheduled {
+                let n_past = self.slots[seq]
+                    .as_ref()
+                    .map_or(0, |slot| slot.progress.n_past);
+                self.clear(seq, n_past)?;
+            }
+            return Err(err.into());
+        }
+
+        // every scheduled token is in the cache now, so advance all slots before sampling any
+        let mut before = Vec::with_capacity(scheduled.len());
+        for &(seq, n_tokens, _) in &scheduled {
+            let slot = self.slots[seq]
+                .as_mut()
+                .expect("scheduled slots are active");
+            before.push(slot.progress);
+            slot.progress.advance(n_tokens);
+        }
+
+        for (i, &(seq, _, logits)) in scheduled.iter().enumerate() {
+            let Some(index) = logits else { continue };
+            let slot = self.slots[seq].as_mut().expect("sampled slots are active");
+            let token = slot.sampler.sample(self.ctx, index);
+            slot.sampled = Some((token, logprob(self.ctx.get_logits_ith(index), token)));
+            if let Err(err) = self.report(seq, &mut events) {
+                self.events = events;
+                // the requests not sampled yet decode their tokens again, the failed one keeps
+                // its sampled token
+                for j in not_sampled(&scheduled, i) {
+                    let (seq, _, _) = scheduled[j];
+                    if let Some(slot) = self.slots[seq].as_mut() {
+                        slot.progress = before[j];
+                        self.clear(seq, before[j].n_past)?;
+                    }
+                }
+                return Err(err);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Move queued requests into free sequences.
+    fn admit(&mut self) {
+        for slot in self.slots.iter_mut().filter(|slot| slot.is_none()) {
+            let Some(queued) = self.queue.pop_front() else {
+                return;
+            };
+            *slot = Some(queued);
+        }
+    }
+
+    /// Report the sampled token of the request in sequence `seq` and free the sequence if the
+    /// request finished. The token stays sampled if it cannot be turned into text.
+    fn report(
+        &mut self,
+        seq: usize,
+        events: &mut Vec<SchedulerEvent>,
+    ) -> Result<(), SchedulerError> {
+        let n_ctx_seq = self.n_ctx_seq();
+        let model = self.ctx.model;
+        let slot = self.slots[seq].as_mut().expect("reported slots are active");
+        let (token, logprob) = slot.sampled.expect("reported slots have a sampled token");
+
+        let stop_token = slot.output.stop.stop_token(token);
+        let (piece, finish_reason) = if stop_token.is_some() || model.is_eog_token(token) {
+            let finish_reason = match stop_token {
+                Some(index) => FinishReason::Stop(slot.output.stop.conditions()[index].clone()),
+                None => FinishReason::EndOfGeneration,
+            };
+            (slot.output.finish(), Some(finish_reason))
+        } else {
+            let bytes = model.token_to_bytes(token, Special::Tokenize)?;
+            slot.output
+                .push(&mut slot.progress, token, &bytes, n_ctx_seq)
+        };
+        slot.sampled = None;
+        let id = slot.id;
+        events.push(SchedulerEvent::Token {
+            request: id,
+            token: GeneratedToken {
+                token,
+                piece,
+                logprob,
+            },
+        });
+        if let Some(finish_reason) = finish_reason {
+            events.push(SchedulerEvent::Finished {
+                request: id,
+                finish_reason,
+            });
+            self.free(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the request in sequence `seq` and clear the sequence.
+    fn free(&mut self, seq: usize) -> Result<(), SchedulerError> {
+        self.slots[seq] = None;
+        self.clear(seq, 0)
+    }
+
+    /// Remove the tokens of sequence `seq` from position `p0` on.
+    fn clear(&mut self, seq: usize, p0: usize) -> Result<(), SchedulerError> {
+        let seq = u32::try_from(seq).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let p0 = u32::try_from(p0).map_err(KvCacheConversionError::P0TooLarge)?;
+        self.ctx.clear_kv_cache_seq(Some(seq), Some(p0), None)?;
+        Ok(())
+    }
+}
+
+/// The entries of `scheduled` (sequence, number of tokens, logits index) to put back when
+/// reporting the one at `failed` fails: the later ones whose logits were not sampled yet.
+/// Prompt chunks without logits stay decoded.
+fn not_sampled(scheduled: &[(usize, usize, Option<i32>)], failed: usize) -> Vec<usize> {
+    (failed + 1..scheduled.len())
+        .filter(|&i| scheduled[i].2.is_some())
+        .collect()
+}
+
+fn pos(n_past: usize) -> i32 {
+    i32::try_from(n_past).expect("position fits into an i32")
+}
+
+fn seq_id(seq: usize) -> i32 {
+    i32::try_from(seq).expect("sequence id fits into an i32")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(stop: &[&str], max_tokens: Option<usize>) -> Output {
+        Output {
+            stop: StopMatcher::new(stop.iter().copied()),
+            max_tokens,
+            pending: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn multibyte_characters_are_released_once_complete() {
+        let mut output = output(&[], None);
+        let mut progress = Progress::default();
+        let token = LlamaToken(1);
+        let bytes = "é".as_bytes();
+        assert_eq!(
+            output.push(&mut progress, token, &bytes[..1], 100),
+            (String::new(), None)
+        );
+        assert_eq!(
+            output.push(&mut progress, token, &bytes[1..], 100),
+            ("é".to_string(), None)
+        );
+        assert_eq!(progress.n_generated, 2);
+        assert_eq!(progress.next, Some(token));
+    }
+
+    #[test]
+    fn finish_reasons_are_ordered() {
+        let token = LlamaToken(1);
+        let full = Progress {
+            n_past: 10,
+            ..Progress::default()
+        };
+
+        // a stop string wins over max tokens and a full sequence
+        let mut progress = full;
+        let (piece, finish_reason) = output(&["!"], Some(1)).push(&mut progress, token, b"hi!", 10);
+        assert_eq!(piece, "hi");
+        assert_eq!(
+            finish_reason,
+            Some(FinishReason::Stop(StopCondition::Text("!".to_string())))
+        );
+        assert_eq!(progress.next, None);
+
+        // max tokens wins over a full sequence and releases the held back text
+        let mut progress = full;
+        let (piece, finish_reason) =
+            output(&["!?"], Some(1)).push(&mut progress, token, b"hi!", 10);
+        assert_eq!(piece, "hi!");
+        assert_eq!(finish_reason, Some(FinishReason::MaxTokens));
+
+        let mut progress = full;
+        let (_, finish_reason) = output(&[], Some(2)).push(&mut progress, token, b"hi", 10);
+        assert_eq!(finish_reason, Some(FinishReason::ContextFull));
+
+        let mut progress = Progress::default();
+        let (_, finish_reason) = output(&[], Some(2)).push(&mut progress, token, b"hi", 10);
+        assert_eq!(finish_reason, None);
+    }
+
+    #[test]
+    fn finishing_flushes_incomplete_text() {
+        let mut output = output(&["</s>"], None);
+        let mut progress = Progress::default();
+        let (piece, _) = output.push(&mut progress, LlamaToken(1), b"a </", 100);
+        assert_eq!(piece, "a ");
+        output.pending.push(0xC3);
+        assert_eq!(output.finish(), "</\u{FFFD}");
+        assert_eq!(output.finish(), "");
+    }
+
+    #[test]
+    fn progress_advances_past_decoded_tokens() {
+        let prefilling = Progress {
+            n_prefilled: 4,
+            n_past: 4,
+            next: None,
+            n_generated: 0,
+        };
+        let mut progress = prefilling;
+        progress.advance(3);
+        assert_eq!((progress.n_prefilled, progress.n_past), (7, 7));
+
+        let generating = Progress {
+            n_prefilled: 7,
+            n_past: 9,
+            next: Some(LlamaToken(5)),
+            n_generated: 2,
+        };
+        let mut progress = generating;
+        progress.advance(1);
+        assert_eq!((progress.n_prefilled, progress.n_past), (7, 10));
+        assert_eq!(progress.next, None);
+        output(&[], None).push(&mut progress, LlamaToken(6), b"x", 100);
+        assert_eq!(progress.n_generated, 3);
+    }
+
+    #[test]
+    fn only_requests_not_sampled_yet_are_restored() {
+        // two generating requests, a prompt chunk and a finished prompt
+        let scheduled = [
+            (0, 1, Some(0)),
+            (2, 1, Some(1)),
+            (1, 5, None),
+            (3, 2, Some(8)),
+        ];
+        assert_eq!(not_sampled(&scheduled, 0), [1, 3]);
+        assert_eq!(not_sampled(&scheduled, 1), [3]);
+        assert!(not_sampled(&scheduled, 3).is_empty());
+    }
+}
@@ -0,0 +1,677 @@
+//! Speculative decoding: a cheap [`Drafter`] proposes several tokens, the target model checks
+//! all of them in a single batch and keeps the longest prefix it would have sampled itself.
+//!
+//! Every token is still sampled from the target model with the target sampler, so the output
+//! follows the same distribution as plain generation. The speedup depends on how often the
+//! drafts match, see [`SpeculativeStats`].
+//!
+//! Drafts come from a smaller model of the same family with [`SpeculativeDecoder::new`], or from
+//! earlier text of the sequence with [`SpeculativeDecoder::prompt_lookup`].
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::speculative::SpeculativeDecoder;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//! use llama_cpp_2::sampling::LlamaSampler;
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! let target = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let draft = LlamaModel::load_from_file(&backend, "path/to/draft", &Default::default())?;
+//! let mut target_ctx = target.new_context(&backend, LlamaContextParams::default())?;
+//! let mut draft_ctx = draft.new_context(&backend, LlamaContextParams::default())?;
+//! let prompt = target.str_to_token("The capital of France is", AddBos::Always)?;
+//! let mut sampler = LlamaSampler::greedy();
+//!
+//! let mut decoder =
+//!     SpeculativeDecoder::new(&mut target_ctx, &mut draft_ctx, &mut sampler, &prompt)?
+//!         .with_n_draft(8)
+//!         .with_max_tokens(128);
+//! for generated in &mut decoder {
+//!     print!("{}", generated?.piece);
+//! }
+//! println!("\nacceptance rate: {:.2}", decoder.stats().acceptance_rate());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::generation::{logprob, take_utf8, FinishReason, GeneratedToken};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{LlamaModel, Special};
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{DecodeError, TokenToStringError};
+
+/// Vocabularies may differ by this many tokens, e.g. for extra padding tokens.
+const MAX_VOCAB_SIZE_DIFFERENCE: i32 = 128;
+
+/// Tokens below this id are not compared, as models disagree on the text of control tokens.
+const VOCAB_CHECK_START_TOKEN: i32 = 5;
+
+/// Failed to decode speculatively.
+#[derive(Debug, thiserror::Error)]
+pub enum SpeculativeError {
+    /// There is nothing to sample from as no prompt was given.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The draft model tokenizes differently from the target model.
+    #[error("the draft model has a different vocabulary: {0}")]
+    VocabMismatch(String),
+    /// The prompt does not fit into the context.
+    #[error("{n_tokens} tokens do not fit into a context of {n_ctx}")]
+    ContextFull {
+        /// The number of tokens of the prompt.
+        n_tokens: usize,
+        /// The size of the context.
+        n_ctx: usize,
+    },
+    /// The rejected tokens could not be removed from the kv cache, which is the case for
+    /// recurrent models.
+    #[error("the kv cache cannot remove rejected tokens")]
+    RollbackFailed,
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// See [`KvCacheConversionError`].
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+    /// See [`TokenToStringError`].
+    #[error("{0}")]
+    TokenToStringError(#[from] TokenToStringError),
+}
+
+/// Proposes tokens for a [`SpeculativeDecoder`] to verify.
+pub trait Drafter {
+    /// Propose up to `n_draft` tokens to follow `tokens`, which holds the prompt and every token
+    /// accepted so far. Fewer tokens, or none, may be proposed if the drafter is not confident.
+    ///
+    /// # Errors
+    ///
+    /// If drafting failed. The error ends generation.
+    fn draft(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_draft: usize,
+    ) -> Result<Vec<LlamaToken>, SpeculativeError>;
+}
+
+/// Counts of how well the drafts matched the target model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeculativeStats {
+    /// The number of target batches decoded to verify drafts.
+    pub n_rounds: usize,
+    /// The number of tokens proposed by the drafter.
+    pub n_drafted: usize,
+    /// The number of proposed tokens the target model accepted.
+    pub n_accepted: usize,
+}
+
+impl SpeculativeStats {
+    /// The share of proposed tokens that was accepted, 0 if nothing was proposed.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // token counts are far below the precision of an f32
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.n_drafted == 0 {
+            return 0.0;
+        }
+        self.n_accepted as f32 / self.n_drafted as f32
+    }
+
+    /// The average number of tokens generated per target batch, including the token the target
+    /// model samples after the accepted drafts.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // token counts are far below the precision of an f32
+    pub fn tokens_per_round(&self) -> f32 {
+        if self.n_rounds == 0 {
+            return 0.0;
+        }
+        (self.n_accepted + self.n_rounds) as f32 / self.n_rounds as f32
+    }
+}
+
+/// Check that `draft` tokenizes text the same way as `target`, so its tokens can be verified by
+/// `target`.
+///
+/// # Errors
+///
+/// If the vocabularies differ in type, special tokens, size or the text of a token.
+pub fn check_vocab(target: &LlamaModel, draft: &LlamaModel) -> Result<(), SpeculativeError> {
+    let mismatch = |what: &str| Err(SpeculativeError::VocabMismatch(what.to_string()));
+    if target.vocab_type() != draft.vocab_type() {
+        return mismatch("the tokenizer types differ");
+    }
+    if target.add_bos_token() != draft.add_bos_token()
+        || target.add_eos_token() != draft.add_eos_token()
+        || target.token_bos() != draft.token_bos()
+        || target.token_eos() != draft.token_eos()
+    {
+        return mismatch("the special tokens differ");
+    }
+    let n_vocab = target.n_vocab().min(draft.n_vocab());
+    if (target.n_vocab() - draft.n_vocab()).abs() > MAX_VOCAB_SIZE_DIFFERENCE {
+        return mismatch(&format!(
+            "the vocabularies have {} and {} tokens",
+            target.n_vocab(),
+            draft.n_vocab()
+        ));
+    }
+    for token in (VOCAB_CHECK_START_TOKEN..n_vocab).map(LlamaToken) {
+        if target.token_text(token).ok() != draft.token_text(token).ok() {
+            return mismatch(&format!("token {token} differs"));
+        }
+    }
+    Ok(())
+}
+
+/// Drafts tokens by greedily generating them with a smaller model of the same family.
+///
+/// The draft context mirrors the sequence of the target context in its own sequence 0. Only the
+/// tokens it has not seen yet are decoded for each draft, and rejected tokens are removed from
+/// its kv cache before the next one.
+#[derive(Debug)]
+pub struct DraftModel<'a, 'draft> {
+    ctx: &'a mut LlamaContext<'draft>,
+    sampler: LlamaSampler,
+    /// The tokens in sequence 0 of the kv cache.
+    cached: Vec<LlamaToken>,
+}
+
+impl<'a, 'draft> DraftModel<'a, 'draft> {
+    /// Draft with `ctx`, whose sequence 0 should be empty, for a target `model`.
+    ///
+    /// # Errors
+    ///
+    /// If the draft model is not compatible with `model`, see [`check_vocab`].
+    pub fn new(
+        ctx: &'a mut LlamaContext<'draft>,
+        model: &LlamaModel,
+    ) -> Result<Self, SpeculativeError> {
+        check_vocab(model, ctx.model)?;
+        Ok(Self {
+            ctx,
+            sampler: LlamaSampler::greedy(),
+            cached: Vec::new(),
+        })
+    }
+
+    /// Sample drafts with `sampler` instead of [`LlamaSampler::greedy`].
+    #[must_use]
+    pub fn with_sampler(mut self, sampler: LlamaSampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// The draft context.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'draft> {
+        self.ctx
+    }
+}
+
+impl Drafter for DraftModel<'_, '_> {
+    fn draft(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_draft: usize,
+    ) -> Result<Vec<LlamaToken>, SpeculativeError> {
+        // every draft token but the last is decoded
+        let room = (self.ctx.n_ctx() as usize + 1).saturating_sub(tokens.len());
+        let n_draft = n_draft.min(room);
+        if tokens.is_empty() || n_draft == 0 {
+            return Ok(Vec::new());
+        }
+
+        // keep what the cache shares with `tokens`, but decode at least one token for logits
+        let n_keep = self
+            .cached
+            .iter()
+            .zip(tokens)
+            .take_while(|(cached, token)| cached == token)
+            .count()
+            .min(tokens.len() - 1);
+        if n_keep < self.cached.len() {
+            if !self.ctx.rollback_kv_cache_seq(0, n_keep)? {
+                return Err(SpeculativeError::RollbackFailed);
+            }
+            self.cached.truncate(n_keep);
+        }
+        let start_pos = i32::try_from(n_keep).expect("position fits into an i32");
+        let mut index = self
+            .ctx
+            .prefill(&tokens[n_keep..], 0, start_pos)?
+            .logits_index;
+        self.cached.extend_from_slice(&tokens[n_keep..]);
+
+        let mut draft = Vec::with_capacity(n_draft);
+        let mut batch = LlamaBatch::new(1, 1);
+        loop {
+            let token = self.sampler.sample(self.ctx, index);
+            draft.push(token);
+            if draft.len() == n_draft || self.ctx.model.is_eog_token(token) {
+                return Ok(draft);
+            }
+            let pos = i32::try_from(self.cached.len()).expect("position fits into an i32");
+            batch.clear();
+            batch.add(token, pos, &[0], true)?;
+            self.ctx.decode(&mut batch)?;
+            self.cached.push(token);
+            index = 0;
+        }
+    }
+}
+
+/// Drafts tokens without a second model by looking up the last n-gram of the sequence in the
+/// prompt and the generated text and proposing the tokens that followed its latest earlier
+/// occurrence. This works well when the output copies from the input, as in summarization or
+/// code editing.
+///
+/// Longer n-grams are tried first. Nothing is proposed if no n-gram occurs earlier.
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::generation::speculative::{Drafter, PromptLookup};
+/// use llama_cpp_2::token::LlamaToken;
+///
+/// let tokens = [1, 2, 3, 4, 5, 9, 2, 3].map(LlamaToken);
+/// let draft = PromptLookup::new(1, 2).draft(&tokens, 2)?;
+/// // `2, 3` occurred before, followed by `4, 5`
+/// assert_eq!(draft, [4, 5].map(LlamaToken));
+/// # Ok::<(), llama_cpp_2::generation::speculative::SpeculativeError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptLookup {
+    min_ngram: usize,
+    max_ngram: usize,
+}
+
+impl PromptLookup {
+    /// Look up n-grams of `min_ngram` to `max_ngram` tokens.
+    ///
+    /// # Panics
+    ///
+    /// - `min_ngram` is 0 or larger than `max_ngram`
+    #[must_use]
+    pub fn new(min_ngram: usize, max_ngram: usize) -> Self {
+        assert!(min_ngram > 0, "an n-gram has at least one token");
+        assert!(
+            min_ngram <= max_ngram,
+            "the minimum n-gram size must not exceed the maximum"
+        );
+        Self {
+            min_ngram,
+            max_ngram,
+        }
+    }
+
+    /// The smallest n-gram that is looked up.
+    #[must_use]
+    pub fn min_ngram(&self) -> usize {
+        self.min_ngram
+    }
+
+    /// The largest n-gram that is looked up.
+    #[must_use]
+    pub fn max_ngram(&self) -> usize {
+        self.max_ngram
+    }
+}
+
+/// Looks up n-grams of 1 to 3 tokens.
+impl Default for PromptLookup {
+    fn default() -> Self {
+        Self::new(1, 3)
+    }
+}
+
+impl Drafter for PromptLookup {
+    fn draft(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_draft: usize,
+    ) -> Result<Vec<LlamaToken>, SpeculativeError> {
+        for n in (self.min_ngram..=self.max_ngram.min(tokens.len().saturating_sub(1))).rev() {
+            let (earlier, ngram) = tokens.split_at(tokens.len() - n);
+            let found = earlier
+                .windows(n)
+                .rposition(|window| window == ngram)
+                .map(|start| start + n);
+            if let Some(start) = found {
+                let end = (start + n_draft).min(tokens.len());
+                return Ok(tokens[start..end].to_vec());
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Decode `tokens[n_past]` followed by `draft` into sequence `seq_id`, which holds
+    /// `tokens[..n_past]`, where `n_past` is one less than the length of `tokens`. Sample from
+    /// every position with `sampler` until a sampled token differs from the draft or ends
+    /// generation, and remove the rejected draft tokens with [`Self::rollback_kv_cache_seq`].
+    ///
+    /// All candidates are checked with a single decode, so drafts can come from anywhere, see
+    /// [`Drafter`].
+    ///
+    /// Returns the sampled tokens with their log probabilities: the accepted draft tokens
+    /// followed by one token of the target model. The kv cache then holds every token but the
+    /// last one.
+    ///
+    /// # Errors
+    ///
+    /// - [`SpeculativeError::EmptyPrompt`] if `tokens` is empty.
+    /// - [`SpeculativeError::RollbackFailed`] if the rejected tokens cannot be removed.
+    /// - decoding failed
+    ///
+    /// # Panics
+    ///
+    /// - a position does not fit into an i32
+    pub fn verify_draft(
+        &mut self,
+        sampler: &mut LlamaSampler,
+        seq_id: i32,
+        tokens: &[LlamaToken],
+        draft: &[LlamaToken],
+    ) -> Result<Vec<(LlamaToken, f32)>, SpeculativeError> {
+        let (&last, past) = tokens.split_last().ok_or(SpeculativeError::EmptyPrompt)?;
+        let n_past = past.len();
+        let mut batch = LlamaBatch::new(draft.len() + 1, 1);
+        for (i, &token) in std::iter::once(&last).chain(draft).enumerate() {
+            let pos = i32::try_from(n_past + i).expect("position fits into an i32");
+            batch.add(token, pos, &[seq_id], true)?;
+        }
+        self.decode(&mut batch)?;
+
+        let mut accepted = Vec::with_capacity(draft.len() + 1);
+        for index in 0..batch.n_tokens() {
+            let token = sampler.sample(self, index);
+            accepted.push((token, logprob(self.get_logits_ith(index), token)));
+            let position = accepted.len() - 1;
+            if draft.get(position) != Some(&token) || self.model.is_eog_token(token) {
+                break;
+            }
+        }
+        if self.rollback_kv_cache_seq(seq_id, n_past + accepted.len())? {
+            Ok(accepted)
+        } else {
+            Err(SpeculativeError::RollbackFailed)
+        }
+    }
+}
+
+/// Generates tokens with the target context, verifying the proposals of a [`Drafter`] in
+/// batches.
+///
+/// Like a [`crate::generation::Generator`], the decoder decodes the prompt into sequence 0 on
+/// the first call to [`Iterator::next`] and yields tokens until an end of generation token,
+/// which is yielded last, or the token limit. Accepted tokens are yielded one at a time. Between
+/// rounds the kv cache holds every token of [`Self::tokens`] but the last one, which is decoded
+/// together with the next draft. After an error the iterator ends.
+#[derive(Debug)]
+pub struct SpeculativeDecoder<'a, 'model, D> {
+    ctx: &'a mut LlamaContext<'model>,
+    sampler: &'a mut LlamaSampler,
+    drafter: D,
+    prompt: Vec<LlamaToken>,
+    n_draft: usize,
+    max_tokens: Option<usize>,
+    tokens: Vec<LlamaToken>,
+    queue: VecDeque<GeneratedToken>,
+    n_generated: usize,
+    pending: Vec<u8>,
+    stats: SpeculativeStats,
+    finish_reason: Option<FinishReason>,
+    failed: bool,
+}
+
+impl<'a, 'model, 'draft> SpeculativeDecoder<'a, 'model, DraftModel<'a, 'draft>> {
+    /// Create a decoder that completes `prompt` with `ctx`, drafting with `draft`. Both
+    /// contexts should have an empty sequence 0.
+    ///
+    /// # Errors
+    ///
+    /// If the draft model is not compatible with the target model, see [`check_vocab`].
+    pub fn new(
+        ctx: &'a mut LlamaContext<'model>,
+        draft: &'a mut LlamaContext<'draft>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+    ) -> Result<Self, SpeculativeError> {
+        let drafter = DraftModel::new(draft, ctx.model)?;
+        Ok(Self::with_drafter(ctx, sampler, prompt, drafter))
+    }
+}
+
+impl<'a, 'model> SpeculativeDecoder<'a, 'model, PromptLookup> {
+    /// Create a decoder that completes `prompt` with `ctx`, drafting by [`PromptLookup`] with
+    /// its default n-gram sizes. No second model is needed.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::generation::speculative::SpeculativeDecoder;
+    /// use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let article = String::new();
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let prompt = format!("{article}\n\nSummarize the article above.\n");
+    /// let prompt = model.str_to_token(&prompt, AddBos::Always)?;
+    /// let mut sampler = LlamaSampler::greedy();
+    ///
+    /// let decoder = SpeculativeDecoder::prompt_lookup(&mut ctx, &mut sampler, &prompt)
+    ///     .with_n_draft(10)
+    ///     .with_max_tokens(256);
+    /// for generated in decoder {
+    ///     print!("{}", generated?.piece);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn prompt_lookup(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+    ) -> Self {
+        Self::with_drafter(ctx, sampler, prompt, PromptLookup::default())
+    }
+}
+
+impl<'a, 'model, D: Drafter> SpeculativeDecoder<'a, 'model, D> {
+    /// Create a decoder that completes `prompt` with `ctx`, verifying the proposals of
+    /// `drafter`.
+    #[must_use]
+    pub fn with_drafter(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+        drafter: D,
+    ) -> Self {
+        Self {
+            ctx,
+            sampler,
+            drafter,
+            prompt: prompt.to_vec(),
+            n_draft: 16,
+            max_tokens: None,
+            tokens: Vec::new(),
+            queue: VecDeque::new(),
+            n_generated: 0,
+            pending: Vec::new(),
+            stats: SpeculativeStats::default(),
+            finish_reason: None,
+            failed: false,
+        }
+    }
+
+    /// The maximum number of tokens to draft per round. Defaults to 16.
+    #[must_use]
+    pub fn with_n_draft(mut self, n_draft: usize) -> Self {
+        self.n_draft = n_draft;
+        self
+    }
+
+    /// Stop after `max_tokens` tokens. Unlimited by default.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// How well the drafts matched so far.
+    #[must_use]
+    pub fn stats(&self) -> &SpeculativeStats {
+        &self.stats
+    }
+
+    /// Why generation stopped, or `None` while it is still running or after an error.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    /// The prompt and every token generated so far, without a final end of generation token.
+    #[must_use]
+    pub fn tokens(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+
+    /// The target context.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// The drafter.
+    #[must_use]
+    pub fn drafter(&self) -> &D {
+        &self.drafter
+    }
+
+    /// Decode the prompt but its last token, which is decoded with the first draft.
+    fn prefill(&mut self) -> Result<(), SpeculativeError> {
+        let prompt = std::mem::take(&mut self.prompt);
+        let (_, past) = prompt.split_last().ok_or(SpeculativeError::EmptyPrompt)?;
+        let n_ctx = self.ctx.n_ctx() as usize;
+        if prompt.len() > n_ctx {
+            return Err(SpeculativeError::ContextFull {
+                n_tokens: prompt.len(),
+                n_ctx,
+            });
+        }
+        if !past.is_empty() {
+            self.ctx.prefill(past, 0, 0)?;
+        }
+        self.tokens = prompt;
+        Ok(())
+    }
+
+    /// Draft, verify and queue the accepted tokens.
+    fn step(&mut self) -> Result<(), SpeculativeError> {
+        if !self.prompt.is_empty() || self.tokens.is_empty() {
+            self.prefill()?;
+        }
+        let remaining = self
+            .max_tokens
+            .map_or(usize::MAX, |max| max.saturating_sub(self.n_generated));
+        let room = (self.ctx.n_ctx() as usize + 1).saturating_sub(self.tokens.len());
+        if remaining == 0 || room == 0 {
+            self.finish(if remaining == 0 {
+                FinishReason::MaxTokens
+            } else {
+                FinishReason::ContextFull
+            });
+            return Ok(());
+        }
+
+        // the target samples one more token than it accepts drafts
+        let n_draft = self.n_draft.min(remaining - 1).min(room - 1);
+        let mut draft = if n_draft > 0 {
+            self.drafter.draft(&self.tokens, n_draft)?
+        } else {
+            Vec::new()
+        };
+        draft.truncate(n_draft);
+        let sampled = self
+            .ctx
+            .verify_draft(self.sampler, 0, &self.tokens, &draft)?;
+
+        self.stats.n_rounds += 1;
+        self.stats.n_drafted += draft.len();
+        self.stats.n_accepted += sampled
+            .iter()
+            .zip(&draft)
+            .filter(|((token, _), drafted)| token == *drafted)
+            .count();
+        for (token, logprob) in sampled {
+            self.push(token, logprob)?;
+        }
+        Ok(())
+    }
+
+    /// Queue a sampled token and check whether generation is done.
+    fn push(&mut self, token: LlamaToken, logprob: f32) -> Result<(), SpeculativeError> {
+        let model = self.ctx.model;
+        if model.is_eog_token(token) {
+            let piece = take_utf8(&mut self.pending, true);
+            self.queue.push_back(GeneratedToken {
+                token,
+                piece,
+                logprob,
+            });
+            self.finish(FinishReason::EndOfGeneration);
+            return Ok(());
+        }
+        self.tokens.push(token);
+        self.n_generated += 1;
+        self.pending
+            .extend(model.token_to_bytes(token, Special::Tokenize)?);
+        let done = self.max_tokens.is_some_and(|max| self.n_generated >= max);
+        let piece = take_utf8(&mut self.pending, done);
+        self.queue.push_back(GeneratedToken {
+            token,
+            piece,
+            logprob,
+        });
+        if done {
+            self.finish(FinishReason::MaxTokens);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, finish_reason: FinishReason) {
+        self.finish_reason.get_or_insert(finish_reason);
+    }
+}
+
+impl<D: Drafter> Iterator for SpeculativeDecoder<'_, '_, D> {
+    type Item = Result<GeneratedToken, SpeculativeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(generated) = self.queue.pop_front() {
+                return Some(Ok(generated));
+            }
+            if self.failed || self.finish_reason.is_some() {
+                return None;
+            }
+            if let Err(err) = self.step() {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
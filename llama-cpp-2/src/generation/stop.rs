@@ -0,0 +1,290 @@
+//! Detect stop conditions in generated text without leaking partial matches.
+
+use crate::token::LlamaToken;
+
+/// A condition that ends generation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StopCondition {
+    /// Stop before this token. The token and its text are not part of the output.
+    Token(LlamaToken),
+    /// Stop when the generated text contains this string, no matter how it is split into
+    /// tokens. The string and anything after it are not part of the output.
+    Text(String),
+}
+
+impl From<LlamaToken> for StopCondition {
+    fn from(token: LlamaToken) -> Self {
+        Self::Token(token)
+    }
+}
+
+impl From<String> for StopCondition {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for StopCondition {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+/// The output of [`StopMatcher::push`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StopMatch {
+    /// Text that is known not to be part of a stop condition and can be released.
+    pub text: String,
+    /// The index of the condition that fired, if any. Nothing should be pushed after this.
+    pub stopped: Option<usize>,
+}
+
+/// Matches [`StopCondition`]s against generated tokens.
+///
+/// Text is only held back while it is a possible prefix of a stop string, everything else is
+/// released immediately.
+///
+/// # Examples
+///
+/// ```rust
+/// use llama_cpp_2::generation::stop::{StopCondition, StopMatcher};
+/// use llama_cpp_2::token::LlamaToken;
+///
+/// let mut matcher = StopMatcher::new(["\nUser:"]);
+/// let token = LlamaToken::new(0);
+///
+/// assert_eq!(matcher.push(token, "Sure!").text, "Sure!");
+/// // could be the start of "\nUser:"
+/// assert_eq!(matcher.push(token, "\nUs").text, "");
+/// let stop = matcher.push(token, "er: hi");
+/// assert_eq!(stop.text, "");
+/// assert_eq!(stop.stopped, Some(0));
+/// assert_eq!(matcher.conditions()[0], StopCondition::Text("\nUser:".to_string()));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StopMatcher {
+    conditions: Vec<StopCondition>,
+    held_back: String,
+}
+
+impl StopMatcher {
+    /// Create a matcher for `conditions`. Empty stop strings never match.
+    #[must_use]
+    pub fn new(conditions: impl IntoIterator<Item = impl Into<StopCondition>>) -> Self {
+        Self {
+            conditions: conditions.into_iter().map(Into::into).collect(),
+            held_back: String::new(),
+        }
+    }
+
+    /// The conditions in the order they were given.
+    #[must_use]
+    pub fn conditions(&self) -> &[StopCondition] {
+        &self.conditions
+    }
+
+    /// Add another condition.
+    pub fn add(&mut self, condition: impl Into<StopCondition>) {
+        self.conditions.push(condition.into());
+    }
+
+    /// The text currently held back because it may be the start of a stop string.
+    #[must_use]
+    pub fn held_back(&self) -> &str {
+        &self.held_back
+    }
+
+    /// The index of the token condition `token` matches, if any.
+    #[must_use]
+    pub fn stop_token(&self, token: LlamaToken) -> Option<usize> {
+        self.conditions
+            .iter()
+            .position(|condition| *condition == StopCondition::Token(token))
+    }
+
+    /// Feed the next token and its text.
+    ///
+    /// When several stop strings match, the one that starts first wins. Text before the match is
+    /// released and the matcher is cleared.
+    pub fn push(&mut self, token: LlamaToken, piece: &str) -> StopMatch {
+        if let Some(index) = self.stop_token(token) {
+            return StopMatch {
+                text: std::mem::take(&mut self.held_back),
+                stopped: Some(index),
+            };
+        }
+
+        self.held_back.push_str(piece);
+        let found = self
+            .conditions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, condition)| match condition {
+                StopCondition::Text(stop) if !stop.is_empty() => self
+                    .held_back
+                    .find(stop.as_str())
+                    .map(|start| (start, index)),
+                _ => None,
+            })
+            .min();
+        if let Some((start, index)) = found {
+            self.held_back.truncate(start);
+            return StopMatch {
+                text: std::mem::take(&mut self.held_back),
+                stopped: Some(index),
+            };
+        }
+
+        let keep = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                StopCondition::Text(stop) => Some(partial_match_len(&self.held_back, stop)),
+                StopCondition::Token(_) => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let text = self.held_back[..self.held_back.len() - keep].to_string();
+        self.held_back.drain(..text.len());
+        StopMatch {
+            text,
+            stopped: None,
+        }
+    }
+
+    /// Release the held back text, e.g. because generation ended for another reason.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held_back)
+    }
+}
+
+/// The length of the longest suffix of `text` that is a proper prefix of `pattern`, i.e. the
+/// number of bytes to hold back because `pattern` may start there once more text arrives.
+pub(crate) fn partial_match_len(text: &str, pattern: &str) -> usize {
+    (1..pattern.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| {
+            text.is_char_boundary(text.len() - len)
+                && pattern.is_char_boundary(len)
+                && text.ends_with(&pattern[..len])
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: LlamaToken = LlamaToken(0);
+
+    /// Feed `pieces` and collect the released text and the index of the condition that fired.
+    fn feed<'a>(
+        matcher: &mut StopMatcher,
+        pieces: impl IntoIterator<Item = &'a str>,
+    ) -> (String, Option<usize>) {
+        let mut text = String::new();
+        for piece in pieces {
+            let stop = matcher.push(TOKEN, piece);
+            text.push_str(&stop.text);
+            if stop.stopped.is_some() {
+                return (text, stop.stopped);
+            }
+        }
+        (text, None)
+    }
+
+    #[test]
+    fn overlapping_stop_strings() {
+        // the stop string that starts first wins, not the one listed first
+        let mut matcher = StopMatcher::new(["bc", "abcd"]);
+        assert_eq!(
+            feed(&mut matcher, ["xab", "cd"]),
+            ("x".to_string(), Some(1))
+        );
+
+        // a complete match is not delayed by a longer stop string that may still match
+        let mut matcher = StopMatcher::new(["abcd", "bc"]);
+        assert_eq!(
+            feed(&mut matcher, ["xa", "bc"]),
+            ("xa".to_string(), Some(1))
+        );
+
+        // text is held back as long as it may start any of the stop strings
+        let mut matcher = StopMatcher::new(["ENDING", "DONE"]);
+        assert_eq!(matcher.push(TOKEN, "the END").text, "the ");
+        assert_eq!(matcher.held_back(), "END");
+        assert_eq!(matcher.push(TOKEN, "LESS DO").text, "ENDLESS ");
+        assert_eq!(matcher.held_back(), "DO");
+    }
+
+    #[test]
+    fn stop_split_across_pieces() {
+        let mut matcher = StopMatcher::new(["\n\nUser:"]);
+        let (text, stopped) = feed(&mut matcher, ["Hi", "\n", "\n", "Us", "e", "r", ": more"]);
+        assert_eq!(text, "Hi");
+        assert_eq!(stopped, Some(0));
+    }
+
+    #[test]
+    fn false_start_is_released() {
+        let mut matcher = StopMatcher::new(["\nUser:"]);
+        assert_eq!(matcher.push(TOKEN, "a\nUs").text, "a");
+        assert_eq!(matcher.held_back(), "\nUs");
+        assert_eq!(matcher.push(TOKEN, "ually").text, "\nUsually");
+        assert_eq!(matcher.held_back(), "");
+    }
+
+    #[test]
+    fn multibyte_characters_at_the_cut() {
+        let mut matcher = StopMatcher::new(["é!", "日本"]);
+        assert_eq!(matcher.push(TOKEN, "café").text, "caf");
+        assert_eq!(matcher.held_back(), "é");
+        let stop = matcher.push(TOKEN, "!");
+        assert_eq!(stop.text, "");
+        assert_eq!(stop.stopped, Some(0));
+
+        let mut matcher = StopMatcher::new(["日本"]);
+        assert_eq!(
+            feed(&mut matcher, ["東京日", "本"]),
+            ("東京".to_string(), Some(0))
+        );
+    }
+
+    #[test]
+    fn stop_tokens() {
+        let mut matcher = StopMatcher::new([LlamaToken(7)]);
+        assert_eq!(matcher.push(TOKEN, "text").text, "text");
+        let stop = matcher.push(LlamaToken(7), "ignored");
+        assert_eq!(stop.text, "");
+        assert_eq!(stop.stopped, Some(0));
+        assert_eq!(matcher.stop_token(LlamaToken(7)), Some(0));
+    }
+
+    #[test]
+    fn flush_without_a_match() {
+        let mut matcher = StopMatcher::new(["</s>"]);
+        assert_eq!(
+            feed(&mut matcher, ["done <", "/"]),
+            ("done ".to_string(), None)
+        );
+        assert_eq!(matcher.flush(), "</");
+        assert_eq!(matcher.held_back(), "");
+        assert_eq!(matcher.flush(), "");
+    }
+
+    #[test]
+    fn empty_stop_strings_never_match() {
+        let mut matcher = StopMatcher::new([""]);
+        assert_eq!(feed(&mut matcher, ["a", "b"]), ("ab".to_string(), None));
+    }
+
+    #[test]
+    fn partial_matches() {
+        assert_eq!(partial_match_len("text <tool", "<tool_call>"), 5);
+        assert_eq!(partial_match_len("text", "<tool_call>"), 0);
+        // a complete match is not a partial one
+        assert_eq!(partial_match_len("ab", "ab"), 0);
+        assert_eq!(partial_match_len("xé", "éa"), 2);
+        assert_eq!(partial_match_len("é", "<"), 0);
+    }
+}
@@ -0,0 +1,257 @@
+//! Stream generated tokens to async code.
+//!
+//! Decoding blocks for as long as the model needs to process a batch, which is far too long for
+//! an async runtime. A [`TokenStream`] runs a [`Generator`] on its own thread and hands the tokens
+//! over through a bounded channel: when the consumer falls behind, the worker waits instead of
+//! decoding ahead, and when the stream is dropped, the worker aborts the batch it is decoding.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::stream::TokenStream;
+//! use llama_cpp_2::llama_backend::LlamaBackend;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let backend = Arc::new(LlamaBackend::init()?);
+//! let model = Arc::new(LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?);
+//! let prompt = model.str_to_token("The capital of France is", AddBos::Always)?;
+//!
+//! let mut stream = TokenStream::builder(backend, model, LlamaContextParams::default(), prompt)
+//!     .with_sampler(|| LlamaSampler::temp(0.8))
+//!     .with_max_tokens(32)
+//!     .spawn()?;
+//! while let Some(generated) = stream.next_token().await {
+//!     print!("{}", generated?.piece);
+//! }
+//! println!("\n{:?}", stream.finish_reason());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Debug, Formatter};
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::context::params::LlamaContextParams;
+use crate::generation::stop::StopCondition;
+use crate::generation::{FinishReason, GenerateError, GeneratedToken, Generator};
+use crate::llama_backend::LlamaBackend;
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::LlamaContextLoadError;
+
+/// Failed to stream tokens.
+#[derive(Debug, thiserror::Error)]
+pub enum TokenStreamError {
+    /// The worker could not create its context.
+    #[error("{0}")]
+    LlamaContextLoadError(#[from] LlamaContextLoadError),
+    /// See [`GenerateError`].
+    #[error("{0}")]
+    GenerateError(#[from] GenerateError),
+}
+
+/// What the worker sends to the stream.
+#[derive(Debug)]
+enum Message {
+    Token(GeneratedToken),
+    Finished(FinishReason),
+    Error(TokenStreamError),
+}
+
+/// Configures a [`TokenStream`], see [`TokenStream::builder`].
+pub struct TokenStreamBuilder {
+    backend: Arc<LlamaBackend>,
+    model: Arc<LlamaModel>,
+    ctx_params: LlamaContextParams,
+    prompt: Vec<LlamaToken>,
+    sampler: Box<dyn FnOnce() -> LlamaSampler + Send>,
+    max_tokens: Option<usize>,
+    stop: Vec<StopCondition>,
+    buffer: usize,
+}
+
+impl Debug for TokenStreamBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenStreamBuilder")
+            .field("ctx_params", &self.ctx_params)
+            .field("prompt", &self.prompt)
+            .field("max_tokens", &self.max_tokens)
+            .field("stop", &self.stop)
+            .field("buffer", &self.buffer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenStreamBuilder {
+    /// Create the sampler on the worker thread with `sampler`, as samplers cannot be sent between
+    /// threads. Defaults to [`LlamaSampler::greedy`].
+    #[must_use]
+    pub fn with_sampler(mut self, sampler: impl FnOnce() -> LlamaSampler + Send + 'static) -> Self {
+        self.sampler = Box::new(sampler);
+        self
+    }
+
+    /// See [`Generator::with_max_tokens`].
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// See [`Generator::with_stop_conditions`].
+    #[must_use]
+    pub fn with_stop_conditions(
+        mut self,
+        conditions: impl IntoIterator<Item = impl Into<StopCondition>>,
+    ) -> Self {
+        self.stop.extend(conditions.into_iter().map(Into::into));
+        self
+    }
+
+    /// The number of tokens the worker may generate ahead of the consumer. Defaults to 16.
+    ///
+    /// # Panics
+    ///
+    /// - `buffer` is 0
+    #[must_use]
+    pub fn with_buffer(mut self, buffer: usize) -> Self {
+        assert!(buffer > 0, "the buffer must hold at least one token");
+        self.buffer = buffer;
+        self
+    }
+
+    /// Start the worker thread. It creates a context from the model and decodes the prompt
+    /// right away.
+    ///
+    /// # Errors
+    ///
+    /// If the thread could not be spawned.
+    pub fn spawn(self) -> std::io::Result<TokenStream> {
+        let (sender, receiver) = mpsc::channel(self.buffer);
+        let abort = Arc::new(AtomicBool::new(false));
+        let worker_abort = Arc::clone(&abort);
+        std::thread::Builder::new()
+            .name("llama-generate".to_string())
+            .spawn(move || self.run(&sender, worker_abort))?;
+        Ok(TokenStream {
+            receiver,
+            abort,
+            finish_reason: None,
+        })
+    }
+
+    fn run(self, sender: &mpsc::Sender<Message>, abort: Arc<AtomicBool>) {
+        let mut ctx = match self.model.new_context(&self.backend, self.ctx_params) {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                // nobody is left to tell if the stream is gone
+                let _ = sender.blocking_send(Message::Error(err.into()));
+                return;
+            }
+        };
+        ctx.set_abort_flag(abort);
+        let mut sampler = (self.sampler)();
+        let mut generator =
+            Generator::new(&mut ctx, &mut sampler, &self.prompt).with_stop_conditions(self.stop);
+        if let Some(max_tokens) = self.max_tokens {
+            generator = generator.with_max_tokens(max_tokens);
+        }
+
+        while !sender.is_closed() {
+            let message = match generator.next() {
+                Some(Ok(generated)) => Message::Token(generated),
+                Some(Err(err)) => Message::Error(err.into()),
+                None => match generator.finish_reason() {
+                    Some(finish_reason) => Message::Finished(finish_reason.clone()),
+                    None => return,
+                },
+            };
+            let done = !matches!(message, Message::Token(_));
+            if sender.blocking_send(message).is_err() || done {
+                return;
+            }
+        }
+    }
+}
+
+/// A [`Stream`] of tokens generated on a worker thread.
+///
+/// The stream ends after the last token, or after the first error. Dropping it cancels
+/// generation: the worker aborts the batch it is decoding through
+/// [`crate::context::LlamaContext::set_abort_flag`] and exits, freeing its context.
+#[derive(Debug)]
+pub struct TokenStream {
+    receiver: mpsc::Receiver<Message>,
+    abort: Arc<AtomicBool>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl TokenStream {
+    /// Configure a stream that completes `prompt` in a new context of `model`. The context is
+    /// created on the worker thread, which keeps `backend` and `model` alive until it exits.
+    #[must_use]
+    pub fn builder(
+        backend: Arc<LlamaBackend>,
+        model: Arc<LlamaModel>,
+        ctx_params: LlamaContextParams,
+        prompt: Vec<LlamaToken>,
+    ) -> TokenStreamBuilder {
+        TokenStreamBuilder {
+            backend,
+            model,
+            ctx_params,
+            prompt,
+            sampler: Box::new(LlamaSampler::greedy),
+            max_tokens: None,
+            stop: Vec::new(),
+            buffer: 16,
+        }
+    }
+
+    /// Wait for the next token, for callers that do not use a stream combinator library.
+    pub async fn next_token(&mut self) -> Option<Result<GeneratedToken, TokenStreamError>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// Why generation stopped, once the stream has ended. `None` while tokens are still coming,
+    /// after an error, or if the worker panicked.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+}
+
+impl Stream for TokenStream {
+    type Item = Result<GeneratedToken, TokenStreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(Message::Token(generated)) => return Poll::Ready(Some(Ok(generated))),
+                Some(Message::Error(err)) => return Poll::Ready(Some(Err(err))),
+                Some(Message::Finished(finish_reason)) => {
+                    self.finish_reason = Some(finish_reason);
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Drop for TokenStream {
+    fn drop(&mut self) {
+        self.abort.store(true, Ordering::Relaxed);
+    }
+}
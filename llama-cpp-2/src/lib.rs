@@ -13,6 +13,8 @@
 //!
 //! - `cuda` enables CUDA gpu support.
 //! - `sampler` adds the [`context::sample::sampler`] struct for a more rusty way of sampling.
+//! - `jinja` adds [`chat::jinja`] for rendering chat templates llama.cpp does not support.
+//! - `async` adds [`generation::stream::TokenStream`] for consuming generated tokens from async code.
 use std::ffi::NulError;
 use std::fmt::Debug;
 use std::num::NonZeroI32;
@@ -22,17 +24,22 @@ use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+pub mod chat;
 pub mod context;
+pub mod embedding;
+pub mod generation;
 pub mod llama_backend;
 pub mod llama_batch;
 mod log;
 pub mod model;
 #[cfg(feature = "mtmd")]
 pub mod mtmd;
+pub mod rerank;
 pub mod sampling;
 pub mod timing;
 pub mod token;
 pub mod token_type;
+pub mod tool_calls;
 
 /// A failable result from a llama.cpp function.
 pub type Result<T> = std::result::Result<T, LLamaCppError>;
@@ -117,6 +124,10 @@ pub enum DecodeError {
     /// The number of tokens in the batch was 0.
     #[error("Decode Error -1: n_tokens == 0")]
     NTokensZero,
+    /// The abort callback set with [`context::LlamaContext::set_abort_callback`] stopped decoding.
+    /// The ubatches that were processed before remain in the kv cache.
+    #[error("Decode Error 2: aborted")]
+    Aborted,
     /// An unknown error occurred.
     #[error("Decode Error {0}: unknown")]
     Unknown(c_int),
@@ -131,6 +142,9 @@ pub enum EncodeError {
     /// The number of tokens in the batch was 0.
     #[error("Encode Error -1: n_tokens == 0")]
     NTokensZero,
+    /// The abort callback set with [`context::LlamaContext::set_abort_callback`] stopped encoding.
+    #[error("Encode Error 2: aborted")]
+    Aborted,
     /// An unknown error occurred.
     #[error("Encode Error {0}: unknown")]
     Unknown(c_int),
@@ -148,6 +162,12 @@ pub enum EmbeddingsError {
     /// The given sequence index exceeds the max sequence id
     #[error("Can't use sequence embeddings with a model supporting only LLAMA_POOLING_TYPE_NONE")]
     NonePoolType,
+    /// Token embeddings are only kept when the context does not pool them
+    #[error("Token embeddings are only available with LLAMA_POOLING_TYPE_NONE")]
+    PooledType,
+    /// Classifier scores are only computed by a context that ranks
+    #[error("Classifier scores are only available with LLAMA_POOLING_TYPE_RANK")]
+    NotRankPoolType,
 }
 
 /// Decode a error from llama.cpp into a [`DecodeError`].
@@ -156,6 +176,7 @@ impl From<NonZeroI32> for DecodeError {
         match value.get() {
             1 => DecodeError::NoKvCacheSlot,
             -1 => DecodeError::NTokensZero,
+            2 => DecodeError::Aborted,
             i => DecodeError::Unknown(i),
         }
     }
@@ -167,6 +188,7 @@ impl From<NonZeroI32> for EncodeError {
         match value.get() {
             1 => EncodeError::NoKvCacheSlot,
             -1 => EncodeError::NTokensZero,
+            2 => EncodeError::Aborted,
             i => EncodeError::Unknown(i),
         }
     }
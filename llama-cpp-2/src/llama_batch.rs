@@ -1,5 +1,8 @@
 //! Safe wrapper around `llama_batch`.
 
+use std::slice;
+
+use crate::context::outputs::BatchOutput;
 use crate::token::LlamaToken;
 use llama_cpp_sys_2::{llama_batch, llama_batch_free, llama_batch_init, llama_pos, llama_seq_id};
 
@@ -192,6 +195,47 @@ impl LlamaBatch {
     pub fn n_tokens(&self) -> i32 {
         self.llama_batch.n_tokens
     }
+
+    /// Whether the tokens have positions, which batches from [`Self::get_one`] do not.
+    pub(crate) fn has_positions(&self) -> bool {
+        !self.llama_batch.pos.is_null()
+    }
+
+    /// The position of the token at `index`, `None` for batches from [`Self::get_one`], which
+    /// have no positions.
+    pub(crate) fn pos(&self, index: i32) -> Option<llama_pos> {
+        if self.llama_batch.pos.is_null() {
+            return None;
+        }
+        let index = usize::try_from(index).expect("cannot fit index into a usize");
+        Some(unsafe { *self.llama_batch.pos.add(index) })
+    }
+
+    /// The output of the token at `index`. llama.cpp places the tokens of a batch from
+    /// [`Self::get_one`] after the last position of sequence 0, so they start at `next_pos`, the
+    /// position after it before the batch was decoded.
+    pub(crate) fn output(&self, index: i32, next_pos: llama_pos) -> BatchOutput {
+        BatchOutput {
+            batch_index: index,
+            pos: self.pos(index).unwrap_or(next_pos + index),
+            seq_ids: self.seq_ids(index),
+        }
+    }
+
+    /// The sequences of the token at `index`. Batches from [`Self::get_one`] have no sequence
+    /// ids and belong to sequence 0.
+    pub(crate) fn seq_ids(&self, index: i32) -> Vec<i32> {
+        if self.llama_batch.seq_id.is_null() || self.llama_batch.n_seq_id.is_null() {
+            return vec![0];
+        }
+        let index = usize::try_from(index).expect("cannot fit index into a usize");
+        unsafe {
+            let n_seq_id = *self.llama_batch.n_seq_id.add(index);
+            let seq_ids = *self.llama_batch.seq_id.add(index);
+            let n_seq_id = usize::try_from(n_seq_id).expect("cannot fit n_seq_id into a usize");
+            slice::from_raw_parts(seq_ids, n_seq_id).to_vec()
+        }
+    }
 }
 
 impl Drop for LlamaBatch {
@@ -214,3 +258,91 @@ impl Drop for LlamaBatch {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A batch over `pos`, `n_seq_id` and `seq_id`, which must outlive it. Empty `pos` gives a
+    /// batch without positions and sequences, like [`LlamaBatch::get_one`].
+    fn batch(
+        tokens: &mut [i32],
+        pos: &mut [llama_pos],
+        n_seq_id: &mut [i32],
+        seq_id: &mut [*mut llama_seq_id],
+    ) -> LlamaBatch {
+        LlamaBatch {
+            allocated: 0,
+            initialized_logits: Vec::new(),
+            llama_batch: llama_batch {
+                n_tokens: i32::try_from(tokens.len()).unwrap(),
+                token: tokens.as_mut_ptr(),
+                embd: std::ptr::null_mut(),
+                pos: or_null(pos),
+                n_seq_id: or_null(n_seq_id),
+                seq_id: or_null(seq_id),
+                logits: std::ptr::null_mut(),
+            },
+        }
+    }
+
+    fn or_null<T>(values: &mut [T]) -> *mut T {
+        if values.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            values.as_mut_ptr()
+        }
+    }
+
+    #[test]
+    fn outputs_use_the_positions_and_sequences_of_the_batch() {
+        let mut seq_0 = [0];
+        let mut seq_1_2 = [1, 2];
+        let mut tokens = [10, 11, 12];
+        let mut pos = [7, 3, 4];
+        let mut n_seq_id = [1, 2, 2];
+        let mut seq_id = [
+            seq_0.as_mut_ptr(),
+            seq_1_2.as_mut_ptr(),
+            seq_1_2.as_mut_ptr(),
+        ];
+        let batch = batch(&mut tokens, &mut pos, &mut n_seq_id, &mut seq_id);
+
+        assert!(batch.has_positions());
+        assert_eq!(
+            batch.output(0, 100),
+            BatchOutput {
+                batch_index: 0,
+                pos: 7,
+                seq_ids: vec![0],
+            }
+        );
+        assert_eq!(
+            batch.output(2, 100),
+            BatchOutput {
+                batch_index: 2,
+                pos: 4,
+                seq_ids: vec![1, 2],
+            }
+        );
+    }
+
+    #[test]
+    fn outputs_of_a_batch_without_positions_continue_sequence_0() {
+        let mut tokens = [10, 11, 12];
+        let batch = batch(&mut tokens, &mut [], &mut [], &mut []);
+
+        assert!(!batch.has_positions());
+        assert_eq!(batch.pos(2), None);
+        // sequence 0 already holds positions 0 to 4
+        assert_eq!(
+            batch.output(2, 5),
+            BatchOutput {
+                batch_index: 2,
+                pos: 7,
+                seq_ids: vec![0],
+            }
+        );
+        assert_eq!(batch.output(0, 0).pos, 0);
+    }
+}
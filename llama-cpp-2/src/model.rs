@@ -6,7 +6,7 @@ use std::path::Path;
 use std::ptr::NonNull;
 use std::str::Utf8Error;
 
-use crate::context::params::LlamaContextParams;
+use crate::context::params::{LlamaContextParams, LlamaPoolingType};
 use crate::context::LlamaContext;
 use crate::llama_backend::LlamaBackend;
 use crate::model::params::LlamaModelParams;
@@ -75,8 +75,8 @@ impl std::fmt::Debug for LlamaChatTemplate {
 /// A Safe wrapper around `llama_chat_message`
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct LlamaChatMessage {
-    role: CString,
-    content: CString,
+    pub(crate) role: CString,
+    pub(crate) content: CString,
 }
 
 impl LlamaChatMessage {
@@ -192,6 +192,43 @@ impl LlamaModel {
         LlamaToken(token)
     }
 
+    /// Whether the tokenizer adds a BOS token at the start of an input.
+    #[must_use]
+    pub fn add_bos_token(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_vocab_get_add_bos(self.vocab_ptr()) }
+    }
+
+    /// Whether the tokenizer adds an EOS token at the end of an input.
+    #[must_use]
+    pub fn add_eos_token(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_vocab_get_add_eos(self.vocab_ptr()) }
+    }
+
+    /// Whether the tokenizer adds a separator token between the parts of a paired input.
+    #[must_use]
+    pub fn add_sep_token(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_vocab_get_add_sep(self.vocab_ptr()) }
+    }
+
+    /// Get the text of a token exactly as it is stored in the vocabulary. Unlike
+    /// [`Self::token_to_str`] this also returns the text of control tokens such as BOS and EOS.
+    ///
+    /// # Errors
+    ///
+    /// If the token text is not valid utf8.
+    ///
+    /// # Panics
+    ///
+    /// If the token is not part of the vocabulary.
+    pub fn token_text(&self, token: LlamaToken) -> Result<&str, Utf8Error> {
+        assert!(
+            (0..self.n_vocab()).contains(&token.0),
+            "token {token} is not part of the vocabulary"
+        );
+        let text = unsafe { llama_cpp_sys_2::llama_vocab_get_text(self.vocab_ptr(), token.0) };
+        unsafe { CStr::from_ptr(text) }.to_str()
+    }
+
     /// Convert single token to a string.
     ///
     /// # Errors
@@ -462,6 +499,23 @@ impl LlamaModel {
         unsafe { llama_cpp_sys_2::llama_n_embd(self.model.as_ptr()) }
     }
 
+    /// The number of outputs of the classifier head of reranking and classification models.
+    #[must_use]
+    pub fn n_cls_out(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_model_n_cls_out(self.model.as_ptr()) }
+    }
+
+    /// The label of output `i` of the classifier head, if the model names its outputs.
+    #[must_use]
+    pub fn cls_label(&self, i: u32) -> Option<String> {
+        let label = unsafe { llama_cpp_sys_2::llama_model_cls_label(self.model.as_ptr(), i) };
+        if label.is_null() {
+            return None;
+        }
+        let label = unsafe { CStr::from_ptr(label) };
+        label.to_str().ok().map(ToOwned::to_owned)
+    }
+
     /// Returns the total size of all the tensors in the model in bytes.
     pub fn size(&self) -> u64 {
         unsafe { llama_cpp_sys_2::llama_model_size(self.model.as_ptr()) }
@@ -477,6 +531,20 @@ impl LlamaModel {
         unsafe { llama_cpp_sys_2::llama_model_is_recurrent(self.model.as_ptr()) }
     }
 
+    /// Returns whether the model has an encoder, e.g. T5. The input of such a model is passed to
+    /// [`LlamaContext::encode`] and generation starts from [`Self::decode_start_token`].
+    #[must_use]
+    pub fn has_encoder(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_model_has_encoder(self.model.as_ptr()) }
+    }
+
+    /// Returns whether the model has a decoder. Encoder only models such as BERT cannot generate
+    /// text.
+    #[must_use]
+    pub fn has_decoder(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_model_has_decoder(self.model.as_ptr()) }
+    }
+
     /// Returns the number of layers within the model.
     pub fn n_layer(&self) -> u32 {
         // It's never possible for this to panic because while the API interface is defined as an int32_t,
@@ -517,6 +585,47 @@ impl LlamaModel {
         )
     }
 
+    /// Get an architecture specific metadata value, e.g. `llama.context_length` for the key
+    /// `context_length` of a llama model.
+    fn arch_meta_val_str(&self, key: &str) -> Option<String> {
+        let arch = self.meta_val_str("general.architecture").ok()?;
+        self.meta_val_str(&format!("{arch}.{key}")).ok()
+    }
+
+    /// The pooling type the model was trained with, read from the `<arch>.pooling_type`
+    /// metadata. [`LlamaPoolingType::Unspecified`] if the model does not declare one, which is
+    /// the case for most generative models.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use llama_cpp_2::context::params::LlamaPoolingType;
+    /// use llama_cpp_2::model::LlamaModel;
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// if model.pooling_type() == LlamaPoolingType::Cls {
+    ///     println!("embeddings come from the first token");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        self.arch_meta_val_str("pooling_type")
+            .and_then(|pooling_type| pooling_type.parse::<i32>().ok())
+            .map_or(LlamaPoolingType::Unspecified, LlamaPoolingType::from)
+    }
+
+    /// Whether the model uses causal attention, read from the `<arch>.attention.causal`
+    /// metadata. `None` if the model does not declare it, in which case llama.cpp assumes causal
+    /// attention.
+    #[must_use]
+    pub fn causal_attention(&self) -> Option<bool> {
+        self.arch_meta_val_str("attention.causal")
+            .and_then(|causal| causal.parse().ok())
+    }
+
     /// Get the number of metadata key/value pairs
     pub fn meta_count(&self) -> i32 {
         unsafe { llama_cpp_sys_2::llama_model_meta_count(self.model.as_ptr()) }
@@ -602,6 +711,26 @@ impl LlamaModel {
         }
     }
 
+    /// Get the names of the additional chat templates the model ships, such as `tool_use` or `rag`.
+    ///
+    /// These are stored under `tokenizer.chat_template.<name>` and can be passed to
+    /// [`Self::chat_template`]. The default template is not included.
+    ///
+    /// # Errors
+    ///
+    /// If a metadata key could not be read.
+    pub fn chat_template_names(&self) -> Result<Vec<String>, MetaValError> {
+        const PREFIX: &str = "tokenizer.chat_template.";
+        let mut names = Vec::new();
+        for index in 0..self.meta_count() {
+            let key = self.meta_key_by_index(index)?;
+            if let Some(name) = key.strip_prefix(PREFIX) {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
     /// Loads a model from a file.
     ///
     /// # Errors
@@ -0,0 +1,288 @@
+//! Score how relevant documents are to a query with a reranking (cross-encoder) model.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::model::LlamaModel;
+//! use llama_cpp_2::rerank::Reranker;
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(8);
+//! let mut reranker = Reranker::new(&backend, &model, params)?.with_sigmoid(true);
+//!
+//! let documents = ["Paris is the capital of France.", "Pandas eat bamboo."];
+//! let ranked = reranker.rank("What is the capital of France?", &documents)?;
+//! for (index, score) in ranked {
+//!     println!("{score:.3} {}", documents[index]);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::params::{LlamaContextParams, LlamaPoolingType};
+use crate::context::LlamaContext;
+use crate::embedding::{EmbedError, Embedder};
+use crate::llama_backend::LlamaBackend;
+use crate::model::{AddBos, LlamaModel};
+use crate::token::LlamaToken;
+use crate::{LlamaContextLoadError, StringToTokenError};
+
+/// Scores query/document pairs with a model that has a classifier head, such as the
+/// `bge-reranker` or `jina-reranker` families.
+///
+/// Each pair is formatted with the `rerank` chat template of the model if it has one, where
+/// `{query}` and `{document}` are replaced by the query and the document. Otherwise the pair is
+/// joined with the special tokens of the tokenizer: `BOS query EOS SEP document EOS`, leaving out
+/// the tokens the tokenizer does not add. As many pairs as the context allows are scored in one
+/// decode, see [`Embedder`].
+#[derive(Debug)]
+pub struct Reranker<'model> {
+    ctx: LlamaContext<'model>,
+    template: Option<String>,
+    class: usize,
+    sigmoid: bool,
+}
+
+impl<'model> Reranker<'model> {
+    /// Create a context for `model` to rerank with. `ctx_params` are adjusted for reranking:
+    /// embeddings are enabled with [`LlamaPoolingType::Rank`] and `n_ubatch` is raised to
+    /// `n_batch`, as every pair must be processed in one ubatch. Use
+    /// [`LlamaContextParams::with_n_seq_max`] to score more pairs per decode.
+    ///
+    /// `backend` is needed besides `model` and `ctx_params` because the reranker owns its
+    /// context, and [`LlamaModel::new_context`] takes the backend to prove it is initialized.
+    ///
+    /// # Errors
+    ///
+    /// If the context could not be created.
+    pub fn new(
+        backend: &LlamaBackend,
+        model: &'model LlamaModel,
+        ctx_params: LlamaContextParams,
+    ) -> Result<Self, LlamaContextLoadError> {
+        let n_batch = ctx_params.n_batch();
+        let ctx_params = ctx_params
+            .with_embeddings(true)
+            .with_pooling_type(LlamaPoolingType::Rank)
+            .with_n_ubatch(n_batch);
+        let ctx = model.new_context(backend, ctx_params)?;
+        let template = model
+            .chat_template(Some("rerank"))
+            .ok()
+            .and_then(|template| template.to_string().ok());
+        Ok(Self {
+            ctx,
+            template,
+            class: 0,
+            sigmoid: false,
+        })
+    }
+
+    /// Map scores to `0..1` with the logistic function. Off by default, so scores are the raw
+    /// logits of the classifier head.
+    #[must_use]
+    pub fn with_sigmoid(mut self, sigmoid: bool) -> Self {
+        self.sigmoid = sigmoid;
+        self
+    }
+
+    /// Rank by output `class` of a classifier head with several outputs, see [`Self::labels`].
+    /// Defaults to the first output.
+    ///
+    /// # Panics
+    ///
+    /// - `class` is not an output of the classifier head
+    #[must_use]
+    pub fn with_class(mut self, class: usize) -> Self {
+        assert!(
+            class < self.n_classes(),
+            "the classifier head has {} outputs",
+            self.n_classes()
+        );
+        self.class = class;
+        self
+    }
+
+    /// The context the reranker decodes into.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        &self.ctx
+    }
+
+    /// The number of outputs of the classifier head.
+    #[must_use]
+    pub fn n_classes(&self) -> usize {
+        self.ctx.model.n_cls_out().max(1) as usize
+    }
+
+    /// The labels of the outputs of the classifier head, empty if the model does not name them.
+    #[must_use]
+    pub fn labels(&self) -> Vec<String> {
+        (0..self.ctx.model.n_cls_out())
+            .map_while(|i| self.ctx.model.cls_label(i))
+            .collect()
+    }
+
+    /// The tokens of the pair of `query` and `document`, as they are scored.
+    ///
+    /// # Errors
+    ///
+    /// If the query or the document could not be tokenized.
+    pub fn pair_tokens(
+        &self,
+        query: &str,
+        document: &str,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        let model = self.ctx.model;
+        if let Some(template) = &self.template {
+            let prompt = template
+                .replace("{query}", query)
+                .replace("{document}", document);
+            return model.str_to_token(&prompt, AddBos::Never);
+        }
+
+        let eos = match model.token_eos() {
+            LlamaToken(-1) => model.token_sep(),
+            eos => eos,
+        };
+        let mut tokens = Vec::new();
+        if model.add_bos_token() {
+            tokens.push(model.token_bos());
+        }
+        tokens.extend(model.str_to_token(query, AddBos::Never)?);
+        if model.add_eos_token() {
+            tokens.push(eos);
+        }
+        if model.add_sep_token() {
+            tokens.push(model.token_sep());
+        }
+        tokens.extend(model.str_to_token(document, AddBos::Never)?);
+        if model.add_eos_token() {
+            tokens.push(eos);
+        }
+        Ok(tokens)
+    }
+
+    /// All outputs of the classifier head for each document, in document order. Each document
+    /// has [`Self::n_classes`] scores.
+    ///
+    /// # Errors
+    ///
+    /// - a pair could not be tokenized or does not fit into a batch
+    /// - decoding failed
+    pub fn scores(
+        &mut self,
+        query: &str,
+        documents: &[impl AsRef<str>],
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let pairs = documents
+            .iter()
+            .map(|document| self.pair_tokens(query, document.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut scores = Embedder::new(&mut self.ctx).rank_scores(&pairs)?;
+        if self.sigmoid {
+            for score in scores.iter_mut().flatten() {
+                *score = 1.0 / (1.0 + (-*score).exp());
+            }
+        }
+        Ok(scores)
+    }
+
+    /// Score each document against `query` and return `(index, score)` pairs, best first.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::scores`].
+    pub fn rank(
+        &mut self,
+        query: &str,
+        documents: &[impl AsRef<str>],
+    ) -> Result<Vec<(usize, f32)>, EmbedError> {
+        let scores = self.scores(query, documents)?;
+        Ok(ranked(&scores, self.class, documents.len()))
+    }
+
+    /// Like [`Self::rank`] but only returns the `n` best documents.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::scores`].
+    pub fn rank_top(
+        &mut self,
+        query: &str,
+        documents: &[impl AsRef<str>],
+        n: usize,
+    ) -> Result<Vec<(usize, f32)>, EmbedError> {
+        let scores = self.scores(query, documents)?;
+        Ok(ranked(&scores, self.class, n))
+    }
+}
+
+/// The `n` best `(index, score)` pairs by output `class`, best first. Documents with equal
+/// scores keep their order.
+fn ranked(scores: &[Vec<f32>], class: usize, n: usize) -> Vec<(usize, f32)> {
+    let mut ranked = scores
+        .iter()
+        .map(|scores| scores.get(class).copied().unwrap_or(f32::NEG_INFINITY))
+        .enumerate()
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranked.truncate(n);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranked_best_first() {
+        let scores = [vec![0.1], vec![2.5], vec![-1.0], vec![2.5]];
+        assert_eq!(
+            ranked(&scores, 0, scores.len()),
+            [(1, 2.5), (3, 2.5), (0, 0.1), (2, -1.0)]
+        );
+    }
+
+    #[test]
+    fn ranked_top_n() {
+        let scores = [vec![0.1], vec![2.5], vec![-1.0]];
+        assert_eq!(ranked(&scores, 0, 2), [(1, 2.5), (0, 0.1)]);
+        assert_eq!(ranked(&scores, 0, 10).len(), 3);
+        assert!(ranked(&scores, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn ranked_by_class() {
+        // (irrelevant, relevant) scores of a two class head
+        let scores = [vec![0.9, 0.1], vec![0.2, 0.8], vec![0.5]];
+        assert_eq!(
+            ranked(&scores, 1, 3),
+            [(1, 0.8), (0, 0.1), (2, f32::NEG_INFINITY)]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs the path of a reranking model in LLAMA_RERANK_MODEL"]
+    fn scores_have_one_value_per_class() {
+        let path = std::env::var("LLAMA_RERANK_MODEL").expect("LLAMA_RERANK_MODEL is not set");
+        let backend = LlamaBackend::init().unwrap();
+        let model_params = crate::model::params::LlamaModelParams::default();
+        let model = LlamaModel::load_from_file(&backend, path, &model_params).unwrap();
+        let params = LlamaContextParams::default().with_n_seq_max(2);
+        let mut reranker = Reranker::new(&backend, &model, params).unwrap();
+
+        let documents = [
+            "Paris is in France.",
+            "Pandas eat bamboo.",
+            "Rust is a language.",
+        ];
+        let scores = reranker.scores("Where is Paris?", &documents).unwrap();
+        assert_eq!(scores.len(), documents.len());
+        for score in &scores {
+            assert_eq!(score.len(), reranker.n_classes());
+        }
+    }
+}
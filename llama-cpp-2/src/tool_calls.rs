@@ -0,0 +1,1079 @@
+//! Extract tool (function) calls from generated text.
+//!
+//! Every model family is trained to emit tool calls in its own format. [`ToolCallFormat::detect`]
+//! picks the format from a model's chat template or architecture and [`ToolCallFormat::parser`]
+//! returns a [`ToolCallParser`] that can be fed the generated text as it is produced.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use llama_cpp_2::tool_calls::{ToolCallFormat, ToolCallParser};
+//!
+//! let mut parser = ToolCallFormat::Hermes.parser();
+//! let mut content = String::new();
+//! let mut tool_calls = Vec::new();
+//! for piece in [
+//!     "Let me check.<tool",
+//!     "_call>\n{\"name\": \"get_weather\", ",
+//!     "\"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+//! ] {
+//!     let delta = parser.push(piece)?;
+//!     content.push_str(&delta.content);
+//!     tool_calls.extend(delta.tool_calls);
+//! }
+//! let delta = parser.finish()?;
+//! content.push_str(&delta.content);
+//! tool_calls.extend(delta.tool_calls);
+//!
+//! assert_eq!(content, "Let me check.");
+//! assert_eq!(tool_calls[0].name, "get_weather");
+//! assert_eq!(tool_calls[0].arguments["city"], "Paris");
+//! # Ok::<(), llama_cpp_2::tool_calls::ToolCallParseError>(())
+//! ```
+
+use std::fmt::Debug;
+
+use serde_json::{Map, Value};
+
+use crate::chat::template::ChatTemplateFamily;
+use crate::generation::stop::partial_match_len;
+use crate::model::LlamaModel;
+
+/// A single tool call made by the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    /// The id of the call, for formats that include one.
+    pub id: Option<String>,
+    /// The name of the tool to call.
+    pub name: String,
+    /// The arguments of the call. This is usually a JSON object.
+    pub arguments: Value,
+}
+
+/// The output of [`ToolCallParser::push`] and [`ToolCallParser::finish`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCallDelta {
+    /// Text that is known not to be part of a tool call and can be shown to the user.
+    pub content: String,
+    /// Tool calls that were completed by this piece of text.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// The output of [`ToolCallParser::parse`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedToolCalls {
+    /// The text outside of tool calls with surrounding whitespace removed.
+    pub content: String,
+    /// The tool calls in the order they were made.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Failed to parse a tool call.
+#[derive(Debug, thiserror::Error)]
+pub enum ToolCallParseError {
+    /// The body of a tool call was not valid JSON.
+    #[error("invalid tool call json: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    /// The body of a tool call did not contain the name of the tool.
+    #[error("tool call has no name: {0}")]
+    MissingName(String),
+    /// The body of a tool call did not match the expected format.
+    #[error("malformed tool call: {0}")]
+    Malformed(String),
+}
+
+/// Incrementally splits generated text into content and tool calls.
+///
+/// Text that could be the start of a tool call is held back until it is known whether it is,
+/// so concatenating the `content` of every delta gives the content of the whole message.
+pub trait ToolCallParser: Debug {
+    /// Feed the next piece of generated text.
+    ///
+    /// # Errors
+    ///
+    /// If a completed tool call could not be parsed.
+    fn push(&mut self, text: &str) -> Result<ToolCallDelta, ToolCallParseError>;
+
+    /// Signal the end of the message. Flushes held back content and parses tool calls that are
+    /// terminated by the end of the message. The parser can be reused for the next message.
+    ///
+    /// # Errors
+    ///
+    /// If an unfinished tool call could not be parsed.
+    fn finish(&mut self) -> Result<ToolCallDelta, ToolCallParseError>;
+
+    /// Parse a complete message.
+    ///
+    /// # Errors
+    ///
+    /// If a tool call could not be parsed.
+    fn parse(&mut self, text: &str) -> Result<ParsedToolCalls, ToolCallParseError> {
+        let mut delta = self.push(text)?;
+        let rest = self.finish()?;
+        delta.content.push_str(&rest.content);
+        delta.tool_calls.extend(rest.tool_calls);
+        Ok(ParsedToolCalls {
+            content: delta.content.trim().to_string(),
+            tool_calls: delta.tool_calls,
+        })
+    }
+}
+
+/// The tool call formats that can be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ToolCallFormat {
+    /// JSON objects wrapped in `<tool_call>` tags, as used by Hermes and many fine-tunes.
+    Hermes,
+    /// Llama 3.1+: JSON or builtin tool calls after `<|python_tag|>`, or a message that is only a
+    /// JSON tool call. Code after `<|python_tag|>` is a call of the `python` tool with the code
+    /// as its `code` argument.
+    Llama3,
+    /// Mistral: a JSON array after `[TOOL_CALLS]`, or `[TOOL_CALLS]name[ARGS]{...}` in newer
+    /// models.
+    Mistral,
+    /// Qwen 2.5 and 3: Hermes style calls, also accepting the XML style
+    /// `<function=name><parameter=key>value</parameter></function>` body of Qwen3-Coder.
+    Qwen,
+}
+
+impl ToolCallFormat {
+    /// Guess the format from the source of a chat template.
+    ///
+    /// ```rust
+    /// use llama_cpp_2::tool_calls::ToolCallFormat;
+    ///
+    /// let template = "{% for m in messages %}<|im_start|>{{ m.role }}...<tool_call>...{% endfor %}";
+    /// assert_eq!(ToolCallFormat::from_template(template), Some(ToolCallFormat::Hermes));
+    /// assert_eq!(ToolCallFormat::from_template("[INST] [/INST]"), None);
+    /// ```
+    #[must_use]
+    pub fn from_template(template: &str) -> Option<Self> {
+        if template.contains("[TOOL_CALLS]") {
+            Some(Self::Mistral)
+        } else if template.contains("<tool_call>") {
+            if template.contains("<function=") || template.contains("Qwen") {
+                Some(Self::Qwen)
+            } else {
+                Some(Self::Hermes)
+            }
+        } else if template.contains("<|python_tag|>")
+            || (template.contains("<|start_header_id|>") && template.contains("tools"))
+        {
+            Some(Self::Llama3)
+        } else {
+            None
+        }
+    }
+
+    /// Guess the format from the `general.architecture` of a model. Only architectures that are
+    /// tied to a single format are recognized.
+    #[must_use]
+    pub fn from_architecture(architecture: &str) -> Option<Self> {
+        match architecture {
+            "qwen2" | "qwen2moe" | "qwen3" | "qwen3moe" => Some(Self::Qwen),
+            "mistral3" => Some(Self::Mistral),
+            "llama4" => Some(Self::Llama3),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of a model, first from the source of its default chat template, then from
+    /// the [`ChatTemplateFamily`] of that template and finally from its architecture.
+    #[must_use]
+    pub fn detect(model: &LlamaModel) -> Option<Self> {
+        let template = model.chat_template(None).ok();
+        let template = template
+            .as_ref()
+            .and_then(|template| template.to_str().ok());
+        template
+            .and_then(Self::from_template)
+            .or_else(|| {
+                template
+                    .and_then(ChatTemplateFamily::detect)
+                    .and_then(ChatTemplateFamily::tool_call_format)
+            })
+            .or_else(|| {
+                model
+                    .meta_val_str("general.architecture")
+                    .ok()
+                    .and_then(|arch| Self::from_architecture(&arch))
+            })
+    }
+
+    /// Create a new parser for this format.
+    #[must_use]
+    pub fn parser(self) -> Box<dyn ToolCallParser + Send> {
+        match self {
+            Self::Hermes => Box::<HermesParser>::default(),
+            Self::Llama3 => Box::<Llama3Parser>::default(),
+            Self::Mistral => Box::<MistralParser>::default(),
+            Self::Qwen => Box::<QwenParser>::default(),
+        }
+    }
+}
+
+/// Parses `<tool_call>{"name": ..., "arguments": ...}</tool_call>`.
+#[derive(Debug, Default)]
+pub struct HermesParser {
+    scanner: Scanner,
+}
+
+impl ToolCallParser for HermesParser {
+    fn push(&mut self, text: &str) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.scanner.scan(text, false, TOOL_CALL_START, take_hermes)
+    }
+
+    fn finish(&mut self) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.scanner.scan("", true, TOOL_CALL_START, take_hermes)
+    }
+}
+
+/// Parses Hermes style calls and the XML bodies used by Qwen3-Coder.
+#[derive(Debug, Default)]
+pub struct QwenParser {
+    scanner: Scanner,
+}
+
+impl ToolCallParser for QwenParser {
+    fn push(&mut self, text: &str) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.scanner.scan(text, false, TOOL_CALL_START, take_qwen)
+    }
+
+    fn finish(&mut self) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.scanner.scan("", true, TOOL_CALL_START, take_qwen)
+    }
+}
+
+/// Parses `[TOOL_CALLS][{"name": ..., "arguments": ...}]` and `[TOOL_CALLS]name[ARGS]{...}`.
+#[derive(Debug, Default)]
+pub struct MistralParser {
+    scanner: Scanner,
+}
+
+impl ToolCallParser for MistralParser {
+    fn push(&mut self, text: &str) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.scanner.scan(text, false, MISTRAL_START, take_mistral)
+    }
+
+    fn finish(&mut self) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.scanner.scan("", true, MISTRAL_START, take_mistral)
+    }
+}
+
+/// Parses `<|python_tag|>` calls and messages that consist of a single JSON tool call.
+#[derive(Debug, Default)]
+pub struct Llama3Parser {
+    scanner: Scanner,
+    /// Text held back while it is unknown whether the message is a bare JSON tool call.
+    pending: String,
+    mode: Llama3Mode,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Llama3Mode {
+    /// Nothing but whitespace was generated so far.
+    #[default]
+    Undecided,
+    /// The message starts with `{` and may be a bare JSON tool call.
+    Json,
+    /// The message is content with optional `<|python_tag|>` calls.
+    Tagged,
+}
+
+impl ToolCallParser for Llama3Parser {
+    fn push(&mut self, text: &str) -> Result<ToolCallDelta, ToolCallParseError> {
+        match self.mode {
+            Llama3Mode::Tagged => self.scanner.scan(text, false, PYTHON_TAG, take_llama3),
+            Llama3Mode::Json => {
+                self.pending.push_str(text);
+                Ok(ToolCallDelta::default())
+            }
+            Llama3Mode::Undecided => {
+                self.pending.push_str(text);
+                match self.pending.trim_start().chars().next() {
+                    None => Ok(ToolCallDelta::default()),
+                    Some('{') => {
+                        self.mode = Llama3Mode::Json;
+                        Ok(ToolCallDelta::default())
+                    }
+                    Some(_) => {
+                        self.mode = Llama3Mode::Tagged;
+                        let pending = std::mem::take(&mut self.pending);
+                        self.scanner.scan(&pending, false, PYTHON_TAG, take_llama3)
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<ToolCallDelta, ToolCallParseError> {
+        let mode = std::mem::take(&mut self.mode);
+        let pending = std::mem::take(&mut self.pending);
+        match mode {
+            Llama3Mode::Json => match take_json_calls(&pending, true, "parameters") {
+                Ok(Some(taken)) if pending[taken.used..].trim().is_empty() => Ok(ToolCallDelta {
+                    content: String::new(),
+                    tool_calls: taken.tool_calls,
+                }),
+                // not a tool call after all, just a message that starts with `{`
+                _ => Ok(ToolCallDelta {
+                    content: pending,
+                    tool_calls: Vec::new(),
+                }),
+            },
+            Llama3Mode::Undecided | Llama3Mode::Tagged => {
+                self.scanner.scan(&pending, true, PYTHON_TAG, take_llama3)
+            }
+        }
+    }
+}
+
+const TOOL_CALL_START: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+const MISTRAL_START: &str = "[TOOL_CALLS]";
+const MISTRAL_ARGS: &str = "[ARGS]";
+const MISTRAL_CALL_ID: &str = "[CALL_ID]";
+const PYTHON_TAG: &str = "<|python_tag|>";
+const PYTHON_CALL: &str = ".call(";
+const CODE_INTERPRETER: &str = "python";
+
+/// The result of trying to read tool calls from the text after a start marker, or `None` if more
+/// text is needed.
+type Taken = Option<TakenCalls>;
+
+/// Tool calls read from the text after a start marker.
+#[derive(Debug)]
+struct TakenCalls {
+    tool_calls: Vec<ToolCall>,
+    /// The number of bytes the calls span.
+    used: usize,
+    /// Another call follows that is not complete yet, so the text after the calls still belongs
+    /// to the tool calls.
+    more: bool,
+}
+
+impl TakenCalls {
+    /// Calls that span `used` bytes and end the tool calls.
+    fn new(tool_calls: Vec<ToolCall>, used: usize) -> Self {
+        Self {
+            tool_calls,
+            used,
+            more: false,
+        }
+    }
+}
+
+/// Splits text on a start marker and hands everything after it to a format specific function
+/// that reads the tool calls.
+#[derive(Debug, Default)]
+struct Scanner {
+    buffer: String,
+    in_call: bool,
+}
+
+impl Scanner {
+    fn scan(
+        &mut self,
+        text: &str,
+        end_of_message: bool,
+        start: &str,
+        take: fn(&str, bool) -> Result<Taken, ToolCallParseError>,
+    ) -> Result<ToolCallDelta, ToolCallParseError> {
+        self.buffer.push_str(text);
+        let mut delta = ToolCallDelta::default();
+        let result = loop {
+            if self.in_call {
+                match take(&self.buffer, end_of_message) {
+                    Ok(Some(taken)) => {
+                        delta.tool_calls.extend(taken.tool_calls);
+                        self.buffer.drain(..taken.used);
+                        self.in_call = taken.more;
+                    }
+                    Ok(None) if end_of_message => {
+                        break Err(ToolCallParseError::Malformed(self.buffer.clone()));
+                    }
+                    Ok(None) => break Ok(delta),
+                    Err(err) => break Err(err),
+                }
+            } else if let Some(idx) = self.buffer.find(start) {
+                delta.content.push_str(&self.buffer[..idx]);
+                self.buffer.drain(..idx + start.len());
+                self.in_call = true;
+            } else {
+                let keep = if end_of_message {
+                    0
+                } else {
+                    partial_match_len(&self.buffer, start)
+                };
+                let emit = self.buffer.len() - keep;
+                delta.content.push_str(&self.buffer[..emit]);
+                self.buffer.drain(..emit);
+                break Ok(delta);
+            }
+        };
+        if end_of_message || result.is_err() {
+            self.buffer.clear();
+            self.in_call = false;
+        }
+        result
+    }
+}
+
+fn take_hermes(body: &str, end_of_message: bool) -> Result<Taken, ToolCallParseError> {
+    take_tagged(body, end_of_message, |call| {
+        let value = serde_json::from_str(call)?;
+        json_call(value, "arguments")
+    })
+}
+
+fn take_qwen(body: &str, end_of_message: bool) -> Result<Taken, ToolCallParseError> {
+    take_tagged(body, end_of_message, |call| {
+        if call.starts_with("<function=") {
+            xml_call(call)
+        } else {
+            let value = serde_json::from_str(call)?;
+            json_call(value, "arguments")
+        }
+    })
+}
+
+/// Reads a single call terminated by `</tool_call>`. A missing end tag is tolerated at the end of
+/// the message.
+fn take_tagged(
+    body: &str,
+    end_of_message: bool,
+    parse: impl Fn(&str) -> Result<ToolCall, ToolCallParseError>,
+) -> Result<Taken, ToolCallParseError> {
+    let (call, used) = match body.find(TOOL_CALL_END) {
+        Some(idx) => (&body[..idx], idx + TOOL_CALL_END.len()),
+        None if end_of_message => (body, body.len()),
+        None => return Ok(None),
+    };
+    Ok(Some(TakenCalls::new(vec![parse(call.trim())?], used)))
+}
+
+fn take_mistral(body: &str, end_of_message: bool) -> Result<Taken, ToolCallParseError> {
+    let skipped = body.len() - body.trim_start().len();
+    let rest = &body[skipped..];
+    if rest.is_empty() {
+        return Ok(None);
+    }
+    if rest.starts_with('[') {
+        return Ok(match json_prefix(rest)? {
+            Some((Value::Array(calls), used)) => {
+                let calls = calls
+                    .into_iter()
+                    .map(|call| json_call(call, "arguments"))
+                    .collect::<Result<_, _>>()?;
+                Some(TakenCalls::new(calls, skipped + used))
+            }
+            Some((value, _)) => return Err(ToolCallParseError::Malformed(value.to_string())),
+            None => None,
+        });
+    }
+
+    let Some(args_idx) = rest.find(MISTRAL_ARGS) else {
+        return Ok(None);
+    };
+    let (name, id) = match rest[..args_idx].split_once(MISTRAL_CALL_ID) {
+        Some((name, id)) => (name, Some(id.trim().to_string())),
+        None => (&rest[..args_idx], None),
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ToolCallParseError::MissingName(rest.to_string()));
+    }
+    let args_start = args_idx + MISTRAL_ARGS.len();
+    match json_prefix(&rest[args_start..])? {
+        Some((arguments, used)) => Ok(Some(TakenCalls::new(
+            vec![ToolCall {
+                id,
+                name: name.to_string(),
+                arguments,
+            }],
+            skipped + args_start + used,
+        ))),
+        None if end_of_message => Err(ToolCallParseError::Malformed(rest.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn take_llama3(body: &str, end_of_message: bool) -> Result<Taken, ToolCallParseError> {
+    let skipped = body.len() - body.trim_start().len();
+    let rest = &body[skipped..];
+    if rest.starts_with('{') {
+        return take_json_calls(body, end_of_message, "parameters");
+    }
+    match python_call(rest) {
+        Ok(Some((call, used))) => Ok(Some(TakenCalls::new(vec![call], skipped + used))),
+        // the call may still be completed, or it is code that runs until the end of the message
+        _ if !end_of_message => Ok(None),
+        _ if rest.trim().is_empty() => Err(ToolCallParseError::Malformed(body.to_string())),
+        _ => {
+            let mut arguments = Map::new();
+            arguments.insert("code".to_string(), Value::from(rest.trim_end()));
+            Ok(Some(TakenCalls::new(
+                vec![ToolCall {
+                    id: None,
+                    name: CODE_INTERPRETER.to_string(),
+                    arguments: Value::Object(arguments),
+                }],
+                body.len(),
+            )))
+        }
+    }
+}
+
+/// Reads one or more `;` separated JSON tool calls. Once the next call is being generated, the
+/// calls before it are returned, so a call that is cut off at the end of the message does not
+/// take the complete calls with it.
+fn take_json_calls(
+    body: &str,
+    end_of_message: bool,
+    arguments_key: &str,
+) -> Result<Taken, ToolCallParseError> {
+    let mut calls = Vec::new();
+    let mut used = 0;
+    loop {
+        let Some((value, len)) = json_prefix(&body[used..])? else {
+            if !calls.is_empty() {
+                return Ok(Some(TakenCalls {
+                    tool_calls: calls,
+                    used,
+                    more: true,
+                }));
+            }
+            if end_of_message {
+                return Err(ToolCallParseError::Malformed(body.to_string()));
+            }
+            return Ok(None);
+        };
+        calls.push(json_call(value, arguments_key)?);
+        used += len;
+
+        let rest = &body[used..];
+        let trimmed = rest.trim_start();
+        match trimmed.strip_prefix(';') {
+            Some(next) if !next.trim().is_empty() => used += rest.len() - next.len(),
+            // the next call may still be generated
+            Some(_) if !end_of_message => return Ok(None),
+            Some(_) => return Ok(Some(TakenCalls::new(calls, body.len()))),
+            None if trimmed.is_empty() && !end_of_message => return Ok(None),
+            None => return Ok(Some(TakenCalls::new(calls, used))),
+        }
+    }
+}
+
+/// Deserializes the JSON value at the start of `text`, returning `None` if it is incomplete.
+fn json_prefix(text: &str) -> Result<Option<(Value, usize)>, serde_json::Error> {
+    let mut stream = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+    match stream.next() {
+        None => Ok(None),
+        Some(Ok(value)) => Ok(Some((value, stream.byte_offset()))),
+        Some(Err(err)) if err.is_eof() => Ok(None),
+        Some(Err(err)) => Err(err),
+    }
+}
+
+/// Converts `{"name": ..., "arguments": ...}` into a [`ToolCall`]. Arguments that are encoded as a
+/// JSON string are decoded.
+fn json_call(value: Value, arguments_key: &str) -> Result<ToolCall, ToolCallParseError> {
+    let Value::Object(mut object) = value else {
+        return Err(ToolCallParseError::Malformed(value.to_string()));
+    };
+    let Some(Value::String(name)) = object.remove("name") else {
+        return Err(ToolCallParseError::MissingName(
+            Value::Object(object).to_string(),
+        ));
+    };
+    let arguments = match object
+        .remove(arguments_key)
+        .or_else(|| object.remove("arguments"))
+        .or_else(|| object.remove("parameters"))
+    {
+        Some(Value::String(arguments)) => serde_json::from_str(&arguments)?,
+        Some(arguments) => arguments,
+        None => Value::Object(Map::new()),
+    };
+    let id = match object.remove("id") {
+        Some(Value::String(id)) => Some(id),
+        _ => None,
+    };
+    Ok(ToolCall {
+        id,
+        name,
+        arguments,
+    })
+}
+
+/// Parses `<function=name><parameter=key>value</parameter></function>`. Parameter values that are
+/// valid JSON are decoded, everything else is kept as a string.
+fn xml_call(call: &str) -> Result<ToolCall, ToolCallParseError> {
+    let malformed = || ToolCallParseError::Malformed(call.to_string());
+    let rest = call.strip_prefix("<function=").ok_or_else(malformed)?;
+    let (name, mut rest) = rest.split_once('>').ok_or_else(malformed)?;
+    let mut arguments = Map::new();
+    while let Some(start) = rest.find("<parameter=") {
+        let (key, after) = rest[start + "<parameter=".len()..]
+            .split_once('>')
+            .ok_or_else(malformed)?;
+        let (value, after) = after.split_once("</parameter>").ok_or_else(malformed)?;
+        let value = value.strip_prefix('\n').unwrap_or(value);
+        let value = value.strip_suffix('\n').unwrap_or(value);
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+        arguments.insert(key.to_string(), value);
+        rest = after;
+    }
+    Ok(ToolCall {
+        id: None,
+        name: name.trim().to_string(),
+        arguments: Value::Object(arguments),
+    })
+}
+
+/// Parses a Llama 3 builtin tool call like `brave_search.call(query="...")`, returning `None` if it
+/// is incomplete.
+fn python_call(text: &str) -> Result<Option<(ToolCall, usize)>, ToolCallParseError> {
+    let malformed = || ToolCallParseError::Malformed(text.to_string());
+    let name_len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let after_name = &text[name_len..];
+    if !after_name.starts_with(PYTHON_CALL) {
+        return if PYTHON_CALL.starts_with(after_name) {
+            Ok(None)
+        } else {
+            Err(malformed())
+        };
+    }
+    if name_len == 0 {
+        return Err(ToolCallParseError::MissingName(text.to_string()));
+    }
+
+    let mut arguments = Map::new();
+    let mut pos = name_len + PYTHON_CALL.len();
+    loop {
+        pos += whitespace_len(&text[pos..]);
+        let Some(next) = text[pos..].chars().next() else {
+            return Ok(None);
+        };
+        if next == ')' {
+            pos += 1;
+            break;
+        }
+        let key_len = text[pos..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(text.len() - pos);
+        let key = &text[pos..pos + key_len];
+        pos += key_len;
+        pos += whitespace_len(&text[pos..]);
+        match text[pos..].chars().next() {
+            None => return Ok(None),
+            Some('=') if !key.is_empty() => pos += 1,
+            Some(_) => return Err(malformed()),
+        }
+        pos += whitespace_len(&text[pos..]);
+        let Some((value, len)) = python_literal(&text[pos..])? else {
+            return Ok(None);
+        };
+        arguments.insert(key.to_string(), value);
+        pos += len;
+        pos += whitespace_len(&text[pos..]);
+        match text[pos..].chars().next() {
+            None => return Ok(None),
+            Some(',') => pos += 1,
+            Some(')') => {}
+            Some(_) => return Err(malformed()),
+        }
+    }
+    Ok(Some((
+        ToolCall {
+            id: None,
+            name: text[..name_len].to_string(),
+            arguments: Value::Object(arguments),
+        },
+        pos,
+    )))
+}
+
+fn whitespace_len(text: &str) -> usize {
+    text.len() - text.trim_start().len()
+}
+
+/// Parses a python string, number, boolean, `None`, list, tuple or dict literal, returning `None`
+/// if it is incomplete.
+fn python_literal(text: &str) -> Result<Option<(Value, usize)>, ToolCallParseError> {
+    let malformed = || ToolCallParseError::Malformed(text.to_string());
+    let mut chars = text.char_indices();
+    let (quote, close) = match chars.next() {
+        None => return Ok(None),
+        Some((_, quote @ ('"' | '\''))) => (quote, None),
+        Some((_, '[')) => ('[', Some(']')),
+        Some((_, '(')) => ('(', Some(')')),
+        Some((_, '{')) => return python_dict(text),
+        Some(_) => {
+            let len = text
+                .find(|c: char| c.is_whitespace() || ",)]}:".contains(c))
+                .unwrap_or(text.len());
+            if len == text.len() {
+                return Ok(None);
+            }
+            let value = match &text[..len] {
+                "True" => Value::Bool(true),
+                "False" => Value::Bool(false),
+                "None" => Value::Null,
+                number => serde_json::from_str::<serde_json::Number>(number)
+                    .ok()
+                    .map(Value::Number)
+                    .ok_or_else(malformed)?,
+            };
+            return Ok(Some((value, len)));
+        }
+    };
+    if let Some(close) = close {
+        let mut items = Vec::new();
+        let mut pos = 1;
+        loop {
+            pos += whitespace_len(&text[pos..]);
+            match text[pos..].chars().next() {
+                None => return Ok(None),
+                Some(c) if c == close => return Ok(Some((Value::Array(items), pos + 1))),
+                Some(_) => {}
+            }
+            let Some((item, len)) = python_literal(&text[pos..])? else {
+                return Ok(None);
+            };
+            items.push(item);
+            pos += len;
+            pos += whitespace_len(&text[pos..]);
+            match text[pos..].chars().next() {
+                None => return Ok(None),
+                Some(',') => pos += 1,
+                Some(c) if c == close => {}
+                Some(_) => return Err(malformed()),
+            }
+        }
+    }
+    let mut value = String::new();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next().map_or('\\', |(_, escaped)| escaped) {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                escaped => value.push(escaped),
+            },
+            c if c == quote => return Ok(Some((Value::String(value), idx + 1))),
+            c => value.push(c),
+        }
+    }
+    Ok(None)
+}
+
+/// Parses a python dict literal, see [`python_literal`]. Keys that are not strings are converted
+/// to their JSON text.
+fn python_dict(text: &str) -> Result<Option<(Value, usize)>, ToolCallParseError> {
+    let malformed = || ToolCallParseError::Malformed(text.to_string());
+    let mut object = Map::new();
+    let mut pos = 1;
+    loop {
+        pos += whitespace_len(&text[pos..]);
+        match text[pos..].chars().next() {
+            None => return Ok(None),
+            Some('}') => return Ok(Some((Value::Object(object), pos + 1))),
+            Some(_) => {}
+        }
+        let Some((key, len)) = python_literal(&text[pos..])? else {
+            return Ok(None);
+        };
+        pos += len;
+        pos += whitespace_len(&text[pos..]);
+        match text[pos..].chars().next() {
+            None => return Ok(None),
+            Some(':') => pos += 1,
+            Some(_) => return Err(malformed()),
+        }
+        pos += whitespace_len(&text[pos..]);
+        let Some((value, len)) = python_literal(&text[pos..])? else {
+            return Ok(None);
+        };
+        let key = match key {
+            Value::String(key) => key,
+            key => key.to_string(),
+        };
+        object.insert(key, value);
+        pos += len;
+        pos += whitespace_len(&text[pos..]);
+        match text[pos..].chars().next() {
+            None => return Ok(None),
+            Some(',') => pos += 1,
+            Some('}') => {}
+            Some(_) => return Err(malformed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Feed `pieces` one after another and collect the content and the tool calls.
+    fn stream<'a>(
+        format: ToolCallFormat,
+        pieces: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(String, Vec<ToolCall>), ToolCallParseError> {
+        let mut parser = format.parser();
+        let mut content = String::new();
+        let mut tool_calls = Vec::new();
+        for piece in pieces {
+            let delta = parser.push(piece)?;
+            content.push_str(&delta.content);
+            tool_calls.extend(delta.tool_calls);
+        }
+        let delta = parser.finish()?;
+        content.push_str(&delta.content);
+        tool_calls.extend(delta.tool_calls);
+        Ok((content, tool_calls))
+    }
+
+    /// Feed `text` one character at a time, so every marker is split at every position.
+    fn stream_chars(
+        format: ToolCallFormat,
+        text: &str,
+    ) -> Result<(String, Vec<ToolCall>), ToolCallParseError> {
+        let pieces = text
+            .char_indices()
+            .map(|(i, c)| &text[i..i + c.len_utf8()])
+            .collect::<Vec<_>>();
+        stream(format, pieces)
+    }
+
+    fn call(name: &str, arguments: Value) -> ToolCall {
+        ToolCall {
+            id: None,
+            name: name.to_string(),
+            arguments,
+        }
+    }
+
+    #[test]
+    fn hermes_splits_content_and_calls() {
+        let text = "Checking both.<tool_call>\n{\"name\": \"weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>\n<tool_call>{\"name\": \"time\", \"arguments\": \"{\\\"zone\\\": \\\"CET\\\"}\"}</tool_call>";
+        let expected = vec![
+            call("weather", json!({"city": "Paris"})),
+            call("time", json!({"zone": "CET"})),
+        ];
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Hermes, text).unwrap();
+        assert_eq!(content.trim(), "Checking both.");
+        assert_eq!(tool_calls, expected);
+
+        let parsed = ToolCallFormat::Hermes.parser().parse(text).unwrap();
+        assert_eq!(parsed.content, "Checking both.");
+        assert_eq!(parsed.tool_calls, expected);
+    }
+
+    #[test]
+    fn hermes_releases_text_that_is_not_a_marker() {
+        let (content, tool_calls) =
+            stream(ToolCallFormat::Hermes, ["a <tool", "box> b <", "tool"]).unwrap();
+        assert_eq!(content, "a <toolbox> b <tool");
+        assert!(tool_calls.is_empty());
+    }
+
+    #[test]
+    fn hermes_accepts_a_missing_end_tag_at_the_end() {
+        let (_, tool_calls) = stream(
+            ToolCallFormat::Hermes,
+            ["<tool_call>{\"name\": \"f\", \"arguments\": {}}"],
+        )
+        .unwrap();
+        assert_eq!(tool_calls, [call("f", json!({}))]);
+    }
+
+    #[test]
+    fn hermes_rejects_malformed_json() {
+        let result = stream(
+            ToolCallFormat::Hermes,
+            ["<tool_call>{\"name\": \"f\", \"arguments\": {]}</tool_call>"],
+        );
+        assert!(matches!(result, Err(ToolCallParseError::InvalidJson(_))));
+
+        let result = stream(
+            ToolCallFormat::Hermes,
+            ["<tool_call>{\"arguments\": {}}</tool_call>"],
+        );
+        assert!(matches!(result, Err(ToolCallParseError::MissingName(_))));
+    }
+
+    #[test]
+    fn qwen_parses_json_and_xml_bodies() {
+        let text = "<tool_call>\n<function=search>\n<parameter=query>\nrust lang\n</parameter>\n<parameter=limit>\n5\n</parameter>\n</function>\n</tool_call><tool_call>{\"name\": \"time\", \"arguments\": {}}</tool_call>";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Qwen, text).unwrap();
+        assert_eq!(content, "");
+        assert_eq!(
+            tool_calls,
+            [
+                call("search", json!({"query": "rust lang", "limit": 5})),
+                call("time", json!({})),
+            ]
+        );
+    }
+
+    #[test]
+    fn mistral_parses_a_json_array() {
+        let text = "Sure.[TOOL_CALLS] [{\"name\": \"a\", \"arguments\": {\"x\": 1}, \"id\": \"abc123def\"}, {\"name\": \"b\", \"arguments\": {}}]";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Mistral, text).unwrap();
+        assert_eq!(content, "Sure.");
+        assert_eq!(
+            tool_calls,
+            [
+                ToolCall {
+                    id: Some("abc123def".to_string()),
+                    ..call("a", json!({"x": 1}))
+                },
+                call("b", json!({})),
+            ]
+        );
+    }
+
+    #[test]
+    fn mistral_parses_name_and_args() {
+        let text =
+            "[TOOL_CALLS]weather[CALL_ID]x1[ARGS]{\"city\": \"Paris\"}[TOOL_CALLS]time[ARGS]{}";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Mistral, text).unwrap();
+        assert_eq!(content, "");
+        assert_eq!(
+            tool_calls,
+            [
+                ToolCall {
+                    id: Some("x1".to_string()),
+                    ..call("weather", json!({"city": "Paris"}))
+                },
+                call("time", json!({})),
+            ]
+        );
+    }
+
+    #[test]
+    fn mistral_rejects_malformed_json() {
+        let result = stream(ToolCallFormat::Mistral, ["[TOOL_CALLS][{\"name\": }]"]);
+        assert!(matches!(result, Err(ToolCallParseError::InvalidJson(_))));
+
+        let result = stream(ToolCallFormat::Mistral, ["[TOOL_CALLS]f[ARGS]{\"x\": "]);
+        assert!(matches!(result, Err(ToolCallParseError::Malformed(_))));
+    }
+
+    #[test]
+    fn llama3_parses_bare_json_calls() {
+        let text =
+            " {\"name\": \"a\", \"parameters\": {\"x\": 1}}; {\"name\": \"b\", \"parameters\": {}}";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Llama3, text).unwrap();
+        assert_eq!(content, "");
+        assert_eq!(
+            tool_calls,
+            [call("a", json!({"x": 1})), call("b", json!({}))]
+        );
+    }
+
+    #[test]
+    fn llama3_keeps_json_like_content() {
+        let text = "{braces} are fine";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Llama3, text).unwrap();
+        assert_eq!(content, text);
+        assert!(tool_calls.is_empty());
+    }
+
+    #[test]
+    fn llama3_parses_builtin_calls() {
+        let text = "Searching.<|python_tag|>brave_search.call(query=\"a, b)\", n=3, tags=['x', \"y\"], opts={'safe': True, 'lang': None}, shape=(1, 2))";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Llama3, text).unwrap();
+        assert_eq!(content, "Searching.");
+        assert_eq!(
+            tool_calls,
+            [call(
+                "brave_search",
+                json!({
+                    "query": "a, b)",
+                    "n": 3,
+                    "tags": ["x", "y"],
+                    "opts": {"safe": true, "lang": null},
+                    "shape": [1, 2],
+                })
+            )]
+        );
+    }
+
+    #[test]
+    fn llama3_reports_code_as_a_python_call() {
+        let text = "<|python_tag|>import math\nprint(math.sqrt(2))\n";
+        let (content, tool_calls) = stream_chars(ToolCallFormat::Llama3, text).unwrap();
+        assert_eq!(content, "");
+        assert_eq!(
+            tool_calls,
+            [call(
+                "python",
+                json!({"code": "import math\nprint(math.sqrt(2))"})
+            )]
+        );
+    }
+
+    #[test]
+    fn llama3_keeps_complete_calls_before_a_cut_off_call() {
+        let text = "<|python_tag|>{\"name\": \"a\", \"parameters\": {}}; {\"name\": \"b\", \"parameters\": {}}; {\"name\": \"c\", \"param";
+        let mut parser = ToolCallFormat::Llama3.parser();
+        let mut tool_calls = Vec::new();
+        for (i, c) in text.char_indices() {
+            let delta = parser.push(&text[i..i + c.len_utf8()]).unwrap();
+            assert_eq!(delta.content, "");
+            tool_calls.extend(delta.tool_calls);
+        }
+        assert_eq!(tool_calls, [call("a", json!({})), call("b", json!({}))]);
+        match parser.finish() {
+            Err(ToolCallParseError::Malformed(fragment)) => {
+                assert_eq!(fragment, " {\"name\": \"c\", \"param");
+            }
+            result => panic!("expected the cut off call to be malformed, got {result:?}"),
+        }
+
+        // the parser is ready for the next message
+        let parsed = parser.parse("Done.").unwrap();
+        assert_eq!(parsed.content, "Done.");
+    }
+
+    #[test]
+    fn llama3_rejects_an_empty_python_tag() {
+        let result = stream(ToolCallFormat::Llama3, ["Hi <|python_tag|> "]);
+        assert!(matches!(result, Err(ToolCallParseError::Malformed(_))));
+    }
+
+    #[test]
+    fn python_literals() {
+        let literal = |text| python_literal(text).unwrap();
+        assert_eq!(literal("[1, 2], x"), Some((json!([1, 2]), 6)));
+        assert_eq!(literal("{'a': [1]})"), Some((json!({"a": [1]}), 10)));
+        assert_eq!(literal("'it\\'s'"), Some((json!("it's"), 7)));
+        assert_eq!(literal("-1.5)"), Some((json!(-1.5), 4)));
+        assert_eq!(literal("False,"), Some((json!(false), 5)));
+        assert_eq!(literal("[1, 2"), None);
+        assert_eq!(literal("12"), None);
+        assert!(python_literal("nope)").is_err());
+    }
+
+    #[test]
+    fn detects_formats_from_templates() {
+        assert_eq!(
+            ToolCallFormat::from_template("[TOOL_CALLS]"),
+            Some(ToolCallFormat::Mistral)
+        );
+        assert_eq!(
+            ToolCallFormat::from_template("<tool_call><function=name>"),
+            Some(ToolCallFormat::Qwen)
+        );
+        assert_eq!(
+            ToolCallFormat::from_template("<|start_header_id|>ipython{{ tools }}"),
+            Some(ToolCallFormat::Llama3)
+        );
+    }
+}